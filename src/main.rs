@@ -1,17 +1,20 @@
+use std::collections::VecDeque;
 use std::ffi::OsString;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::error::TryRecvError;
 
 use anyhow::{Error, Result};
-use log::trace;
-use ratatui::style::{Color, Style};
+use log::{debug, error, info, trace, warn};
+use ratatui::style::{Color, Modifier, Style};
 use ratatui::text::{Line, Span};
 use tokio::task;
 
 use clap::Parser;
-use crossterm::event::{KeyCode, KeyEventKind};
+use clap_complete::engine::{ArgValueCompleter, CompletionCandidate};
+use crossterm::event::{KeyCode, KeyEventKind, KeyModifiers};
 
 const UNCHECKED: &str = "\u{2610}";
 const CHECKED: &str = "\u{2611}";
@@ -19,33 +22,451 @@ const FAILED: &str = "\u{2612}";
 
 const DEFAULT_MAX_CONCURRENCY: usize = 1;
 
+/// Times to run a step, as long as its exit code says to retry.
+const RETRY_ATTEMPTS: usize = 3;
+
+/// Timeout for requests to HTTP and SMTP servers.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Output lines longer than this many bytes are split, unless configured.
+const DEFAULT_MAX_LINE: usize = 64 * 1024;
+
+/// Prefix of stderr lines in raw mode and output logs.
+const STDERR_PREFIX: &str = "stderr|";
+
+/// Prefix of stderr lines in the TUI, a red `!`.
+const STDERR_GUTTER: &str = "\x1b[31m!\x1b[0m ";
+
+/// How often to check the load average, with `--max-load`.
+const LOAD_POLL: Duration = Duration::from_secs(1);
+
+/// Name of the workflow directory searched for if `--dir` is not given.
+const WORKFLOW_DIR: &str = ".tickbox";
+
+/// Number of old log files to keep, as `<log>.1` and so on.
+const LOG_KEEP: usize = 3;
+
+/// Socket for the native journald protocol.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Output lines matching this are counted as errors, unless configured.
+const DEFAULT_ERROR_REGEX: &str = r"(?i)\berror\b";
+
+/// Number of output lines per failed step shown in the summary.
+const SUMMARY_LINES: usize = 10;
+
+/// Number of slowest steps listed in the summary.
+const SUMMARY_SLOWEST: usize = 5;
+
+/// How long a plugin gets to answer an event, before it's killed.
+const PLUGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check if the workflow changed on disk.
+const CHANGE_POLL: Duration = Duration::from_secs(2);
+
+/// Cap on the work a `when` or `computed_envs` script may do, so that one
+/// stuck in a loop fails instead of hanging the workflow.
+const SCRIPT_MAX_OPERATIONS: u64 = 1_000_000;
+
+/// How often to refresh cached sudo credentials, well within sudo's
+/// default timeout of 5-15 minutes.
+const SUDO_REFRESH: Duration = Duration::from_secs(60);
+
+/// How long the workflow pane border flashes, with `--bell`.
+const FLASH_TIME: Duration = Duration::from_secs(1);
+
+/// Minimum time between redraws because of new output.
+const FRAME_TIME: Duration = Duration::from_millis(33);
+
+/// Default maximum number of output lines waiting for the UI.
+const DEFAULT_OUTPUT_BUFFER: usize = 100_000;
+
+/// Number of runs to keep in the history of a workflow, for `tickbox diff`.
+const HISTORY_RUNS: usize = 100;
+
+/// In compact mode, collapse runs of at least this many finished steps.
+const COMPACT_MIN_RUN: usize = 3;
+
+/// Lines of the output pane still visible after paging.
+const PAGE_OVERLAP: usize = 2;
+
 #[derive(clap::Parser, Debug, Clone)]
-#[command(version, about)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Print a JSON Schema of the config, for editors to check
+    /// `tickbox.json` with, and exit.
+    #[arg(long)]
+    schema: bool,
+
+    #[command(flatten)]
+    run: Opt,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Command {
+    /// Run a workflow. This is the default.
+    Run(Opt),
+
+    /// List named workflows.
+    ListWorkflows {
+        /// Directory with workflows. Defaults to finding `.tickbox`.
+        #[arg(long)]
+        dir: Option<std::path::PathBuf>,
+    },
+
+    /// Convert a workflow directory into a single workflow file, for
+    /// `--file`.
+    ///
+    /// Shell scripts become inline steps, and other steps refer to their
+    /// files.
+    Export {
+        /// Workflow directory. Defaults to finding `.tickbox`.
+        #[arg(long)]
+        dir: Option<std::path::PathBuf>,
+
+        /// File to write to, instead of stdout.
+        #[arg(long)]
+        out: Option<std::path::PathBuf>,
+    },
+
+    /// Convert a workflow file into a workflow directory.
+    Import {
+        /// Workflow file, as used with `--file`.
+        file: std::path::PathBuf,
+
+        /// Workflow directory to create.
+        #[arg(long)]
+        dir: std::path::PathBuf,
+    },
+
+    /// Download a workflow from a git repository or tarball, and run it.
+    ///
+    /// The workflow is kept in `~/.cache/tickbox/workflows`, and updated
+    /// every time.
+    Fetch {
+        /// Git repository, or URL of a `.tar.gz` or similar, as made by
+        /// `tickbox pack`.
+        url: String,
+
+        #[command(flatten)]
+        opt: Opt,
+    },
+
+    /// Pack a workflow directory into a `.tar.gz`, for `tickbox fetch`.
+    Pack {
+        /// Workflow directory. Defaults to finding `.tickbox`.
+        #[arg(long)]
+        dir: Option<std::path::PathBuf>,
+
+        /// Archive to create.
+        #[arg(long)]
+        out: std::path::PathBuf,
+    },
+
+    /// Rename step files to evenly spaced numbers, e.g. 10, 20, 30.
+    ///
+    /// Steps that shared a number still do. Settings for specific steps in
+    /// `tickbox.json` are updated too.
+    Renumber {
+        /// Workflow directory. Defaults to finding `.tickbox`.
+        #[arg(long)]
+        dir: Option<std::path::PathBuf>,
+
+        /// Difference between step numbers.
+        #[arg(long, default_value_t = 10)]
+        spacing: usize,
+
+        /// Only print what would be renamed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Create a new step script, numbered to go after a given step.
+    NewStep {
+        /// Description of the step, also used for its file name.
+        description: String,
+
+        /// Number of the step to put it after. Defaults to the last step.
+        #[arg(long)]
+        after: Option<usize>,
+
+        /// Workflow directory. Defaults to finding `.tickbox`.
+        #[arg(long)]
+        dir: Option<std::path::PathBuf>,
+    },
+
+    /// Print the structure of a workflow as a graph, for Graphviz or
+    /// Mermaid.
+    ///
+    /// Steps that may run in parallel are side by side, and step groups are
+    /// boxes. Only steps that would run are shown.
+    Graph {
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+
+        #[command(flatten)]
+        opt: Opt,
+    },
+
+    /// Play back a run recorded with `--record` in the TUI.
+    Replay {
+        /// Recording, as written by `--record`.
+        file: std::path::PathBuf,
+
+        /// How many times faster than real time to play it back.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+
+    /// Compare two runs of a workflow from its history: which steps passed
+    /// or failed, and how much slower or faster they were.
+    Diff {
+        /// Run ID (or a prefix of one), `last`, or `last-success`.
+        run_a: String,
+
+        /// Run to compare with, like `run_a`.
+        run_b: String,
+
+        #[command(flatten)]
+        opt: Opt,
+    },
+
+    /// Print how often each step passed, from the history of a workflow.
+    Stats {
+        /// Only list flaky steps, that have both passed and failed.
+        #[arg(long)]
+        flaky: bool,
+
+        #[command(flatten)]
+        opt: Opt,
+    },
+
+    /// Suggest steps to run in parallel, from how long they took in the
+    /// history of a workflow, and how much time that would save.
+    ///
+    /// Steps sharing a `lock` or other resource aren't suggested together.
+    /// Whether steps depend on each other in other ways is up to you.
+    Analyze {
+        #[command(flatten)]
+        opt: Opt,
+    },
+
+    /// Check a workflow without running it.
+    ///
+    /// Loads the steps and config, failing on errors, and prints warnings
+    /// about things that look like mistakes, like steps sharing a number.
+    Validate {
+        #[command(flatten)]
+        opt: Opt,
+    },
+
+    /// Write man pages and the config reference to a directory.
+    HelpPages {
+        /// Output directory.
+        #[arg(long)]
+        out: std::path::PathBuf,
+    },
+
+    /// Print a shell completion script.
+    ///
+    /// For completion of workflow and step names too, use dynamic completion
+    /// instead, e.g. `source <(COMPLETE=bash tickbox)`.
+    Completions {
+        /// Shell to generate completions for.
+        shell: clap_complete::Shell,
+    },
+}
+
+impl Opt {
+    /// Plugins to send events to.
+    fn plugins(&self) -> Vec<std::path::PathBuf> {
+        if self.no_plugins {
+            Vec::new()
+        } else {
+            list_plugins()
+        }
+    }
+}
+
+/// Which steps to run.
+#[derive(Clone, Debug)]
+struct StepFilter {
+    matching: regex::Regex,
+    only: Vec<regex::Regex>,
+
+    /// From the `skip` of the `--profile`.
+    skip: Vec<regex::Regex>,
+
+    /// Steps turned off with `--interactive`.
+    deselected: Vec<String>,
+}
+
+impl StepFilter {
+    /// Filter for `--matching`, `--only`, and the step filters of a profile.
+    /// The profile's `only` is replaced by `--only`.
+    fn new(opt: &Opt, profile: &Profile) -> Self {
+        StepFilter {
+            matching: opt.matching.clone(),
+            only: match opt.only.is_empty() {
+                true => profile.only.clone(),
+                false => opt.only.clone(),
+            },
+            skip: profile.skip.clone(),
+            deselected: Vec::new(),
+        }
+    }
+
+    /// Return `true` if the step should run.
+    fn selected(&self, name: &str) -> bool {
+        !self.deselected.iter().any(|d| d == name)
+            && !self.skip.iter().any(|r| r.is_match(name))
+            && self.matching.is_match(name)
+            && (self.only.is_empty() || self.only.iter().any(|r| r.is_match(name)))
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
 struct Opt {
-    /// Directory with workflow scripts.
+    /// Name of a workflow, meaning a subdirectory of the workflow directory.
+    /// E.g. `release` for `.tickbox/release`.
+    #[arg(add = ArgValueCompleter::new(complete_workflows))]
+    name: Option<String>,
+
+    /// Directory with workflow scripts. If not given, look for a `.tickbox`
+    /// directory in the current directory and its parents.
     #[arg(long)]
-    dir: std::path::PathBuf,
+    dir: Option<std::path::PathBuf>,
+
+    /// Workflow file, with all steps defined in its `steps` setting, instead
+    /// of a workflow directory.
+    #[arg(long, conflicts_with_all = ["dir", "name"])]
+    file: Option<std::path::PathBuf>,
 
     /// Directory that tickbox should use as a starting working directory.
-    #[arg(long, default_value = ".")]
-    cwd: std::path::PathBuf,
+    /// Defaults to the current directory, or if `--dir` is not given, the
+    /// directory containing `.tickbox`.
+    #[arg(long)]
+    cwd: Option<std::path::PathBuf>,
 
     /// Only run steps (files) matching regex.
-    #[arg(long, default_value = ".*")]
+    #[arg(long, default_value = ".*", add = ArgValueCompleter::new(complete_steps))]
     matching: regex::Regex,
 
-    /// Wait when done, even if successful.
+    /// Only run steps matching this shell glob, e.g. `30-*`. Can be given
+    /// more than once, to run steps matching any of them.
+    #[arg(long, value_parser = parse_glob, add = ArgValueCompleter::new(complete_steps))]
+    only: Vec<regex::Regex>,
+
+    /// Profile from the `profiles` setting to run with, e.g. `staging`.
     #[arg(long)]
-    wait: bool,
+    profile: Option<String>,
 
-    /// Optionally log to file.
+    /// When to wait for the user when done, instead of exiting. Plain
+    /// `--wait` means always.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_value = "on-failure",
+        default_missing_value = "always"
+    )]
+    wait: WaitMode,
+
+    /// When done and waiting, exit after this long (e.g. `30s`), unless a
+    /// key is pressed.
+    #[arg(long, value_parser=parse_duration)]
+    exit_after: Option<Duration>,
+
+    /// When a step fails, kill the steps running in parallel with it.
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Abort the workflow if it's still running after this long (e.g.
+    /// `45m`), killing the running steps.
+    #[arg(long, value_parser=parse_duration)]
+    workflow_timeout: Option<Duration>,
+
+    /// Ring the terminal bell, and flash the workflow pane, when a step fails
+    /// or the workflow finishes.
+    #[arg(long)]
+    bell: bool,
+
+    /// Don't show how the workflow is going in the terminal title.
+    #[arg(long)]
+    no_title: bool,
+
+    /// Optionally log to file. Previous logs are kept as `<log>.1` and so on.
     #[arg(long, default_value = "/dev/null")]
     log: String,
 
+    /// Log level. Scheduling decisions are logged at `debug`.
+    #[arg(long, env = "TICKBOX_LOG_LEVEL", default_value = "info")]
+    log_level: log::LevelFilter,
+
+    /// Where to log: the `--log` file, syslog, or the systemd journal.
+    #[arg(long, value_enum, default_value_t = LogTarget::File)]
+    log_target: LogTarget,
+
+    /// ID of this run, e.g. from an orchestrator running tickbox, instead of
+    /// a generated one.
+    #[arg(long)]
+    run_id: Option<String>,
+
+    /// Don't run the plugins in `~/.config/tickbox/plugins`.
+    #[arg(long)]
+    no_plugins: bool,
+
+    /// Pick the steps to run, and the order of parallel steps, before
+    /// starting.
+    #[arg(long, conflicts_with = "disable_tui")]
+    interactive: bool,
+
     /// Optionally disable TUI.
     #[arg(long)]
     disable_tui: bool,
 
+    /// Without the TUI, don't start lines of output with the name of the
+    /// step.
+    #[arg(long)]
+    no_prefix: bool,
+
+    /// Without the TUI, only print when steps start and finish, and the
+    /// summary.
+    #[arg(long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Without the TUI, also print the decisions of the scheduler.
+    #[arg(long)]
+    verbose: bool,
+
+    /// Record everything the UI is sent to this file, for `tickbox
+    /// replay`.
+    #[arg(long)]
+    record: Option<std::path::PathBuf>,
+
+    /// Keep the temporary directory when done, and print where it is.
+    #[arg(long)]
+    keep_temp: bool,
+
+    /// Run steps without network access, skipping steps that need it.
+    #[arg(long)]
+    offline: bool,
+
+    /// Refuse to run steps whose SHA-256 checksum doesn't match the
+    /// `tickbox.sha256` file in the workflow directory.
+    #[arg(long)]
+    verify: bool,
+
+    /// Check the minisign signature of `tickbox.sha256` with this public key
+    /// file. Implies `--verify`.
+    #[arg(long)]
+    verify_key: Option<std::path::PathBuf>,
+
     /// Enable parallel ranges.
     #[arg(long, num_args=1, value_delimiter=',', value_parser=parse_range)]
     parallel: Vec<(usize, usize)>,
@@ -53,6 +474,71 @@ struct Opt {
     /// Maximum task concurrency.
     #[arg(long)]
     max_concurrency: Option<usize>,
+
+    /// Directory to write the full output of every step to, as
+    /// `<step>.log`.
+    #[arg(long)]
+    output_dir: Option<std::path::PathBuf>,
+
+    /// Don't start parallel steps while the load average is above this.
+    #[arg(long)]
+    max_load: Option<f64>,
+
+    /// Lock file preventing concurrent runs of the workflow. Defaults to
+    /// `.tickbox.lock` in the workflow directory.
+    #[arg(long)]
+    lock: Option<std::path::PathBuf>,
+
+    /// Color theme: `default`, `ascii`, or `colorblind`. Overrides config.
+    #[arg(long, value_parser=parse_theme)]
+    theme: Option<Theme>,
+
+    /// If the workflow is locked, wait this long (e.g. `30s`, `5m`) for the
+    /// lock before giving up.
+    #[arg(long, value_parser=parse_duration)]
+    wait_for_lock: Option<Duration>,
+
+    /// Instead of running the workflow once, keep running it whenever its
+    /// `schedule` says to, until killed.
+    #[arg(long)]
+    daemon: bool,
+
+    /// OpenTelemetry collector to send a trace of the run to, using OTLP over
+    /// HTTP(S), e.g. `http://localhost:4318`.
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+}
+
+/// Turn a shell glob into an anchored regex. Supports `*`, `?`, `[...]`, and
+/// `[!...]`.
+fn parse_glob(s: &str) -> Result<regex::Regex, String> {
+    let mut re = String::from("^");
+    let mut class = false;
+    for c in s.chars() {
+        match c {
+            '!' if class && re.ends_with('[') => re.push('^'),
+            _ if class => {
+                if c == ']' {
+                    class = false;
+                } else if c == '\\' {
+                    re.push('\\');
+                }
+                re.push(c);
+            }
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                class = true;
+                re.push(c);
+            }
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if class {
+        return Err(format!("Unclosed [ in glob: {s}"));
+    }
+    re.push('$');
+    regex::Regex::new(&re).map_err(|e| format!("Invalid glob {s}: {e}"))
 }
 
 fn parse_range(s: &str) -> Result<(usize, usize), String> {
@@ -73,552 +559,9671 @@ fn parse_range(s: &str) -> Result<(usize, usize), String> {
     Ok((start, end))
 }
 
+/// Parse a duration like `90`, `90s`, `1.5m`, or `2h`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, mult) = if let Some(n) = s.strip_suffix('h') {
+        (n, 3600.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else {
+        (s.strip_suffix('s').unwrap_or(s), 1.0)
+    };
+    let n = num
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite() && *n >= 0.0)
+        .ok_or(format!("Invalid duration: {s}"))?;
+    Ok(Duration::from_secs_f64(n * mult))
+}
+
+/// How to draw one task state in the status pane.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StateStyle {
+    glyph: String,
+    color: Color,
+}
+
+impl StateStyle {
+    fn new(glyph: &str, color: Color) -> Self {
+        Self {
+            glyph: glyph.to_string(),
+            color,
+        }
+    }
+}
+
+/// Glyphs and colors of the status pane.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Theme {
+    pending: StateStyle,
+    running: StateStyle,
+    complete: StateStyle,
+    failed: StateStyle,
+    skipped: StateStyle,
+    aborted: StateStyle,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            pending: StateStyle::new(UNCHECKED, Color::Yellow),
+            running: StateStyle::new(UNCHECKED, Color::Blue),
+            complete: StateStyle::new(CHECKED, Color::Green),
+            failed: StateStyle::new(FAILED, Color::Red),
+            skipped: StateStyle::new(UNCHECKED, Color::Gray),
+            aborted: StateStyle::new(FAILED, Color::Magenta),
+        }
+    }
+}
+
+impl Theme {
+    /// Look up a built in theme.
+    fn named(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Self::default()),
+            // For terminals without unicode.
+            "ascii" => Some(Self {
+                pending: StateStyle::new("[ ]", Color::Yellow),
+                running: StateStyle::new("[~]", Color::Blue),
+                complete: StateStyle::new("[x]", Color::Green),
+                failed: StateStyle::new("[!]", Color::Red),
+                skipped: StateStyle::new("[-]", Color::Gray),
+                aborted: StateStyle::new("[/]", Color::Magenta),
+            }),
+            // Okabe-Ito palette, avoiding red/green.
+            "colorblind" => Some(Self {
+                pending: StateStyle::new(UNCHECKED, Color::Rgb(240, 228, 66)),
+                running: StateStyle::new(UNCHECKED, Color::Rgb(86, 180, 233)),
+                complete: StateStyle::new(CHECKED, Color::Rgb(0, 114, 178)),
+                failed: StateStyle::new(FAILED, Color::Rgb(213, 94, 0)),
+                skipped: StateStyle::new(UNCHECKED, Color::Gray),
+                aborted: StateStyle::new(FAILED, Color::Rgb(204, 121, 167)),
+            }),
+            _ => None,
+        }
+    }
+
+    fn style(&self, state: &State) -> &StateStyle {
+        match state {
+            State::Pending => &self.pending,
+            State::Running(_) => &self.running,
+            State::Complete(_) => &self.complete,
+            State::Failed(_) => &self.failed,
+            State::Skipped => &self.skipped,
+            State::Aborted(_) => &self.aborted,
+        }
+    }
+}
+
+fn parse_theme(s: &str) -> Result<Theme, String> {
+    Theme::named(s).ok_or(format!("Unknown theme: {s}"))
+}
+
 #[derive(Default)]
 struct UiState {
     scroll: usize,
-}
 
-// Render the UI, once.
-fn render(frame: &mut ratatui::Frame, out: &str, status: &[Line], state: &mut UiState) {
-    use ratatui::layout::Layout;
-    use ratatui::prelude::*;
-    use ratatui::widgets::{Block, Paragraph};
+    /// Height of the output pane contents, as of the last render.
+    out_height: usize,
 
-    let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
-        .split(frame.area());
-    let top = chunks[0];
-    let bottom = chunks[1];
+    /// First visible line of the status pane.
+    status_scroll: usize,
 
-    // Render top part.
-    frame.render_widget(
-        Paragraph::new(status.to_owned()).block(Block::bordered().title("Workflow")),
-        top,
-    );
-    let nlines = out.lines().collect::<Vec<_>>().len();
-    state.scroll = state.scroll.clamp(
-        0,
-        nlines.max(bottom.height as usize) - bottom.height as usize + 2,
-    );
+    /// Keep the first running step visible in the status pane.
+    follow: bool,
 
-    // Render bottom part, the command output.
-    use ansi_to_tui::IntoText;
-    let out: Vec<Line> = out
-        .lines()
-        .rev()
-        // Subtract top and bottom border.
-        .skip(state.scroll)
-        .take((bottom.height - 2).into())
-        .collect::<Vec<_>>()
-        .into_iter()
-        .rev()
-        .flat_map(|line| line.into_text().unwrap())
-        .collect::<Vec<_>>();
+    /// Collapse runs of finished steps in the status pane.
+    compact: bool,
 
-    frame.render_widget(
-        Paragraph::new(out).block(Block::bordered().title("Command output")),
-        bottom,
-    );
-}
+    /// Only show the headers of groups without running or failed steps.
+    collapse_groups: bool,
 
-/// A task is one step in a workflow, and therefore one file on disk.
-#[derive(Debug, Clone)]
-struct Task {
-    n: usize,
-    id: usize,
-    name: String,
-    cmd: std::path::PathBuf,
-    state: State,
+    /// Show the steps by parallel group, instead of as a list.
+    graph: bool,
+
+    /// Hide finished steps from the status pane, except failed ones.
+    hide_done: bool,
+
+    /// Wait when done, instead of exiting.
+    wait: bool,
+
+    /// Show the help overlay.
+    help: bool,
+
+    /// Feedback shown in the output pane title, e.g. after saving output.
+    message: Option<String>,
+
+    /// Number of lines of output.
+    lines: usize,
+
+    /// Number of lines of output matching the error regex.
+    errors: usize,
+
+    /// Name of the step currently producing output.
+    streaming: Option<String>,
+
+    /// Estimated time until the workflow is done.
+    remaining: Option<Duration>,
+
+    /// The workflow took, or is expected to take, longer than its budget.
+    over_budget: bool,
+
+    /// Summary of the run, once finished.
+    summary: Option<Vec<Line<'static>>>,
+
+    /// Show the summary instead of the output.
+    show_summary: bool,
+
+    /// Flash the workflow pane border until then.
+    flash_until: Option<Instant>,
+
+    /// Only show steps whose name or state matches.
+    filter: Option<regex::Regex>,
+
+    /// Filter being typed, after pressing `F`.
+    filter_input: Option<String>,
+
+    /// Two steps' outputs shown side by side, instead of all output.
+    split: Option<Split>,
+
+    /// Hide the status pane, giving the output the whole screen.
+    zoom: bool,
+
+    /// Step shown in the details popup.
+    details: Option<usize>,
+
+    /// Exit at this time, unless a key is pressed first.
+    exit_at: Option<Instant>,
 }
 
-/// The state of a task.
-#[derive(Clone, Debug)]
-enum State {
-    Complete(Duration),
-    Failed(Duration),
-    Running(Instant),
-    Pending,
-    Skipped,
+/// Outputs of two steps, side by side.
+struct Split {
+    /// Step numbers shown in the left and right pane.
+    steps: [usize; 2],
+
+    /// Lines scrolled back from the end, in each pane.
+    scroll: [usize; 2],
+
+    /// The pane that scrolling and picking steps apply to.
+    active: usize,
 }
 
-impl std::fmt::Display for State {
-    fn fmt(&self, w: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        match self {
-            State::Pending => write!(w, "Pending"),
-            State::Running(_) => write!(w, "Running"),
-            State::Failed(d) => write!(w, "Failed after {}", format_duration(*d)),
-            State::Complete(d) => write!(w, "Succeeded after {}", format_duration(*d)),
-            State::Skipped => write!(w, "Skipped"),
+impl Split {
+    /// Show the first failed step, or else the running or last step, next to
+    /// the step before it.
+    fn new(status: &[Task]) -> Self {
+        let first = status
+            .iter()
+            .find(|t| matches!(t.state, State::Failed(_)))
+            .or_else(|| status.iter().find(|t| matches!(t.state, State::Running(_))))
+            .or(status.last())
+            .map(|t| t.n)
+            .unwrap_or(0);
+        Self {
+            steps: [first, first.saturating_sub(1)],
+            scroll: [0, 0],
+            active: 0,
         }
     }
+
+    /// Show the next step in the active pane, or the previous if `delta`
+    /// is negative, wrapping around.
+    fn cycle(&mut self, delta: isize, steps: usize) {
+        let steps = steps.max(1) as isize;
+        let n = &mut self.steps[self.active];
+        *n = (*n as isize + delta).rem_euclid(steps) as usize;
+        self.scroll[self.active] = 0;
+    }
 }
 
-/// Return `true` if this is a sync point, that stops parallel steps.
-fn sync_point(
-    task: &Task,
-    running: &[&Task],
-    opt_par: &[(usize, usize)],
-    conf_par_re: &[regex::Regex],
-) -> bool {
-    if !opt_par.is_empty() {
-        // If command line flag ranges are provided, then use that instead of
-        // the config.
-        if let Some(r) = opt_par.iter().find(|r| r.0 <= task.id && task.id <= r.1) {
-            return !running.iter().all(|t| r.0 <= t.id && t.id <= r.1);
+impl UiState {
+    /// Show details of the next step, or the previous if `delta` is
+    /// negative, wrapping around.
+    fn pick_details(&mut self, delta: isize, steps: usize) {
+        if let Some(n) = &mut self.details {
+            *n = (*n as isize + delta).rem_euclid(steps.max(1) as isize) as usize;
         }
-        return true;
     }
-    if let Some(r) = conf_par_re.iter().find(|r| r.is_match(&task.name)) {
-        return !running.iter().all(|t| r.is_match(&t.name));
+
+    /// Move the output view `lines` lines towards the end, or towards the
+    /// start if negative.
+    fn scroll_output(&mut self, lines: isize) {
+        let scroll = match &mut self.split {
+            Some(split) => &mut split.scroll[split.active],
+            None => &mut self.scroll,
+        };
+        *scroll = scroll.saturating_add_signed(-lines);
+    }
+
+    /// Move the status pane view `lines` lines down, or up if negative.
+    fn scroll_status(&mut self, lines: isize) {
+        self.status_scroll = self.status_scroll.saturating_add_signed(lines);
+        self.follow = false;
+    }
+
+    /// How many lines PageUp and PageDown move the output view.
+    fn page(&self) -> isize {
+        self.out_height.saturating_sub(PAGE_OVERLAP).max(1) as isize
     }
-    true
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use regex::Regex;
+/// Return `true` if the filter matches the step name or its state, like
+/// `failure` or `running`.
+fn filter_matches(filter: &regex::Regex, t: &Task) -> bool {
+    filter.is_match(&t.name) || filter.is_match(t.state.short_name())
+}
 
-    #[test]
-    fn sync_test() -> Result<()> {
-        let running = [
-            &Task {
-                n: 0,
-                id: 1,
-                name: "01-first".into(),
-                cmd: "".into(),
-                state: State::Pending,
-            },
-            &Task {
-                n: 1,
-                id: 2,
-                name: "02-second".into(),
-                cmd: "".into(),
-                state: State::Pending,
-            },
-        ];
-        let new = Task {
-            n: 2,
-            id: 3,
-            name: "03-third".into(),
-            cmd: "".into(),
-            state: State::Pending,
+/// Handle a key while typing a filter. Enter applies it, and Esc cancels.
+///
+/// An invalid regex is used as a plain substring.
+fn edit_filter(state: &mut UiState, code: KeyCode) {
+    let Some(input) = &mut state.filter_input else {
+        return;
+    };
+    match code {
+        KeyCode::Char(c) => input.push(c),
+        KeyCode::Backspace => {
+            input.pop();
+        }
+        KeyCode::Enter => {
+            state.filter = match input.as_str() {
+                "" => None,
+                s => Some(
+                    regex::Regex::new(s)
+                        .unwrap_or_else(|_| regex::Regex::new(&regex::escape(s)).unwrap()),
+                ),
+            };
+            state.filter_input = None;
+            state.status_scroll = 0;
+        }
+        KeyCode::Esc => state.filter_input = None,
+        _ => {}
+    }
+}
+
+/// Settings for the TUI.
+struct TuiConfig {
+    theme: Theme,
+
+    /// Output lines matching this are counted as errors.
+    error_regex: regex::Regex,
+
+    /// Styles for output lines. The first match wins.
+    highlights: Vec<Highlight>,
+
+    /// Ring the bell and flash when a step fails, or the workflow finishes.
+    bell: bool,
+
+    /// Show how the workflow is going in the terminal title.
+    title: bool,
+
+    /// Step numbers of each group of steps that may run in parallel.
+    parallel_groups: Vec<Vec<usize>>,
+
+    /// How long steps took the last time they succeeded.
+    estimates: std::collections::HashMap<String, Duration>,
+
+    /// How long steps are expected to take.
+    budget: std::collections::HashMap<String, Duration>,
+
+    /// How long the workflow is expected to take.
+    sla: Option<Duration>,
+
+    /// Maximum number of steps running at the same time.
+    max_concurrency: usize,
+
+    /// Unique ID of this run, for the summary.
+    run_id: String,
+
+    /// Toggles to start out with.
+    ui: UiDefaults,
+
+    /// Key bindings.
+    keys: Keymap,
+
+    /// When to wait for the user when done.
+    wait: WaitMode,
+
+    /// When done and waiting, exit after this long.
+    exit_after: Option<Duration>,
+
+    /// Environment from the config, for the details popup.
+    envs: Vec<(OsString, OsString)>,
+
+    /// What happened to the finished steps, for the details popup.
+    records: Arc<Mutex<Vec<StepRecord>>>,
+}
+
+/// Output lines matching `regex` get drawn in this style.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Highlight {
+    #[serde(deserialize_with = "deserialize_one_regex")]
+    regex: regex::Regex,
+    #[serde(default)]
+    fg: Option<Color>,
+    #[serde(default)]
+    bg: Option<Color>,
+    #[serde(default)]
+    bold: bool,
+}
+
+impl Highlight {
+    fn new(regex: &str, fg: Color, bold: bool) -> Self {
+        Self {
+            regex: regex::Regex::new(regex).expect("invalid built in regex"),
+            fg: Some(fg),
+            bg: None,
+            bold,
+        }
+    }
+
+    fn style(&self) -> Style {
+        let mut style = Style::default();
+        if let Some(fg) = self.fg {
+            style = style.fg(fg);
+        }
+        if let Some(bg) = self.bg {
+            style = style.bg(bg);
+        }
+        if self.bold {
+            style = style.add_modifier(Modifier::BOLD);
+        }
+        style
+    }
+}
+
+/// Highlights used unless configured.
+fn default_highlights() -> Vec<Highlight> {
+    vec![
+        Highlight::new(r"\berror(\[\w+\])?:", Color::Red, true),
+        Highlight::new(r"\bFAILED\b", Color::Red, true),
+        Highlight::new(r"\bwarning(\[\w+\])?:", Color::Yellow, false),
+    ]
+}
+
+/// Title of the output pane, with line counts and such.
+fn output_title(state: &UiState) -> Line<'static> {
+    let mut title = vec![Span::raw("Command output")];
+    if let Some(name) = &state.streaming {
+        title.push(Span::raw(format!(": {name}")));
+    }
+    title.push(Span::raw(if state.scroll > 0 {
+        format!(" [{} lines, {} up]", state.lines, state.scroll)
+    } else {
+        format!(" [{} lines]", state.lines)
+    }));
+    if state.errors > 0 {
+        title.push(Span::raw(" "));
+        title.push(Span::styled(
+            format!(" {} errors ", state.errors),
+            Style::default().fg(Color::White).bg(Color::Red),
+        ));
+    }
+    if let Some(msg) = &state.message {
+        title.push(Span::raw(format!(" - {msg}")));
+    }
+    Line::from(title)
+}
+
+/// Something a key can be bound to in the TUI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    ScrollDown,
+    ScrollUp,
+    PageDown,
+    PageUp,
+    WorkflowDown,
+    WorkflowUp,
+    Follow,
+    Compact,
+    CollapseGroups,
+    Graph,
+    HideDone,
+    Filter,
+    Wait,
+    Zoom,
+    Copy,
+    Save,
+    Summary,
+    Restart,
+    Redraw,
+    Split,
+    SplitSwitch,
+    SplitNext,
+    SplitPrev,
+    Details,
+    Suspend,
+    Help,
+    Quit,
+}
+
+/// Actions, with their default keys and help text.
+const ACTIONS: &[(Action, &[&str], &str)] = &[
+    (Action::ScrollDown, &["j", "Down"], "Scroll output down"),
+    (Action::ScrollUp, &["k", "Up"], "Scroll output up"),
+    (Action::PageDown, &["PageDown"], "Scroll output down a page"),
+    (Action::PageUp, &["PageUp"], "Scroll output up a page"),
+    (Action::WorkflowDown, &["J"], "Scroll workflow down"),
+    (Action::WorkflowUp, &["K"], "Scroll workflow up"),
+    (Action::Follow, &["f"], "Toggle follow mode"),
+    (Action::Compact, &["c"], "Toggle compact mode"),
+    (Action::CollapseGroups, &["g"], "Toggle collapsing groups"),
+    (Action::Graph, &["G"], "Toggle graph view"),
+    (Action::HideDone, &["h"], "Toggle hiding finished steps"),
+    (Action::Filter, &["F"], "Filter workflow by regex"),
+    (Action::Wait, &["w"], "Toggle wait on exit"),
+    (Action::Zoom, &["z"], "Toggle full screen output"),
+    (Action::Copy, &["y"], "Copy output to clipboard"),
+    (Action::Save, &["s"], "Save output to file"),
+    (Action::Summary, &["v"], "Toggle summary, when finished"),
+    (Action::Restart, &["R"], "Restart the workflow"),
+    (Action::Redraw, &["l"], "Redraw the screen"),
+    (Action::Split, &["S"], "Toggle split view of two steps"),
+    (Action::SplitSwitch, &["Tab"], "Switch split view pane"),
+    (Action::SplitNext, &["]"], "Next step in split view pane"),
+    (
+        Action::SplitPrev,
+        &["["],
+        "Previous step in split view pane",
+    ),
+    (
+        Action::Details,
+        &["Enter"],
+        "Toggle step details, j/k to pick the step",
+    ),
+    (Action::Suspend, &["Ctrl-z"], "Suspend to shell"),
+    (Action::Help, &["?"], "Toggle this help"),
+    (Action::Quit, &["q", "Q"], "Exit"),
+];
+
+/// A key press, as far as bindings care.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    code: KeyCode,
+    ctrl: bool,
+}
+
+/// Parse a key like `j`, `PageDown`, or `Ctrl-n`.
+fn parse_key(s: &str) -> Result<Key> {
+    let (ctrl, name) = match s.strip_prefix("Ctrl-") {
+        Some(name) => (true, name),
+        None => (false, s),
+    };
+    let code = match name {
+        "Up" => KeyCode::Up,
+        "Down" => KeyCode::Down,
+        "Left" => KeyCode::Left,
+        "Right" => KeyCode::Right,
+        "PageUp" => KeyCode::PageUp,
+        "PageDown" => KeyCode::PageDown,
+        "Home" => KeyCode::Home,
+        "End" => KeyCode::End,
+        "Enter" => KeyCode::Enter,
+        "Tab" => KeyCode::Tab,
+        "Backspace" => KeyCode::Backspace,
+        "Space" => KeyCode::Char(' '),
+        _ => {
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if ctrl => KeyCode::Char(c.to_ascii_lowercase()),
+                (Some(c), None) => KeyCode::Char(c),
+                _ => return Err(Error::msg(format!("invalid key {s:?}"))),
+            }
+        }
+    };
+    Ok(Key { code, ctrl })
+}
+
+/// Keys bound to TUI actions.
+struct Keymap {
+    keys: std::collections::HashMap<Key, Action>,
+
+    /// Keys and help text, in help overlay order.
+    help: Vec<(String, &'static str)>,
+}
+
+impl Keymap {
+    /// The default bindings, with those in `custom` replacing the defaults
+    /// of their actions.
+    fn new(custom: &std::collections::HashMap<Action, Vec<String>>) -> Result<Self> {
+        let mut keys = std::collections::HashMap::new();
+        let mut help = Vec::new();
+        for (action, defaults, text) in ACTIONS {
+            let names = match custom.get(action) {
+                Some(names) => names.clone(),
+                None => defaults.iter().map(|s| s.to_string()).collect(),
+            };
+            for name in &names {
+                let key = parse_key(name)?;
+                if let Some(other) = keys.insert(key, *action) {
+                    return Err(Error::msg(format!(
+                        "key {name:?} bound to both {other:?} and {action:?}"
+                    )));
+                }
+            }
+            if !names.is_empty() {
+                help.push((names.join(" / "), *text));
+            }
+        }
+        Ok(Self { keys, help })
+    }
+
+    /// The action bound to a key press, if any.
+    fn action(&self, key: &crossterm::event::KeyEvent) -> Option<Action> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let code = match key.code {
+            KeyCode::Char(c) if ctrl => KeyCode::Char(c.to_ascii_lowercase()),
+            code => code,
         };
-        for (a, b, out) in [
-            (vec![], vec![], true),
-            // Test command line.
-            (vec![(0, 1)], vec![], true),
-            (vec![(0, 2)], vec![], true),
-            (vec![(0, 3)], vec![], false),
-            (vec![(0, 4)], vec![], false),
-            (vec![(1, 4)], vec![], false),
-            (vec![(2, 4)], vec![], true),
-            // Test config.
-            (vec![], vec![Regex::new("XXX")?], true),
-            (vec![], vec![Regex::new("^01-")?], true),
-            (vec![], vec![Regex::new("^0[1-2]-")?], true),
-            (vec![], vec![Regex::new("^0[1-3]-")?], false),
-            (vec![], vec![Regex::new("^0[1-4]-")?], false),
-            (vec![], vec![Regex::new("^0[2-4]-")?], true),
-        ] {
-            assert_eq!(
-                sync_point(&new, &running, &a, &b),
-                out,
-                "failed for input {a:?} {b:?} => {out}"
+        self.keys.get(&Key { code, ctrl }).copied()
+    }
+}
+
+/// A rectangle of at most the given size, centered in `area`.
+fn centered(area: ratatui::layout::Rect, width: u16, height: u16) -> ratatui::layout::Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    ratatui::layout::Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    )
+}
+
+/// Render the help overlay.
+fn render_help(frame: &mut ratatui::Frame, state: &UiState, keys: &Keymap) {
+    use ratatui::widgets::{Block, Clear, Paragraph};
+    let onoff = |b: bool| if b { "on" } else { "off" };
+    let keylen = keys.help.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    let mut lines: Vec<Line> = keys
+        .help
+        .iter()
+        .map(|(k, v)| Line::from(format!("{k:<keylen$}  {v}")))
+        .collect();
+    lines.push(Line::from(""));
+    lines.push(Line::from(format!("Follow mode:  {}", onoff(state.follow))));
+    lines.push(Line::from(format!(
+        "Compact mode: {}",
+        onoff(state.compact)
+    )));
+    lines.push(Line::from(format!(
+        "Fold groups:  {}",
+        onoff(state.collapse_groups)
+    )));
+    lines.push(Line::from(format!("Graph view:   {}", onoff(state.graph))));
+    lines.push(Line::from(format!(
+        "Hide done:    {}",
+        onoff(state.hide_done)
+    )));
+    lines.push(Line::from(format!("Wait on exit: {}", onoff(state.wait))));
+    let width = lines.iter().map(|l| l.width()).max().unwrap_or(0) + 4;
+    let area = centered(frame.area(), width as u16, lines.len() as u16 + 2);
+    frame.render_widget(Clear, area);
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title("Help")),
+        area,
+    );
+}
+
+/// Split a line into lines no wider than `width` columns.
+fn wrap_line(line: Line<'static>, width: usize) -> Vec<Line<'static>> {
+    use unicode_width::UnicodeWidthChar;
+    let style = line.style;
+    let mut out = Vec::new();
+    let mut cur = Vec::new();
+    let mut cur_width = 0;
+    for span in line.spans {
+        let mut buf = String::new();
+        for c in span.content.chars() {
+            let w = c.width().unwrap_or(0);
+            if cur_width > 0 && cur_width + w > width {
+                if !buf.is_empty() {
+                    cur.push(Span::styled(std::mem::take(&mut buf), span.style));
+                }
+                out.push(Line::from(std::mem::take(&mut cur)).style(style));
+                cur_width = 0;
+            }
+            buf.push(c);
+            cur_width += w;
+        }
+        if !buf.is_empty() {
+            cur.push(Span::styled(buf, span.style));
+        }
+    }
+    out.push(Line::from(cur).style(style));
+    out
+}
+
+/// Output, both as received and parsed into styled lines.
+#[derive(Default)]
+struct Output {
+    /// The output as received, with ANSI escapes.
+    text: String,
+
+    /// Each line of `text`, parsed and highlighted, so that's only done once.
+    lines: Vec<Line<'static>>,
+}
+
+impl Output {
+    /// Append a line of output, which may contain newlines.
+    fn push(&mut self, line: &str, highlights: &[Highlight]) {
+        use ansi_to_tui::IntoText;
+        self.text += line;
+        self.text += "\n";
+        for line in line.split('\n') {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            let plain = strip_ansi(line);
+            let highlight = highlights.iter().find(|h| h.regex.is_match(&plain));
+            let parsed = line.into_text().unwrap().lines;
+            if parsed.is_empty() {
+                self.lines.push(Line::default());
+            }
+            self.lines.extend(parsed.into_iter().map(|mut l| {
+                if let Some(h) = highlight {
+                    for span in &mut l.spans {
+                        span.style = span.style.patch(h.style());
+                    }
+                }
+                l
+            }));
+        }
+    }
+
+    /// Forget all output.
+    fn clear(&mut self) {
+        self.text.clear();
+        self.lines.clear();
+    }
+}
+
+/// The lines of output to show in a pane of the given size, `scroll` lines
+/// back from the end.
+///
+/// Lines are wrapped to the pane width, starting from the end, and only as
+/// far back as needed to fill the pane at the current scroll position.
+fn wrap_output(
+    out: &[Line<'static>],
+    width: usize,
+    height: usize,
+    scroll: &mut usize,
+) -> Vec<Line<'static>> {
+    let mut wrapped = Vec::new();
+    let mut all_wrapped = true;
+    for line in out.iter().rev() {
+        if wrapped.len() >= *scroll + height {
+            all_wrapped = false;
+            break;
+        }
+        let mut lines = wrap_line(line.clone(), width);
+        lines.reverse();
+        wrapped.extend(lines);
+    }
+    if all_wrapped {
+        *scroll = (*scroll).min(wrapped.len().saturating_sub(height));
+    }
+    wrapped
+        .into_iter()
+        .skip(*scroll)
+        .take(height)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect()
+}
+
+/// Render the status pane, with the steps.
+fn render_status(
+    frame: &mut ratatui::Frame,
+    top: ratatui::layout::Rect,
+    status: &[Line],
+    focus: Option<usize>,
+    state: &mut UiState,
+) {
+    use ratatui::widgets::{Block, Paragraph};
+    let status_height = top.height.saturating_sub(2) as usize;
+    if let Some(focus) = focus.filter(|_| state.follow) {
+        if focus < state.status_scroll {
+            state.status_scroll = focus;
+        } else if focus >= state.status_scroll + status_height {
+            state.status_scroll = focus + 1 - status_height;
+        }
+    }
+    state.status_scroll = state
+        .status_scroll
+        .min(status.len().saturating_sub(status_height));
+    let name = if state.graph {
+        "Workflow graph"
+    } else {
+        "Workflow"
+    };
+    let name = match (&state.filter_input, &state.filter) {
+        (Some(input), _) => format!("{name}, filter: {input}_"),
+        (None, Some(filter)) => format!("{name}, filter: {filter}"),
+        (None, None) => name.to_string(),
+    };
+    let title = if status.len() > status_height {
+        format!(
+            "{name} {}-{}/{}",
+            state.status_scroll + 1,
+            (state.status_scroll + status_height).min(status.len()),
+            status.len()
+        )
+    } else {
+        name.to_string()
+    };
+    let title = match state.remaining {
+        Some(r) => format!("{title} ≈ {} remaining", format_eta(r)),
+        None => title,
+    };
+    let title = match state.over_budget {
+        true => format!("{title} - over time budget"),
+        false => title,
+    };
+    let title = match state.exit_at {
+        Some(t) => format!(
+            "{title} - exiting in {}s, press any key to stay",
+            t.saturating_duration_since(Instant::now())
+                .as_secs_f64()
+                .ceil()
+        ),
+        None => title,
+    };
+    frame.render_widget(
+        Paragraph::new(
+            status
+                .iter()
+                .skip(state.status_scroll)
+                .take(status_height)
+                .cloned()
+                .collect::<Vec<_>>(),
+        )
+        .block(
+            Block::bordered()
+                .title(title)
+                .border_style(match state.flash_until {
+                    Some(_) => Style::default().fg(Color::Red),
+                    None => Style::default(),
+                }),
+        ),
+        top,
+    );
+}
+
+// Render the UI, once.
+//
+// `focus` is the status line to keep visible, when following. `step_out` and
+// `steps` are the output and status of each step, for the split view and
+// details popup.
+#[allow(clippy::too_many_arguments)]
+fn render(
+    frame: &mut ratatui::Frame,
+    out: &Output,
+    step_out: &[Output],
+    steps: &[Task],
+    status: &[Line],
+    focus: Option<usize>,
+    state: &mut UiState,
+    cfg: &TuiConfig,
+) {
+    use ratatui::layout::Layout;
+    use ratatui::prelude::*;
+    use ratatui::widgets::{Block, Paragraph};
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(frame.area());
+    let bottom = if state.zoom {
+        frame.area()
+    } else {
+        render_status(frame, chunks[0], status, focus, state);
+        chunks[1]
+    };
+
+    // Render bottom part, the command output. Subtract top and bottom border.
+    let height = bottom.height.saturating_sub(2) as usize;
+    state.out_height = height;
+    if let Some(split) = &mut state.split {
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .split(bottom);
+        for (pane, area) in panes.iter().enumerate() {
+            let n = split.steps[pane];
+            let text = step_out.get(n).map(|o| o.lines.as_slice()).unwrap_or(&[]);
+            let width = area.width.saturating_sub(2).max(1) as usize;
+            let lines = wrap_output(text, width, height, &mut split.scroll[pane]);
+            let name = steps.get(n).map(|t| t.name.as_str()).unwrap_or("?");
+            let title = if split.scroll[pane] > 0 {
+                format!("{name} [{} up]", split.scroll[pane])
+            } else {
+                name.to_string()
+            };
+            let border = if pane == split.active {
+                Style::default().add_modifier(Modifier::BOLD)
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            frame.render_widget(
+                Paragraph::new(lines).block(Block::bordered().title(title).border_style(border)),
+                *area,
             );
         }
-        Ok(())
+    } else if let Some(summary) = state.summary.as_ref().filter(|_| state.show_summary) {
+        frame.render_widget(
+            Paragraph::new(summary.clone())
+                .wrap(ratatui::widgets::Wrap { trim: false })
+                .block(Block::bordered().title("Summary (v to show output)")),
+            bottom,
+        );
+    } else {
+        let width = bottom.width.saturating_sub(2).max(1) as usize;
+        let out = wrap_output(&out.lines, width, height, &mut state.scroll);
+        frame.render_widget(
+            Paragraph::new(out).block(Block::bordered().title(output_title(state))),
+            bottom,
+        );
+    }
+    if let Some(task) = state.details.and_then(|n| steps.get(n)) {
+        let output = step_out.get(task.n).map(|o| o.lines.as_slice());
+        render_details(frame, task, output.unwrap_or(&[]), cfg);
+    }
+    if state.help {
+        render_help(frame, state, &cfg.keys);
+    }
+}
+
+/// Number of lines of output in the details popup.
+const DETAILS_LINES: usize = 20;
+
+/// Render the details popup of a step.
+fn render_details(
+    frame: &mut ratatui::Frame,
+    task: &Task,
+    output: &[Line<'static>],
+    cfg: &TuiConfig,
+) {
+    use ratatui::widgets::{Block, Clear, Paragraph, Wrap};
+    let record = cfg
+        .records
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|r| r.name == task.name)
+        .cloned();
+    let lines = details_lines(
+        task,
+        record.as_ref(),
+        &cfg.envs,
+        cfg.estimates.get(&task.name).copied(),
+        output,
+    );
+    let area = frame.area();
+    let area = centered(area, area.width.saturating_sub(8), lines.len() as u16 + 2);
+    frame.render_widget(Clear, area);
+    frame.render_widget(
+        Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered().title(format!("{} (Esc to close)", task.name))),
+        area,
+    );
+}
+
+/// What's known about a step: how it's run, how it went, and the end of
+/// its output.
+fn details_lines(
+    task: &Task,
+    record: Option<&StepRecord>,
+    envs: &[(OsString, OsString)],
+    estimate: Option<Duration>,
+    output: &[Line<'static>],
+) -> Vec<Line<'static>> {
+    let field = |name: &str, value: String| Line::from(format!("{name:<14}{value}"));
+    let mut lines = vec![match &task.inline {
+        Some(inline) => field("Command:", format!("bash -c {inline:?}")),
+        None => field("Command:", task.cmd.display().to_string()),
+    }];
+    lines.push(field("State:", task.state.describe()));
+    let started = match (&task.state, record) {
+        (State::Running(st), _) => std::time::SystemTime::now().checked_sub(since(*st)),
+        (_, Some(r)) => Some(r.started_at),
+        _ => None,
+    };
+    if let Some(t) = started {
+        lines.push(field("Started:", format_time(t)));
+    }
+    if let Some(r) = record {
+        lines.push(field("Finished:", format_time(r.started_at + r.took)));
+        let code = r.exit_code.map_or("none".to_string(), |c| c.to_string());
+        lines.push(field("Exit code:", code));
+        lines.push(field("Attempts:", r.attempts.to_string()));
+    }
+    if let Some(e) = estimate {
+        lines.push(field(
+            "Last success:",
+            format_duration(e).trim().to_string(),
+        ));
+    }
+
+    // Only what's different from tickbox's own environment.
+    let envs: std::collections::BTreeMap<&OsString, &OsString> =
+        envs.iter().chain(&task.envs).map(|(k, v)| (k, v)).collect();
+    let changed: Vec<_> = envs
+        .into_iter()
+        .filter(|(k, v)| std::env::var_os(k).as_ref() != Some(*v))
+        .collect();
+    if !changed.is_empty() {
+        lines.push(Line::from(""));
+        lines.push(Line::from("Environment:"));
+        for (k, v) in changed {
+            lines.push(Line::from(format!(
+                "  {}={}",
+                k.to_string_lossy(),
+                v.to_string_lossy()
+            )));
+        }
+    }
+    if !output.is_empty() {
+        lines.push(Line::from(""));
+        lines.push(Line::from("Output:"));
+        let skip = output.len().saturating_sub(DETAILS_LINES);
+        lines.extend(output[skip..].iter().cloned());
+    }
+    lines
+}
+
+/// Local time, like `2024-01-02 15:04:05`.
+fn format_time(t: std::time::SystemTime) -> String {
+    let secs = match t.duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as libc::time_t,
+        Err(_) => return "?".to_string(),
+    };
+    // SAFETY: localtime_r only writes to the tm it's given.
+    let tm = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&secs, &mut tm).is_null() {
+            return "?".to_string();
+        }
+        tm
+    };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// A task is one step in a workflow, and therefore one file on disk.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Task {
+    n: usize,
+    id: usize,
+    name: String,
+    cmd: std::path::PathBuf,
+    state: State,
+
+    /// Name of the group (subdirectory) the step is in, if any.
+    group: Option<String>,
+
+    /// Environment from the config of the group, if it's a workflow.
+    envs: Vec<(OsString, OsString)>,
+
+    /// Instance of a matrix step, which can run in parallel with the others.
+    matrix: bool,
+
+    /// The step is supposed to fail, and is a failure if it succeeds.
+    expected_failure: bool,
+
+    /// Shell command from the config, run instead of `cmd`.
+    inline: Option<String>,
+
+    /// Running, but hasn't produced output for its `stall_timeout`.
+    stalled: bool,
+
+    /// How far along the step says it is, in percent.
+    progress: Option<u8>,
+
+    /// Number of warnings the step has printed.
+    warnings: usize,
+
+    /// Sub-items the step has said it's working on, and whether they're
+    /// done.
+    checklist: Vec<(String, bool)>,
+}
+
+impl Task {
+    /// Name to show in the status pane, indented if in a group.
+    fn display_name(&self) -> String {
+        match &self.group {
+            Some(group) => format!("  {}", &self.name[group.len() + 1..]),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Mark a sub-item as done, by default the latest one that isn't.
+    fn check_done(&mut self, item: Option<&str>) {
+        let open = self
+            .checklist
+            .iter_mut()
+            .rev()
+            .find(|(name, done)| !done && item.is_none_or(|i| i == name));
+        if let Some((_, done)) = open {
+            *done = true;
+        }
+    }
+}
+
+/// The state of a task.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+enum State {
+    Complete(Duration),
+    Failed(Duration),
+    Running(
+        #[serde(
+            serialize_with = "serialize_instant",
+            deserialize_with = "deserialize_instant"
+        )]
+        Instant,
+    ),
+    Pending,
+    Skipped,
+
+    /// Killed because another step failed.
+    Aborted(Duration),
+}
+
+impl std::fmt::Display for State {
+    fn fmt(&self, w: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            State::Pending => write!(w, "Pending"),
+            State::Running(_) => write!(w, "Running"),
+            State::Failed(d) => write!(w, "Failed after {}", format_duration(*d)),
+            State::Complete(d) => write!(w, "Succeeded after {}", format_duration(*d)),
+            State::Skipped => write!(w, "Skipped"),
+            State::Aborted(d) => write!(w, "Aborted after {}", format_duration(*d)),
+        }
+    }
+}
+
+impl State {
+    /// Like `to_string()`, but without padding the duration.
+    fn describe(&self) -> String {
+        self.to_string()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Short lower case name, e.g. for env vars.
+    fn short_name(&self) -> &'static str {
+        match self {
+            State::Pending => "pending",
+            State::Running(_) => "running",
+            State::Failed(_) => "failure",
+            State::Complete(_) => "success",
+            State::Skipped => "skipped",
+            State::Aborted(_) => "aborted",
+        }
+    }
+}
+
+/// Return `true` if this is a sync point, that stops parallel steps.
+fn sync_point(
+    task: &Task,
+    running: &[&Task],
+    opt_par: &[(usize, usize)],
+    conf_par_re: &[regex::Regex],
+) -> bool {
+    if task.matrix && running.iter().all(|t| t.matrix && t.cmd == task.cmd) {
+        // Instances of the same matrix step.
+        return false;
+    }
+    if !opt_par.is_empty() {
+        // If command line flag ranges are provided, then use that instead of
+        // the config.
+        if let Some(r) = opt_par.iter().find(|r| r.0 <= task.id && task.id <= r.1) {
+            return !running.iter().all(|t| r.0 <= t.id && t.id <= r.1);
+        }
+        return true;
+    }
+    if let Some(r) = conf_par_re.iter().find(|r| r.is_match(&task.name)) {
+        return !running.iter().all(|t| r.is_match(&t.name));
+    }
+    true
+}
+
+/// Order to start the steps in, as indices. Parallel groups are started
+/// highest priority first, and are otherwise in the original order.
+fn schedule_order(
+    steps: &[Task],
+    opt_par: &[(usize, usize)],
+    conf_par_re: &[regex::Regex],
+    priority: &std::collections::HashMap<String, i64>,
+) -> Vec<usize> {
+    let prio = |t: &&Task| std::cmp::Reverse(priority.get(&t.name).copied().unwrap_or(0));
+    let mut order = Vec::new();
+    for mut group in parallel_groups(steps, opt_par, conf_par_re) {
+        group.sort_by_key(prio);
+        order.extend(group.iter().map(|t| t.n));
+    }
+    order
+}
+
+/// Split the steps into groups that may run in parallel.
+fn parallel_groups<'a>(
+    steps: &'a [Task],
+    opt_par: &[(usize, usize)],
+    conf_par_re: &[regex::Regex],
+) -> Vec<Vec<&'a Task>> {
+    let mut groups = Vec::new();
+    let mut group: Vec<&Task> = Vec::new();
+    for s in steps {
+        if sync_point(s, &group, opt_par, conf_par_re) && !group.is_empty() {
+            groups.push(std::mem::take(&mut group));
+        }
+        group.push(s);
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    /// Step `n` of a test workflow, with the id taken from the name prefix.
+    fn test_task(n: usize, name: &str, state: State) -> Task {
+        Task {
+            n,
+            id: parse_usize_prefix(name).unwrap_or(n),
+            name: name.to_string(),
+            cmd: name.into(),
+            state,
+            group: None,
+            envs: Vec::new(),
+            matrix: false,
+            expected_failure: false,
+            inline: None,
+            stalled: false,
+            progress: None,
+            warnings: 0,
+            checklist: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn cli_test() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn schedule_test() -> Result<()> {
+        let at = |year, month, day, hour, minute| CronTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+        };
+        // 2026-10-16 is a Friday.
+        let t = at(2026, 10, 16, 12, 30);
+        assert_eq!(t.weekday(), 5);
+        assert_eq!(at(2000, 2, 29, 0, 0).weekday(), 2);
+        for (schedule, want) in [
+            ("0 3 * * *", Some(at(2026, 10, 17, 3, 0))),
+            ("@hourly", Some(at(2026, 10, 16, 13, 0))),
+            ("*/15 * * * *", Some(at(2026, 10, 16, 12, 45))),
+            ("5/20 12 * * *", Some(at(2026, 10, 16, 12, 45))),
+            ("0 0 * * 7", Some(at(2026, 10, 18, 0, 0))),
+            ("0 0 * * 1-5", Some(at(2026, 10, 19, 0, 0))),
+            ("0 0 1 * 5", Some(at(2026, 10, 23, 0, 0))),
+            ("0 0 1,31 * *", Some(at(2026, 10, 31, 0, 0))),
+            ("0 0 29 2 *", Some(at(2028, 2, 29, 0, 0))),
+            ("@yearly", Some(at(2027, 1, 1, 0, 0))),
+            ("0 0 30 2 *", None),
+        ] {
+            let schedule: Schedule = schedule.parse()?;
+            assert_eq!(schedule.next_after(t), want, "{schedule:?}");
+        }
+        assert_eq!(
+            "30 12 * * *".parse::<Schedule>()?.next_after(t),
+            Some(at(2026, 10, 17, 12, 30))
+        );
+        for bad in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "x * * * *",
+        ] {
+            assert!(bad.parse::<Schedule>().is_err(), "{bad}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_duration_test() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("5d").is_err());
+    }
+
+    #[test]
+    fn parse_ioprio_test() {
+        assert_eq!(parse_ioprio("idle"), Ok((3 << 13) | 4));
+        assert_eq!(parse_ioprio("best-effort:7"), Ok((2 << 13) | 7));
+        assert!(parse_ioprio("best-effort:8").is_err());
+        assert!(parse_ioprio("whenever").is_err());
+    }
+
+    #[tokio::test]
+    async fn line_reader_test() -> Result<()> {
+        let data: &[u8] = b"abc\r\n\xffd\nefghij";
+        let mut r = LineReader::new(data, 4);
+        let mut got = Vec::new();
+        while let Some(line) = r.next_line().await? {
+            got.push(line);
+        }
+        assert_eq!(got, vec!["abc", "\u{fffd}d", "efgh", "ij"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workflow_timeout_test() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::TempDir::new()?;
+        let log = dir.path().join("hook.log");
+        let hook = dir.path().join("after.sh");
+        std::fs::write(
+            &hook,
+            format!(
+                "#!/bin/sh\necho \"$TICKBOX_STEP_NAME $TICKBOX_STEP_RESULT\" >> '{}'\n",
+                log.display()
+            ),
+        )?;
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755))?;
+        let opt = Cli::parse_from(["tickbox", "--no-plugins", "--workflow-timeout", "1s"]).run;
+        let conf = Config {
+            after_each: Some(hook),
+            ..Default::default()
+        };
+        let steps = vec![Task {
+            inline: Some("sleep 30".into()),
+            ..test_task(0, "10-slow", State::Pending)
+        }];
+        let (tx, mut rx) = ui_channel(100);
+        tokio::spawn(async move { while rx.recv_many().await.is_some() {} });
+        let filter = StepFilter::new(&opt, &Profile::default());
+        let ok = run_workflow(
+            steps,
+            opt,
+            conf,
+            filter,
+            Offline::Online,
+            1,
+            tx,
+            Arc::default(),
+            Arc::default(),
+        )
+        .await;
+        assert!(!ok);
+        assert_eq!(std::fs::read_to_string(&log)?, "10-slow aborted\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_plugin_test() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::TempDir::new()?;
+        let plugin = |name: &str, script: &str| -> Result<std::path::PathBuf> {
+            let path = dir.path().join(name);
+            std::fs::write(&path, script)?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+            Ok(path)
+        };
+        // Doesn't read the event, which is too big for the pipe buffer.
+        let veto = plugin("veto", "#!/bin/sh\necho '{\"veto\": \"frozen\"}'\n")?;
+        let event = serde_json::json!({"event": "step_started", "step": "x".repeat(1 << 20)});
+        let got = run_plugin(&veto, &event, &[], PLUGIN_TIMEOUT).await?;
+        assert_eq!(got.veto.as_deref(), Some("frozen"));
+
+        let slow = plugin("slow", "#!/bin/sh\nexec sleep 30\n")?;
+        let started = Instant::now();
+        let err = run_plugin(&slow, &event, &[], Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("timed out"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_command_path_test() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("a dir $HOME");
+        std::fs::create_dir(&path)?;
+        let path = path.join("10-step; false");
+        std::fs::write(&path, "#!/bin/sh\nexit \"$1\"\n")?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        let (tx, mut rx) = ui_channel(100);
+        tokio::spawn(async move { while rx.recv_many().await.is_some() {} });
+        for (shell, arg, want) in [(false, "0", 0), (false, "3", 3), (true, "3", 3)] {
+            let run = RunOptions {
+                args: vec![arg.to_string()],
+                shell,
+                ..Default::default()
+            };
+            let (got, _) = run_command(0, "test", &path, &[], &run, tx.clone()).await?;
+            assert_eq!(got, Some(want), "shell={shell} arg={arg}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_glob_test() -> Result<(), String> {
+        let glob = parse_glob("1?-*.sh")?;
+        assert!(glob.is_match("10-build.sh"));
+        assert!(!glob.is_match("110-build.sh"));
+        assert!(!glob.is_match("10-build.sh~"));
+        assert!(parse_glob("[0-2]0-*")?.is_match("20-test"));
+        assert!(parse_glob("a.b")?.is_match("a.b"));
+        assert!(!parse_glob("a.b")?.is_match("axb"));
+        assert!(!parse_glob("[!0-2]0-*")?.is_match("20-test"));
+        assert!(parse_glob("[0-2").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn new_step_id_test() -> Result<()> {
+        assert_eq!(new_step_id(&[], None)?, 10);
+        assert_eq!(new_step_id(&[10, 20, 30], None)?, 40);
+        assert_eq!(new_step_id(&[10, 20, 30], Some(10))?, 15);
+        assert_eq!(new_step_id(&[10, 20, 30], Some(30))?, 40);
+        assert_eq!(new_step_id(&[10, 20, 30], Some(5))?, 7);
+        assert!(new_step_id(&[10, 11], Some(10)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn renumber_test() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        for name in ["1-a", "5-b", "5-c", "10-d", "07-e"] {
+            std::fs::write(dir.path().join(name), "")?;
+        }
+        std::fs::write(dir.path().join("tickbox.json"), r#"{"nice": {"5-b": 5}}"#)?;
+        renumber(Some(dir.path().to_path_buf()), 10, false)?;
+        let names: Vec<String> = load_tasks(dir.path(), &[])?
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        assert_eq!(names.len(), 5);
+        for name in ["10-a", "20-b", "20-c", "30-e", "40-d"] {
+            assert!(
+                names.contains(&name.to_string()),
+                "{name} missing from {names:?}"
+            );
+        }
+        assert_eq!(load_config(dir.path())?.nice.get("20-b"), Some(&5));
+        Ok(())
+    }
+
+    #[test]
+    fn wrap_line_test() {
+        let line = Line::from(vec![Span::raw("abc"), Span::raw("defg")]);
+        let got: Vec<String> = wrap_line(line, 3).iter().map(|l| l.to_string()).collect();
+        assert_eq!(got, vec!["abc", "def", "g"]);
+        assert_eq!(wrap_line(Line::from(""), 3).len(), 1);
+    }
+
+    #[test]
+    fn sync_test() -> Result<()> {
+        let running = [
+            &test_task(0, "01-first", State::Pending),
+            &test_task(1, "02-second", State::Pending),
+        ];
+        let new = test_task(2, "03-third", State::Pending);
+        for (a, b, out) in [
+            (vec![], vec![], true),
+            // Test command line.
+            (vec![(0, 1)], vec![], true),
+            (vec![(0, 2)], vec![], true),
+            (vec![(0, 3)], vec![], false),
+            (vec![(0, 4)], vec![], false),
+            (vec![(1, 4)], vec![], false),
+            (vec![(2, 4)], vec![], true),
+            // Test config.
+            (vec![], vec![Regex::new("XXX")?], true),
+            (vec![], vec![Regex::new("^01-")?], true),
+            (vec![], vec![Regex::new("^0[1-2]-")?], true),
+            (vec![], vec![Regex::new("^0[1-3]-")?], false),
+            (vec![], vec![Regex::new("^0[1-4]-")?], false),
+            (vec![], vec![Regex::new("^0[2-4]-")?], true),
+        ] {
+            assert_eq!(
+                sync_point(&new, &running, &a, &b),
+                out,
+                "failed for input {a:?} {b:?} => {out}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn schedule_order_test() {
+        let steps: Vec<Task> = ["01-a", "02-b", "02-c", "03-d"]
+            .iter()
+            .enumerate()
+            .map(|(n, name)| test_task(n, name, State::Pending))
+            .collect();
+        let priority = [("02-c".to_string(), 10), ("03-d".to_string(), 20)]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            schedule_order(&steps, &[(2, 2)], &[], &priority),
+            vec![0, 2, 1, 3]
+        );
+        // Priority doesn't reorder across groups.
+        assert_eq!(
+            schedule_order(&steps, &[], &[], &priority),
+            vec![0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn estimate_remaining_test() {
+        let steps: Vec<Task> = ["01-a", "02-b", "02-c", "02-d", "03-e"]
+            .iter()
+            .enumerate()
+            .map(|(n, name)| test_task(n, name, State::Pending))
+            .collect();
+        let groups = vec![vec![0], vec![1, 2, 3], vec![4]];
+        let estimates = [("01-a", 10), ("02-b", 30), ("02-c", 20), ("02-d", 20)]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), Duration::from_secs(v)))
+            .collect();
+        let secs = |max| estimate_remaining(&steps, &groups, &estimates, max).map(|d| d.as_secs());
+        assert_eq!(secs(1), Some(80));
+        // Limited by the slowest step.
+        assert_eq!(secs(3), Some(40));
+        // Limited by the total, spread over two.
+        let mut steps = steps;
+        steps[0].state = State::Complete(Duration::from_secs(10));
+        let secs = |max| estimate_remaining(&steps, &groups, &estimates, max).map(|d| d.as_secs());
+        assert_eq!(secs(2), Some(35));
+        assert_eq!(
+            estimate_remaining(&steps, &groups, &Default::default(), 1),
+            None
+        );
+        assert_eq!(format_eta(Duration::from_millis(1500)), "2s");
+        assert_eq!(format_eta(Duration::from_secs(61)), "2m");
+        assert_eq!(format_eta(Duration::from_secs(3720)), "1h02m");
+    }
+
+    #[test]
+    fn journal_entry_test() {
+        assert_eq!(
+            journal_entry(6, "hello"),
+            b"PRIORITY=6\nSYSLOG_IDENTIFIER=tickbox\nMESSAGE=hello\n"
+        );
+        assert_eq!(
+            journal_entry(3, "a\nb"),
+            b"PRIORITY=3\nSYSLOG_IDENTIFIER=tickbox\nMESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n"
+        );
+    }
+
+    #[test]
+    fn email_curl_config_test() -> Result<()> {
+        let email: EmailNotification = serde_json::from_str(
+            r#"{"host": "smtp.example.com:587", "from": "ci@example.com",
+                "to": ["a@example.com", "b@example.com"]}"#,
+        )?;
+        assert_eq!(
+            email.curl_config()?,
+            "url = \"smtp://smtp.example.com:587\"\n\
+             mail-from = \"ci@example.com\"\n\
+             ssl-reqd\n\
+             mail-rcpt = \"a@example.com\"\n\
+             mail-rcpt = \"b@example.com\"\n"
+        );
+        let email: EmailNotification = serde_json::from_str(
+            r#"{"host": "smtp.example.com", "tls": "tls", "from": "ci@example.com",
+                "to": ["a@example.com"], "username": "ci", "password_env": "PATH"}"#,
+        )?;
+        let path = std::env::var("PATH")?;
+        assert!(
+            email
+                .curl_config()?
+                .ends_with(&format!("user = \"ci:{path}\"\n"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn email_mail_test() -> Result<()> {
+        let email: EmailNotification = serde_json::from_str(
+            r#"{"host": "smtp.example.com", "from": "ci@example.com", "to": ["a@example.com"]}"#,
+        )?;
+        let mail = email.mail("tickbox: x\r\nBcc: evil@example.com failed", "a\nb");
+        assert_eq!(
+            mail,
+            "From: ci@example.com\r\nTo: a@example.com\r\n\
+             Subject: tickbox: x  Bcc: evil@example.com failed\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\r\na\r\nb\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn curl_json_command_test() {
+        let headers = ["Authorization: Bearer secret-token".to_string()];
+        let (command, config) = curl_json_command(
+            "PUT",
+            "https://hooks.example.com/secret-path",
+            &headers,
+            std::path::Path::new("/tmp/body.json"),
+        );
+        let args: Vec<_> = command.get_args().collect();
+        assert!(
+            args.iter().all(|a| !a.to_string_lossy().contains("secret")),
+            "{args:?}"
+        );
+        assert!(args.contains(&std::ffi::OsStr::new("@/tmp/body.json")));
+        assert_eq!(
+            config,
+            "url = \"https://hooks.example.com/secret-path\"\n\
+             header = \"Content-Type: application/json\"\n\
+             header = \"Authorization: Bearer secret-token\"\n"
+        );
+        assert_eq!(curl_quote("a\"b\\c\nd"), r#""a\"b\\c\nd""#);
+    }
+
+    #[test]
+    fn percent_encode_test() {
+        assert_eq!(percent_encode("!abc:example.com"), "%21abc%3Aexample.com");
+        assert_eq!(percent_encode("a b/c~"), "a%20b%2Fc~");
+    }
+
+    #[test]
+    fn compute_envs_test() -> Result<()> {
+        let scripts = [
+            ("A", r#"run("echo hello; echo")"#),
+            ("B", r#"env.A + " world""#),
+            ("C", "env.X.parse_int() + 1"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let mut envs = vec![("X".into(), "1".into())];
+        compute_envs(&scripts, &mut envs)?;
+        assert_eq!(
+            envs,
+            [
+                ("X".into(), "1".into()),
+                ("A".into(), "hello".into()),
+                ("B".into(), "hello world".into()),
+                ("C".into(), "2".into())
+            ]
+        );
+        for script in [r#"run("false")"#, "env.X +", "loop {}"] {
+            let fail = [("D".to_string(), script.to_string())]
+                .into_iter()
+                .collect();
+            assert!(compute_envs(&fail, &mut envs).is_err(), "{script}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_condition_test() -> Result<()> {
+        let envs = [("TICKBOX_BRANCH".into(), "main".into())];
+        assert!(check_condition(r#"env.TICKBOX_BRANCH == "main""#, &envs).await?);
+        assert!(!check_condition(r#"env.TICKBOX_BRANCH.starts_with("rel")"#, &envs).await?);
+        assert!(!check_condition(r#"env.DEPLOY == "yes""#, &envs).await?);
+        assert!(!check_condition("env.DEPLOY != ()", &envs).await?);
+        assert!(check_condition("env.TICKBOX_BRANCH != ()", &envs).await?);
+        assert!(check_condition(r#"run("echo $TICKBOX_BRANCH") == "main""#, &envs).await?);
+        assert!(check_condition(r#""not a bool""#, &envs).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn picker_test() {
+        let steps: Vec<Task> = ["01-a", "02-b", "02-c", "02-d", "03-e"]
+            .iter()
+            .enumerate()
+            .map(|(n, name)| test_task(n, name, State::Pending))
+            .collect();
+        let groups = parallel_groups(&steps, &[(2, 2)], &[]);
+        let mut picker = Picker::new(&groups);
+        // Can't move out of the group.
+        picker.shift(1);
+        assert_eq!(picker.order, [0, 1, 2, 3, 4]);
+        picker.down();
+        picker.shift(-1);
+        assert_eq!(picker.order, [0, 1, 2, 3, 4]);
+        picker.shift(1);
+        picker.shift(1);
+        picker.shift(1);
+        assert_eq!(picker.order, [0, 2, 3, 1, 4]);
+        picker.toggle();
+        assert_eq!(picker.deselected(&steps), ["02-b"]);
+        assert_eq!(
+            picker.priorities(&steps),
+            [
+                ("02-c".to_string(), 4),
+                ("02-d".to_string(), 3),
+                ("02-b".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn keymap_test() {
+        use crossterm::event::KeyEvent;
+        assert_eq!(
+            parse_key("Ctrl-N").unwrap(),
+            Key {
+                code: KeyCode::Char('n'),
+                ctrl: true
+            }
+        );
+        assert_eq!(parse_key("PageDown").unwrap().code, KeyCode::PageDown);
+        assert!(parse_key("Ctrl-").is_err());
+        assert!(parse_key("PgDn").is_err());
+
+        let keys = Keymap::new(&std::collections::HashMap::new()).unwrap();
+        let press = |code, modifiers| KeyEvent::new(code, modifiers);
+        assert_eq!(
+            keys.action(&press(KeyCode::Char('j'), KeyModifiers::NONE)),
+            Some(Action::ScrollDown)
+        );
+        assert_eq!(
+            keys.action(&press(KeyCode::Char('J'), KeyModifiers::SHIFT)),
+            Some(Action::WorkflowDown)
+        );
+        assert_eq!(
+            keys.action(&press(KeyCode::Char('z'), KeyModifiers::CONTROL)),
+            Some(Action::Suspend)
+        );
+        assert_eq!(
+            keys.action(&press(KeyCode::Char('x'), KeyModifiers::NONE)),
+            None
+        );
+
+        // Swapping keys is fine, but binding one twice is not.
+        let custom = |pairs: &[(Action, &[&str])]| -> std::collections::HashMap<_, _> {
+            pairs
+                .iter()
+                .map(|(a, k)| (*a, k.iter().map(|s| s.to_string()).collect()))
+                .collect()
+        };
+        let keys = Keymap::new(&custom(&[
+            (Action::ScrollDown, &["k"]),
+            (Action::ScrollUp, &["j"]),
+        ]))
+        .unwrap();
+        assert_eq!(
+            keys.action(&press(KeyCode::Char('k'), KeyModifiers::NONE)),
+            Some(Action::ScrollDown)
+        );
+        assert_eq!(keys.action(&press(KeyCode::Down, KeyModifiers::NONE)), None);
+        assert!(Keymap::new(&custom(&[(Action::ScrollDown, &["k"])])).is_err());
+    }
+
+    #[test]
+    fn scroll_test() {
+        let mut state = UiState {
+            out_height: 20,
+            ..Default::default()
+        };
+        assert_eq!(state.page(), 18);
+        state.scroll_output(-state.page());
+        assert_eq!(state.scroll, 18);
+        state.scroll_output(1);
+        assert_eq!(state.scroll, 17);
+        state.scroll_output(state.page());
+        assert_eq!(state.scroll, 0);
+
+        // Tiny panes still move.
+        state.out_height = 1;
+        assert_eq!(state.page(), 1);
+
+        state.follow = true;
+        state.scroll_status(-1);
+        assert_eq!(state.status_scroll, 0);
+        assert!(!state.follow);
+    }
+
+    #[test]
+    fn split_test() {
+        let task = |n, state| Task {
+            id: n,
+            ..test_task(n, &format!("{n}0-step"), state)
+        };
+        let mut status = vec![
+            task(0, State::Complete(Duration::ZERO)),
+            task(1, State::Complete(Duration::ZERO)),
+            task(2, State::Pending),
+        ];
+        assert_eq!(Split::new(&status).steps, [2, 1]);
+        status[2].state = State::Failed(Duration::ZERO);
+        let mut split = Split::new(&status);
+        assert_eq!(split.steps, [2, 1]);
+        split.scroll = [5, 5];
+        split.cycle(1, status.len());
+        assert_eq!(split.steps, [0, 1]);
+        assert_eq!(split.scroll, [0, 5]);
+        split.active = 1;
+        split.cycle(-2, status.len());
+        assert_eq!(split.steps, [0, 2]);
+        assert_eq!(Split::new(&[]).steps, [0, 0]);
+    }
+
+    #[test]
+    fn output_test() {
+        let mut out = Output::default();
+        out.push("one\n\x1b[31mtwo\x1b[0m", &default_highlights());
+        out.push("", &[]);
+        out.push("error: three", &default_highlights());
+        assert_eq!(out.text, "one\n\x1b[31mtwo\x1b[0m\n\nerror: three\n");
+        assert_eq!(out.lines.len(), 4);
+        assert_eq!(out.lines[1].to_string(), "two");
+        assert_eq!(out.lines[1].spans[0].style.fg, Some(Color::Red));
+        assert!(
+            out.lines[3].spans[0]
+                .style
+                .add_modifier
+                .contains(Modifier::BOLD)
+        );
+
+        // Wrapped from the end, only as far back as needed.
+        let mut scroll = 0;
+        let lines = wrap_output(&out.lines, 5, 2, &mut scroll);
+        let text: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        assert_eq!(text, vec![": thr", "ee"]);
+        let mut scroll = 100;
+        let lines = wrap_output(&out.lines, 5, 2, &mut scroll);
+        assert_eq!(scroll, 4);
+        assert_eq!(lines[0].to_string(), "one");
+
+        out.clear();
+        assert!(out.lines.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ui_channel_test() {
+        let (tx, mut rx) = ui_channel(2);
+        let line = |s: &str| UIUpdate::AddLine(0, s.to_string());
+        tx.send(line("one")).await.unwrap();
+        tx.send(UIUpdate::Wait).await.unwrap();
+        tx.send(line("two")).await.unwrap();
+        tx.send(line("three")).await.unwrap();
+        let text = |updates: Vec<UIUpdate>| -> Vec<String> {
+            updates
+                .into_iter()
+                .map(|u| match u {
+                    UIUpdate::AddLine(_, l) => l,
+                    UIUpdate::Wait => "wait".to_string(),
+                    _ => "other".to_string(),
+                })
+                .collect()
+        };
+        assert_eq!(
+            text(rx.recv_many().await.unwrap()),
+            vec![
+                "[1 lines of output dropped, because the UI fell behind]",
+                "wait",
+                "two",
+                "three"
+            ]
+        );
+
+        // Everything sent before the senders are gone is still received.
+        let tx2 = tx.clone();
+        tx2.send(line("four")).await.unwrap();
+        drop(tx);
+        drop(tx2);
+        assert_eq!(text(rx.recv_many().await.unwrap()), vec!["four"]);
+        assert!(rx.recv_many().await.is_none());
+
+        let (tx, rx) = ui_channel(2);
+        drop(rx);
+        assert!(tx.send(UIUpdate::Wait).await.is_err());
+    }
+
+    #[test]
+    fn duplicate_ids_test() {
+        let task = |id, name: &str, group: Option<&str>| Task {
+            id,
+            group: group.map(|g| g.to_string()),
+            ..test_task(0, name, State::Pending)
+        };
+        let steps = vec![
+            task(10, "10-a", None),
+            task(10, "10-b", None),
+            task(20, "20-test/10-unit", Some("20-test")),
+            task(20, "20-test/20-integration", Some("20-test")),
+            task(30, "30-c", None),
+        ];
+        assert_eq!(
+            duplicate_ids(&steps),
+            vec![(10, vec!["10-a".to_string(), "10-b".to_string()])]
+        );
+    }
+
+    #[test]
+    fn order_test() -> Result<()> {
+        assert_eq!(step_id("20-test/10-unit", &[])?, 20);
+        assert!(step_id("build.sh", &[]).is_err());
+        let order = vec!["setup.sh".to_string(), "test".to_string()];
+        assert_eq!(step_id("setup.sh", &order)?, 1);
+        assert_eq!(step_id("test/unit.sh", &order)?, 2);
+        assert!(step_id("10-build.sh", &order).is_err());
+
+        let dir = tempfile::TempDir::new()?;
+        std::fs::create_dir(dir.path().join("test"))?;
+        for name in ["setup.sh", "test/10-unit.sh", "test/20-integration.sh"] {
+            std::fs::write(dir.path().join(name), "")?;
+        }
+        let names: Vec<(usize, String)> = load_tasks(dir.path(), &order)?
+            .into_iter()
+            .map(|t| (t.id, t.name))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                (1, "setup.sh".to_string()),
+                (2, "test/10-unit.sh".to_string()),
+                (2, "test/20-integration.sh".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn resources_free_test() -> Result<()> {
+        let conf: Config = serde_json::from_str(
+            r#"{
+                "resources": {"gpu": 2},
+                "needs": {"a": {"gpu": 1}, "b": {"gpu": 1}, "c": {"gpu": 2}},
+                "lock": {"a": "db", "d": "db"}
+            }"#,
+        )?;
+        let task = |name: &str| Task {
+            id: 10,
+            ..test_task(0, name, State::Pending)
+        };
+        assert!(resources_free(&conf, &task("a"), &[]));
+        assert!(resources_free(&conf, &task("b"), &[task("a")]));
+        assert!(!resources_free(&conf, &task("c"), &[task("a")]));
+        assert!(!resources_free(&conf, &task("b"), &[task("c")]));
+        assert!(!resources_free(&conf, &task("d"), &[task("a")]));
+        assert!(resources_free(&conf, &task("d"), &[task("b")]));
+        assert!(resources_free(&conf, &task("e"), &[task("c"), task("d")]));
+        Ok(())
+    }
+
+    #[test]
+    fn budget_note_test() {
+        let task = |state| Task {
+            id: 10,
+            ..test_task(0, "a", state)
+        };
+        let secs = Duration::from_secs;
+        let done = task(State::Complete(secs(70)));
+        assert_eq!(budget_note(&done, None), None);
+        assert_eq!(
+            budget_note(&done, Some(secs(60))),
+            Some("+10.0s over budget".to_string())
+        );
+        assert_eq!(
+            budget_note(&done, Some(secs(100))),
+            Some("30.0s under budget".to_string())
+        );
+        assert_eq!(
+            budget_note(&task(State::Running(clock())), Some(secs(100))),
+            None
+        );
+        assert_eq!(budget_note(&task(State::Pending), Some(secs(1))), None);
+    }
+
+    #[test]
+    fn cleanup_temp_test() -> Result<()> {
+        let record = |name: &str, result| StepRecord {
+            name: name.to_string(),
+            group: None,
+            started_at: std::time::SystemTime::now(),
+            took: Duration::ZERO,
+            exit_code: None,
+            attempts: 1,
+            result,
+            annotations: Vec::new(),
+        };
+        let setup = || -> Result<tempfile::TempDir> {
+            let dir = tempfile::TempDir::new()?;
+            std::fs::create_dir(dir.path().join("10-ok"))?;
+            std::fs::create_dir(dir.path().join("20-fail"))?;
+            Ok(dir)
+        };
+        let ok = [record("10-ok", "success")];
+        let failed = [record("10-ok", "success"), record("20-fail", "failure")];
+
+        let dir = setup()?;
+        let path = dir.path().to_path_buf();
+        assert_eq!(cleanup_temp(dir, TempCleanup::AlwaysDelete, &failed), None);
+        assert!(!path.exists());
+
+        let dir = setup()?;
+        let path = dir.path().to_path_buf();
+        assert_eq!(cleanup_temp(dir, TempCleanup::KeepOnFailure, &ok), None);
+        assert!(!path.exists());
+
+        let dir = setup()?;
+        let path = dir.path().to_path_buf();
+        let kept = cleanup_temp(dir, TempCleanup::KeepOnFailure, &failed);
+        assert_eq!(kept.as_ref(), Some(&path));
+        assert!(!path.join("10-ok").exists());
+        assert!(path.join("20-fail").exists());
+        std::fs::remove_dir_all(&path)?;
+
+        let dir = setup()?;
+        let path = dir.path().to_path_buf();
+        assert_eq!(
+            cleanup_temp(dir, TempCleanup::AlwaysKeep, &ok),
+            Some(path.clone())
+        );
+        assert!(path.join("10-ok").exists());
+        std::fs::remove_dir_all(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn run_as_test() -> Result<()> {
+        assert_eq!(RunAs::new(None, None)?, None);
+        let (uid, gid, account) = lookup_user("root")?;
+        assert_eq!((uid, gid), (0, 0));
+        assert_eq!(account.map(|(name, _)| name).as_deref(), Some("root"));
+        assert_eq!(lookup_user("0")?.0, 0);
+        assert_eq!(lookup_group("0")?, 0);
+        assert!(lookup_user("no-such-user-hopefully").is_err());
+        assert!(lookup_group("no-such-group-hopefully").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sandbox_test() -> Result<()> {
+        let sandbox: Sandbox = serde_json::from_str(r#"{"write": ["out", "~/.cache"]}"#)?;
+        let envs = [
+            (
+                OsString::from("TICKBOX_CWD"),
+                OsString::from("/home/me/src"),
+            ),
+            (OsString::from("HOME"), OsString::from("/home/me")),
+            (
+                OsString::from("TICKBOX_STEP_TMPDIR"),
+                OsString::from("/tmp/x/10-a"),
+            ),
+        ];
+        let got = sandbox.bwrap_args(std::path::Path::new("/wf/10-a.sh"), &envs);
+        let want = [
+            "--ro-bind",
+            "/",
+            "/",
+            "--dev",
+            "/dev",
+            "--proc",
+            "/proc",
+            "--unshare-net",
+            "--die-with-parent",
+            "--tmpfs",
+            "/home/me",
+            "--ro-bind-try",
+            "/wf",
+            "/wf",
+            // Hidden by the home directory tmpfs, but needed by the step.
+            "--ro-bind-try",
+            "/home/me/src",
+            "/home/me/src",
+            "--bind-try",
+            "/home/me/src/out",
+            "/home/me/src/out",
+            "--bind-try",
+            "/home/me/.cache",
+            "/home/me/.cache",
+            "--bind-try",
+            "/tmp/x/10-a",
+            "/tmp/x/10-a",
+        ];
+        assert_eq!(got, want.map(OsString::from));
+
+        let sandbox: Sandbox = serde_json::from_str(r#"{"hide": [], "network": true}"#)?;
+        let got = sandbox.bwrap_args(std::path::Path::new("/wf/10-a.sh"), &envs[..2]);
+        assert!(!got.contains(&"--unshare-net".into()));
+        assert!(!got.contains(&"--tmpfs".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_test() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        std::fs::write(dir.path().join("10-a.sh"), "hello\n")?;
+        std::fs::write(dir.path().join("20-b.sh"), "tampered\n")?;
+        std::fs::write(dir.path().join("30-c.sh"), "new\n")?;
+        // `echo hello | sha256sum`, and the same for `world`.
+        let text = "\
+5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  10-a.sh
+e258d248fda94c63753607f7c4494ee0fcbe92f1a76bfdac795c9d84101eb317 *20-b.sh
+";
+        std::fs::write(dir.path().join(MANIFEST_FILE), text)?;
+        let manifest = Manifest::load(dir.path(), None)?;
+        assert_eq!(manifest.sums.len(), 2);
+        manifest.verify(&dir.path().join("10-a.sh"))?;
+        assert!(manifest.verify(&dir.path().join("20-b.sh")).is_err());
+        assert!(manifest.verify(&dir.path().join("30-c.sh")).is_err());
+        assert!(Manifest::parse("abc  10-a.sh", dir.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fetch_url_test() {
+        assert!(is_archive_url("https://example.com/deploy.tar.gz"));
+        assert!(is_archive_url("https://example.com/deploy.tgz?token=x"));
+        assert!(!is_archive_url("https://github.com/example/workflows.git"));
+        assert!(!is_archive_url("git@github.com:example/workflows"));
+        assert_eq!(
+            fetch_cache_name("https://example.com/a/b.tgz"),
+            "https___example.com_a_b.tgz"
+        );
+    }
+
+    #[test]
+    fn tool_resolver_test() {
+        let tools: std::collections::BTreeMap<String, String> =
+            [("terraform", "1.7"), ("node-js", "20")]
+                .map(|(t, v)| (t.to_string(), v.to_string()))
+                .into();
+        let os = |v: &[&str]| v.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            ToolResolver::Mise.resolve(&tools),
+            (
+                os(&["mise", "exec", "node-js@20", "terraform@1.7", "--"]),
+                vec![]
+            )
+        );
+        assert_eq!(
+            ToolResolver::Asdf.resolve(&tools),
+            (
+                vec![],
+                vec![
+                    ("ASDF_NODE_JS_VERSION".into(), "20".into()),
+                    ("ASDF_TERRAFORM_VERSION".into(), "1.7".into())
+                ]
+            )
+        );
+        assert_eq!(
+            ToolResolver::Nix.resolve(&tools),
+            (
+                os(&[
+                    "nix",
+                    "shell",
+                    "nixpkgs/20#node-js",
+                    "nixpkgs/1.7#terraform",
+                    "--command"
+                ]),
+                vec![]
+            )
+        );
+        assert_eq!(
+            ToolResolver::Mise.resolve(&Default::default()),
+            (vec![], vec![])
+        );
+    }
+
+    #[test]
+    fn dev_shell_test() -> Result<()> {
+        let conf: Config = serde_json::from_str(
+            r#"{
+                "flake": {"a": ".#devShell"},
+                "nix_shell": {"b": "./shell.nix"}
+            }"#,
+        )?;
+        let os = |v: &[&str]| v.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            dev_shell(&conf, "a"),
+            os(&["nix", "develop", ".#devShell", "--command"])
+        );
+        assert_eq!(
+            dev_shell(&conf, "b"),
+            os(&["nix", "develop", "--file", "./shell.nix", "--command"])
+        );
+        assert!(dev_shell(&conf, "c").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn step_command_test() {
+        let p = |line| parse_step_command(line);
+        assert_eq!(p("::tickbox progress 42%"), Some(StepCommand::Progress(42)));
+        assert_eq!(p("::tickbox progress 42"), Some(StepCommand::Progress(42)));
+        assert_eq!(
+            p("::tickbox progress 3/12"),
+            Some(StepCommand::Progress(25))
+        );
+        assert_eq!(
+            p("::tickbox progress 150%"),
+            Some(StepCommand::Progress(100))
+        );
+        assert_eq!(p("::tickbox progress 1/0"), None);
+        assert_eq!(p("::tickbox progress lots"), None);
+        assert_eq!(p("::tickbox frobnicate"), None);
+        assert_eq!(p("progress 42%"), None);
+        let out = |k: &str, v: &str| {
+            Some(StepCommand::Annotate(Annotation::Output(
+                k.into(),
+                v.into(),
+            )))
+        };
+        assert_eq!(
+            p("::tickbox set-output version=1.2.3"),
+            out("version", "1.2.3")
+        );
+        assert_eq!(
+            p("::tickbox set-output url=http://x/?a=b"),
+            out("url", "http://x/?a=b")
+        );
+        assert_eq!(p("::tickbox set-output version"), None);
+        assert_eq!(p("::tickbox set-output =1"), None);
+        let warn = Annotation::Warning("disk almost full".into());
+        assert_eq!(warn.to_string(), "warning: disk almost full");
+        assert_eq!(
+            p("::tickbox warning \"disk almost full\""),
+            Some(StepCommand::Annotate(warn.clone()))
+        );
+        assert_eq!(
+            p("::tickbox warning disk almost full"),
+            Some(StepCommand::Annotate(warn))
+        );
+        assert_eq!(
+            p("::tickbox add-step foo make -C pkg/foo test"),
+            Some(StepCommand::AddStep(
+                "foo".into(),
+                "make -C pkg/foo test".into()
+            ))
+        );
+        assert_eq!(p("::tickbox add-step foo"), None);
+        assert_eq!(p("::tickbox add-step"), None);
+        let mut annotations = Vec::new();
+        assert_eq!(
+            push_annotation(&mut annotations, 2, Annotation::Warning("a".into())),
+            1
+        );
+        assert_eq!(
+            push_annotation(
+                &mut annotations,
+                2,
+                Annotation::Output("k".into(), "v".into())
+            ),
+            1
+        );
+        assert_eq!(warning_count(&annotations, 2), 1);
+        assert_eq!(warning_count(&annotations, 5), 0);
+        assert_eq!(progress_bar(0), "░░░░░░░░░░   0%");
+        assert_eq!(progress_bar(42), "████░░░░░░  42%");
+        assert_eq!(progress_bar(100), "██████████ 100%");
+    }
+
+    #[tokio::test]
+    async fn added_steps_test() {
+        let task = |n, name: &str| Task {
+            id: n,
+            ..test_task(n, name, State::Pending)
+        };
+        let mut steps = vec![task(0, "10-list"), task(1, "20-more"), task(2, "30-last")];
+        let states = Arc::new(Mutex::new(vec![State::Pending; 3]));
+        let (tx, mut rx) = ui_channel(10);
+        let added = |parent: usize| AddedSteps {
+            parent: steps[parent].clone(),
+            states: states.clone(),
+            steps: Arc::default(),
+        };
+        let (a, b) = (added(0), added(1));
+        let b = AddedSteps {
+            steps: a.steps.clone(),
+            ..b
+        };
+        let foo = a.add("foo", "echo foo", &tx).unwrap();
+        let bar = b.add("bar", "echo bar", &tx).unwrap();
+        let baz = a.add("baz", "echo baz", &tx).unwrap();
+        assert_eq!(foo.name, "10-list [foo]");
+        assert_eq!(foo.inline.as_deref(), Some("echo foo"));
+        assert!(foo.matrix);
+        assert_eq!((foo.n, bar.n, baz.n), (3, 4, 5));
+        let ns: Vec<_> = rx
+            .take()
+            .into_iter()
+            .map(|u| match u {
+                UIUpdate::Status(t) => t.n,
+                _ => panic!("not a status update"),
+            })
+            .collect();
+        assert_eq!(ns, [3, 4, 5]);
+
+        // Nothing to start until the parent is done.
+        let mut waiting = Vec::new();
+        let mut order = VecDeque::from([2]);
+        assert!(
+            !schedule_added(&a.steps, &mut steps, &mut waiting, &mut order, &states, &tx).await
+        );
+        assert_eq!(steps.len(), 6);
+        assert_eq!(waiting, [(0, 3), (1, 4), (0, 5)]);
+        assert_eq!(order, [2]);
+
+        states.lock().unwrap()[0] = State::Complete(Duration::ZERO);
+        states.lock().unwrap()[1] = State::Failed(Duration::ZERO);
+        assert!(schedule_added(&a.steps, &mut steps, &mut waiting, &mut order, &states, &tx).await);
+        assert!(waiting.is_empty());
+        assert_eq!(order, [3, 5, 2]);
+        assert!(matches!(steps[4].state, State::Skipped));
+
+        let mut groups = vec![vec![0], vec![1], vec![2]];
+        for n in 3..6 {
+            add_to_groups(&mut groups, &steps, n);
+        }
+        assert_eq!(
+            groups,
+            [vec![0], vec![1], vec![2], vec![3], vec![4], vec![5]]
+        );
+        // As if 10-list had added them all.
+        steps[4].cmd = "10-list".into();
+        let mut groups = vec![vec![0], vec![1], vec![2]];
+        for n in 3..6 {
+            add_to_groups(&mut groups, &steps, n);
+        }
+        assert_eq!(groups, [vec![0], vec![1], vec![2], vec![3, 4, 5]]);
+    }
+
+    #[test]
+    fn checklist_test() {
+        let p = |line| parse_step_command(line);
+        assert_eq!(
+            p("::tickbox check \"migrate table users\""),
+            Some(StepCommand::Check("migrate table users".into()))
+        );
+        assert_eq!(p("::tickbox check"), None);
+        assert_eq!(p("::tickbox done"), Some(StepCommand::Done(None)));
+        assert_eq!(
+            p("::tickbox done users"),
+            Some(StepCommand::Done(Some("users".into())))
+        );
+        let mut task = Task {
+            id: 1,
+            checklist: ["users", "groups", "posts"]
+                .iter()
+                .map(|i| (i.to_string(), false))
+                .collect(),
+            ..test_task(0, "10-migrate", State::Running(clock()))
+        };
+        task.check_done(None);
+        task.check_done(Some("users"));
+        task.check_done(Some("nope"));
+        let done: Vec<_> = task.checklist.iter().map(|(_, d)| *d).collect();
+        assert_eq!(done, [true, false, true]);
+        let theme = Theme::default();
+        let lines = checklist_lines(&task, "  ", &theme);
+        assert_eq!(lines[0].to_string(), format!("  {CHECKED} users"));
+        assert_eq!(lines[1].to_string(), format!("  {UNCHECKED} groups"));
+        assert_eq!(lines[1].style.fg, Some(theme.running.color));
+        task.state = State::Failed(Duration::ZERO);
+        let lines = checklist_lines(&task, "", &theme);
+        assert_eq!(lines[1].style.fg, Some(theme.failed.color));
+    }
+
+    #[test]
+    fn details_test() {
+        let mut task = Task {
+            cmd: "/w/20-test".into(),
+            envs: vec![("TICKBOX_TEST_DETAILS".into(), "step".into())],
+            ..test_task(1, "20-test", State::Failed(Duration::from_secs(3)))
+        };
+        let started_at = std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let record = StepRecord {
+            name: task.name.clone(),
+            group: None,
+            started_at,
+            took: Duration::from_secs(3),
+            exit_code: Some(2),
+            attempts: 1,
+            result: "failure",
+            annotations: Vec::new(),
+        };
+        let envs = [
+            ("TICKBOX_TEST_DETAILS".into(), "config".into()),
+            ("PATH".into(), std::env::var_os("PATH").unwrap_or_default()),
+        ];
+        let output: Vec<Line> = (0..30).map(|i| Line::from(format!("line {i}"))).collect();
+        let lines: Vec<String> = details_lines(
+            &task,
+            Some(&record),
+            &envs,
+            Some(Duration::from_secs(2)),
+            &output,
+        )
+        .iter()
+        .map(|l| l.to_string())
+        .collect();
+        assert_eq!(lines[0], "Command:      /w/20-test");
+        assert_eq!(lines[1], "State:        Failed after 3.0s");
+        assert_eq!(
+            lines[2],
+            format!("Started:      {}", format_time(started_at))
+        );
+        assert_eq!(lines[4], "Exit code:    2");
+        assert_eq!(lines[5], "Attempts:     1");
+        assert_eq!(lines[6], "Last success: 2.0s");
+        // Unchanged variables aren't shown, and steps override the config.
+        assert_eq!(
+            lines[8..10],
+            ["Environment:", "  TICKBOX_TEST_DETAILS=step"]
+        );
+        assert_eq!(lines[11], "Output:");
+        assert_eq!(lines.len(), 12 + DETAILS_LINES);
+        assert_eq!(lines.last().unwrap(), "line 29");
+
+        task.inline = Some("make test".into());
+        task.envs.clear();
+        let lines = details_lines(&task, None, &[], None, &[]);
+        assert_eq!(lines[0].to_string(), "Command:      bash -c \"make test\"");
+        assert_eq!(lines.len(), 2);
+
+        let time = format_time(started_at);
+        assert_eq!(time.len(), "2023-11-14 22:13:20".len());
+        assert!(time.starts_with("2023-11-1"));
+
+        let mut state = UiState {
+            details: Some(0),
+            ..Default::default()
+        };
+        state.pick_details(-1, 3);
+        assert_eq!(state.details, Some(2));
+        state.pick_details(1, 3);
+        assert_eq!(state.details, Some(0));
+    }
+
+    #[test]
+    fn terminal_title_test() {
+        let task = |state| Task {
+            id: 1,
+            ..test_task(0, "10-step", state)
+        };
+        let mut status = vec![
+            task(State::Complete(Duration::ZERO)),
+            task(State::Skipped),
+            task(State::Running(clock())),
+            task(State::Pending),
+        ];
+        assert_eq!(terminal_title(&status), "tickbox 3/4 ✓2");
+        status[2].state = State::Failed(Duration::ZERO);
+        assert_eq!(terminal_title(&status), "tickbox 3/4 ✓2 ✗1");
+    }
+
+    #[test]
+    fn line_prefix_test() {
+        let task = |n, name: &str| Task {
+            id: n,
+            ..test_task(n, name, State::Pending)
+        };
+        let status = [
+            task(0, "10-build"),
+            task(1, "20-a-very-long-test-step-name"),
+        ];
+        assert_eq!(line_prefix(&status, 0, 12, false), "10-build     | ");
+        assert_eq!(line_prefix(&status, 1, 12, false), "20-a-very-lo | ");
+        assert_eq!(line_prefix(&status, 5, 3, false), "?   | ");
+        assert_eq!(
+            line_prefix(&status, 1, 8, true),
+            "\x1b[33m20-a-ver |\x1b[0m "
+        );
+    }
+
+    #[test]
+    fn log_sched_test() {
+        let (tx, mut rx) = ui_channel(10);
+        let task = Task {
+            id: 1,
+            ..test_task(0, "10-build", State::Pending)
+        };
+        log_sched(&tx, "dispatch", &task, None);
+        log_sched(&tx, "join", &task, Some(false));
+        let msgs: Vec<_> = rx
+            .take()
+            .into_iter()
+            .map(|u| match u {
+                UIUpdate::Scheduled(msg) => msg,
+                _ => panic!("not a scheduler update"),
+            })
+            .collect();
+        assert_eq!(msgs, ["dispatch 10-build", "join 10-build: failure"]);
+        assert_eq!(
+            State::Complete(Duration::from_secs(2)).describe(),
+            "Succeeded after 2.0s"
+        );
+    }
+
+    #[test]
+    fn recording_test() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("run.tbx");
+        let header = RecordingHeader {
+            run_id: "abc".into(),
+            parallel_groups: vec![vec![0]],
+            estimates: std::collections::HashMap::new(),
+            budget: std::collections::HashMap::new(),
+            sla: Some(Duration::from_secs(60)),
+            max_concurrency: 2,
+        };
+        let (tx, mut rx) = ui_channel(10);
+        rx.recorder = Some(Recorder::create(&path, &header)?);
+        let task = Task {
+            id: 1,
+            envs: vec![("A".into(), "b".into())],
+            progress: Some(10),
+            checklist: vec![("x".into(), true)],
+            ..test_task(
+                0,
+                "10-build",
+                State::Running(clock() - Duration::from_secs(5)),
+            )
+        };
+        tx.send_now(UIUpdate::Status(task)).unwrap();
+        tx.send_now(UIUpdate::AddLine(0, "hello".into())).unwrap();
+        tx.send_now(UIUpdate::Finished).unwrap();
+        assert_eq!(rx.take().len(), 3);
+        drop(rx);
+
+        let (header, events) = load_recording(&path)?;
+        assert_eq!(header.run_id, "abc");
+        assert_eq!(header.sla, Some(Duration::from_secs(60)));
+        assert_eq!(events.len(), 3);
+        let updates: Vec<UIUpdate> = events
+            .into_iter()
+            .map(|(_, u)| serde_json::from_value(u))
+            .collect::<Result<_, _>>()?;
+        match &updates[..] {
+            [
+                UIUpdate::Status(task),
+                UIUpdate::AddLine(0, line),
+                UIUpdate::Finished,
+            ] => {
+                assert_eq!(task.name, "10-build");
+                assert_eq!(task.envs, vec![(OsString::from("A"), OsString::from("b"))]);
+                assert_eq!(task.checklist, vec![("x".to_string(), true)]);
+                match task.state {
+                    State::Running(t) => assert!(since(t) >= Duration::from_secs(5)),
+                    ref s => panic!("wrong state {s:?}"),
+                }
+                assert_eq!(line, "hello");
+            }
+            _ => panic!("wrong updates"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn history_test() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join(".tickbox.history");
+        assert!(load_history(&path)?.is_empty());
+        let run = |id: &str, success, steps: &[(&str, &str, u64)]| RunHistory {
+            run_id: id.to_string(),
+            started_at: std::time::UNIX_EPOCH,
+            success,
+            steps: steps
+                .iter()
+                .map(|(name, result, secs)| StepHistory {
+                    name: name.to_string(),
+                    result: result.to_string(),
+                    took: Duration::from_secs(*secs),
+                })
+                .collect(),
+        };
+        save_history(
+            &path,
+            run(
+                "abc-1",
+                true,
+                &[("10-build", "success", 10), ("20-test", "success", 30)],
+            ),
+        )?;
+        save_history(
+            &path,
+            run(
+                "abd-2",
+                false,
+                &[("10-build", "success", 12), ("15-lint", "failure", 1)],
+            ),
+        )?;
+        let runs = load_history(&path)?;
+        assert_eq!(runs.len(), 2);
+        assert_eq!(find_run(&runs, "last")?.run_id, "abd-2");
+        assert_eq!(find_run(&runs, "last-success")?.run_id, "abc-1");
+        assert_eq!(find_run(&runs, "abc")?.run_id, "abc-1");
+        assert!(find_run(&runs, "ab").is_err());
+        assert!(find_run(&runs, "x").is_err());
+
+        let stats = step_stats(&runs);
+        assert_eq!(
+            stats["10-build"],
+            StepStats {
+                passed: 2,
+                failed: 0,
+                changes: 0
+            }
+        );
+        save_history(
+            &path,
+            run(
+                "abe-3",
+                true,
+                &[("10-build", "skipped", 0), ("15-lint", "success", 1)],
+            ),
+        )?;
+        let runs = load_history(&path)?;
+        let stats = step_stats(&runs);
+        assert_eq!(
+            stats["15-lint"],
+            StepStats {
+                passed: 1,
+                failed: 1,
+                changes: 1
+            }
+        );
+        assert_eq!(
+            stats_lines(&stats, true),
+            [
+                "step     runs  pass rate  changes",
+                "15-lint     2        50%        1  flaky",
+            ]
+        );
+        assert_eq!(stats_lines(&stats, false).len(), 4);
+
+        let lines = diff_lines(&runs[0], &runs[1]);
+        assert_eq!(
+            lines[3..],
+            [
+                "  10-build  success  success     10.0s    12.0s +2.0s",
+                "! 15-lint   -        failure         -     1.0s",
+                "! 20-test   success  -           30.0s        -",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn suggest_parallel_test() -> Result<()> {
+        let conf: Config = serde_json::from_str(r#"{"lock": {"30-b.sh": "db", "40-c.sh": "db"}}"#)?;
+        let steps: Vec<Task> = ["10-setup.sh", "20-a.sh", "30-b.sh", "40-c.sh", "50-d.sh"]
+            .iter()
+            .enumerate()
+            .map(|(n, name)| test_task(n, name, State::Pending))
+            .collect();
+        let secs = |s| Duration::from_secs(s);
+        let durations: std::collections::HashMap<String, Duration> = [
+            ("10-setup.sh", secs(1)),
+            ("20-a.sh", secs(10)),
+            ("30-b.sh", secs(20)),
+            ("40-c.sh", secs(30)),
+            ("50-d.sh", secs(5)),
+        ]
+        .into_iter()
+        .map(|(n, d)| (n.to_string(), d))
+        .collect();
+        let groups: Vec<Vec<&Task>> = steps.iter().map(|t| vec![t]).collect();
+        let got = suggest_parallel(&groups, &durations, &conf, 4);
+        let summary: Vec<_> = got.iter().map(|s| (s.range, s.saving)).collect();
+        assert_eq!(summary, [((10, 30), secs(11)), ((40, 50), secs(5))]);
+        assert_eq!(
+            got[1].steps,
+            [
+                ("40-c.sh".to_string(), secs(30)),
+                ("50-d.sh".to_string(), secs(5))
+            ]
+        );
+        let re = Regex::new(&got[0].regex)?;
+        let matched: Vec<_> = steps.iter().filter(|t| re.is_match(&t.name)).collect();
+        assert_eq!(matched.len(), 3);
+        assert!(!re.is_match("20-axsh"));
+        assert!(suggest_parallel(&groups, &durations, &conf, 1).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn config_error_test() {
+        let path = std::path::Path::new("tickbox.json");
+        let err = |text: &str| parse_config(text, path).err().unwrap().to_string();
+        assert_eq!(
+            err("{\n  \"parallell_regex\": []\n}"),
+            "tickbox.json:2:19: unknown setting `parallell_regex`, did you mean `parallel_regex`?"
+        );
+        assert_eq!(
+            err(r#"{"ui": {"no_titel": true}}"#),
+            "tickbox.json:1:18: ui: unknown setting `no_titel`, did you mean `no_title`?"
+        );
+        assert_eq!(
+            err(r#"{"temp_cleanup": "keep"}"#),
+            "tickbox.json:1:23: temp_cleanup: unknown value `keep`"
+        );
+        assert!(
+            err(r#"{"max_concurrency": "2"}"#).starts_with("tickbox.json:1:23: max_concurrency: ")
+        );
+        assert!(parse_config(r#"{"$schema": "tickbox.schema.json"}"#, path).is_ok());
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "ab"), 2);
+    }
+
+    #[test]
+    fn config_schema_test() -> Result<()> {
+        // Every example in the config reference is a valid config.
+        for example in CONFIG_REFERENCE.split("```\n").skip(1).step_by(2) {
+            parse_config(&format!("{{{example}}}"), std::path::Path::new("example"))?;
+        }
+        let schema = config_schema();
+        let props = &schema["properties"];
+        assert_eq!(props["parallel_regex"]["type"], "array");
+        assert_eq!(props["max_concurrency"]["type"], "integer");
+        assert_eq!(
+            props["theme"]["type"],
+            serde_json::json!(["string", "object"])
+        );
+        assert_eq!(props["user"]["type"], "object");
+        assert_eq!(props["group"]["type"], "object");
+        assert!(
+            props["envs"]["description"]
+                .as_str()
+                .unwrap()
+                .starts_with("Map of environment variables")
+        );
+        assert_eq!(schema["additionalProperties"], false);
+        Ok(())
+    }
+
+    #[test]
+    fn profile_test() -> Result<()> {
+        let mut conf: Config = serde_json::from_str(
+            r#"{
+                "envs": {"TARGET": "dev", "DEBUG": "1"},
+                "parallel_regex": ["^20-"],
+                "max_concurrency": 4,
+                "profiles": {
+                    "prod": {
+                        "envs": {"TARGET": "prod"},
+                        "max_concurrency": 1,
+                        "skip": ["*-debug.sh"]
+                    },
+                    "staging": {"parallel_regex": [], "only": ["10-*", "20-*"]}
+                }
+            }"#,
+        )?;
+        let mut staging = conf.clone();
+        let profile = staging.apply_profile("staging")?;
+        assert!(staging.parallel_regex.is_empty());
+        assert_eq!(staging.max_concurrency, Some(4));
+        assert_eq!(profile.only.len(), 2);
+
+        let profile = conf.apply_profile("prod")?;
+        assert_eq!(conf.max_concurrency, Some(1));
+        assert_eq!(conf.parallel_regex.len(), 1);
+        // The last value of a variable is the one steps get.
+        let target: Vec<_> = conf.envs.iter().filter(|(k, _)| k == "TARGET").collect();
+        assert_eq!(target.last().unwrap().1, "prod");
+        assert!(conf.apply_profile("qa").is_err());
+
+        let opt = Cli::parse_from(["tickbox", "--only", "30-*"]).run;
+        let filter = StepFilter::new(&opt, &profile);
+        assert!(filter.selected("30-test.sh"));
+        assert!(!filter.selected("30-test-debug.sh"));
+        assert!(!filter.selected("10-build.sh"));
+        Ok(())
+    }
+
+    #[test]
+    fn load_workflow_file_test() -> Result<()> {
+        // A file without a directory part, in the current directory.
+        let file = tempfile::Builder::new().suffix(".json").tempfile_in(".")?;
+        std::fs::write(
+            file.path(),
+            r#"{"steps": [{"name": "10-build", "script": "build.sh"}]}"#,
+        )?;
+        let name = file.path().file_name().unwrap().to_str().unwrap();
+        let opt = Cli::parse_from(["tickbox", "--file", name]).run;
+        let wf = load_workflow(&opt)?;
+        assert_eq!(wf.steps[0].cmd, std::env::current_dir()?.join("build.sh"));
+        Ok(())
+    }
+
+    #[test]
+    fn expand_matrix_test() {
+        let task = test_task(0, "01-build", State::Pending);
+        let mut matrix = std::collections::HashMap::new();
+        matrix.insert(
+            "01-build".to_string(),
+            [
+                (
+                    "os".to_string(),
+                    vec!["linux".to_string(), "macos".to_string()],
+                ),
+                ("arch".to_string(), vec!["arm".to_string()]),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let got = expand_matrix(vec![task], &matrix);
+        let names: Vec<&str> = got.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "01-build [arch=arm os=linux]",
+                "01-build [arch=arm os=macos]"
+            ]
+        );
+        assert_eq!(got[1].n, 1);
+        assert_eq!(
+            got[1].envs,
+            vec![
+                (OsString::from("TICKBOX_MATRIX_ARCH"), OsString::from("arm")),
+                (OsString::from("TICKBOX_MATRIX_OS"), OsString::from("macos")),
+            ]
+        );
+        assert!(!sync_point(&got[1], &[&got[0]], &[], &[]));
+    }
+}
+
+/// Resources a step uses while running. A lock is a resource of which
+/// there's only one, unless configured otherwise.
+fn step_needs(conf: &Config, name: &str) -> std::collections::HashMap<String, u64> {
+    let mut needs = conf.needs.get(name).cloned().unwrap_or_default();
+    if let Some(lock) = conf.lock.get(name) {
+        *needs.entry(lock.clone()).or_default() += 1;
+    }
+    needs
+}
+
+/// Check if there are enough resources left to start a step, given the
+/// steps already running.
+fn resources_free(conf: &Config, task: &Task, running: &[Task]) -> bool {
+    step_needs(conf, &task.name).iter().all(|(res, n)| {
+        let have = conf.resources.get(res).copied().unwrap_or(1);
+        let used: u64 = running
+            .iter()
+            .filter_map(|r| step_needs(conf, &r.name).get(res).copied())
+            .sum();
+        used + n <= have
+    })
+}
+
+/// Log a scheduling decision as a JSON record, and tell the UI.
+fn log_sched(tx: &UiSender, event: &str, task: &Task, ok: Option<bool>) {
+    let msg = match ok {
+        Some(true) => format!("{event} {}: success", task.name),
+        Some(false) => format!("{event} {}: failure", task.name),
+        None => format!("{event} {}", task.name),
+    };
+    let _ = tx.send_now(UIUpdate::Scheduled(msg));
+    debug!(
+        "{}",
+        serde_json::json!({
+            "event": event,
+            "step": task.name,
+            "id": task.id,
+            "n": task.n,
+            "ok": ok,
+        })
+    );
+}
+
+/// A UIUpdate is sent to the UI thread whenever there's any news.
+#[derive(serde::Serialize, serde::Deserialize)]
+enum UIUpdate {
+    /// A step failed, so wait when finished, with `--wait=on-failure`.
+    Wait,
+
+    /// Update the status window.
+    Status(Task),
+
+    /// Add a line of output from step number n to the stdout/stderr window.
+    AddLine(usize, String),
+
+    /// Like `AddLine`, but the line is from stderr.
+    AddStderrLine(usize, String),
+
+    /// Step number n has stopped, or started again, producing output.
+    Stalled(usize, bool),
+
+    /// Step number n says how far along it is, in percent.
+    Progress(usize, u8),
+
+    /// Step number n said something about itself.
+    Annotation(usize, Annotation),
+
+    /// Step number n is working on a sub-item.
+    Check(usize, String),
+
+    /// Step number n is done with a sub-item, by default the latest.
+    CheckDone(usize, Option<String>),
+
+    /// The scheduler decided something, like starting a step.
+    Scheduled(String),
+
+    /// The workflow is being restarted, so forget everything. It may have
+    /// been reloaded, so these are the new parallel groups.
+    Restart(Vec<Vec<usize>>),
+
+    /// The workflow has finished.
+    Finished,
+}
+
+/// Create a channel for updates to the UI.
+///
+/// Unlike an mpsc channel, sending never waits for the UI. When more than
+/// `capacity` lines of output are waiting, the oldest are dropped, so that a
+/// chatty step can't hold up the workflow, nor swamp the UI. Other updates
+/// are never dropped.
+fn ui_channel(capacity: usize) -> (UiSender, UiReceiver) {
+    let shared = Arc::new(UiShared {
+        capacity: capacity.max(1),
+        queue: Mutex::new(UiQueue {
+            senders: 1,
+            ..Default::default()
+        }),
+        notify: tokio::sync::Notify::new(),
+    });
+    (
+        UiSender {
+            shared: shared.clone(),
+        },
+        UiReceiver {
+            shared,
+            recorder: None,
+        },
+    )
+}
+
+/// State shared by the ends of a UI channel.
+struct UiShared {
+    capacity: usize,
+    queue: Mutex<UiQueue>,
+
+    /// Wakes up the receiver.
+    notify: tokio::sync::Notify,
+}
+
+#[derive(Default)]
+struct UiQueue {
+    updates: VecDeque<UIUpdate>,
+
+    /// Number of lines of output in `updates`.
+    lines: usize,
+
+    /// Lines dropped since the receiver last took any, and the step of the
+    /// last one.
+    dropped: usize,
+    dropped_step: usize,
+
+    senders: usize,
+
+    /// The receiver is gone.
+    closed: bool,
+}
+
+impl UIUpdate {
+    fn is_line(&self) -> bool {
+        matches!(self, UIUpdate::AddLine(..) | UIUpdate::AddStderrLine(..))
+    }
+}
+
+/// Error sending to a UI that's gone.
+#[derive(Debug)]
+struct UiClosed;
+
+/// Sending end of a UI channel.
+struct UiSender {
+    shared: Arc<UiShared>,
+}
+
+impl UiSender {
+    /// Queue an update for the UI, failing if it's gone.
+    ///
+    /// Never actually waits, but is async like an mpsc sender.
+    async fn send(&self, update: UIUpdate) -> Result<(), UiClosed> {
+        self.send_now(update)
+    }
+
+    /// Like `send`, but not async, so it can be called with a lock held.
+    fn send_now(&self, update: UIUpdate) -> Result<(), UiClosed> {
+        {
+            let mut q = self.shared.queue.lock().unwrap();
+            if q.closed {
+                return Err(UiClosed);
+            }
+            if update.is_line() {
+                if q.lines >= self.shared.capacity {
+                    let oldest = q.updates.iter().position(UIUpdate::is_line);
+                    if let Some(UIUpdate::AddLine(n, _) | UIUpdate::AddStderrLine(n, _)) =
+                        oldest.and_then(|i| q.updates.remove(i))
+                    {
+                        q.lines -= 1;
+                        q.dropped += 1;
+                        q.dropped_step = n;
+                    }
+                }
+                q.lines += 1;
+            }
+            q.updates.push_back(update);
+        }
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+}
+
+impl Clone for UiSender {
+    fn clone(&self) -> Self {
+        self.shared.queue.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for UiSender {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().senders -= 1;
+        self.shared.notify.notify_one();
+    }
+}
+
+/// Receiving end of a UI channel.
+struct UiReceiver {
+    shared: Arc<UiShared>,
+
+    /// Where to write everything taken, with `--record`.
+    recorder: Option<Recorder>,
+}
+
+impl UiReceiver {
+    /// Wait for updates, and take all that are waiting. Returns `None` once
+    /// all senders are gone, and everything has been taken.
+    async fn recv_many(&mut self) -> Option<Vec<UIUpdate>> {
+        loop {
+            let updates = self.take();
+            if !updates.is_empty() {
+                return Some(updates);
+            }
+            if self.shared.queue.lock().unwrap().senders == 0 {
+                return None;
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// Take all waiting updates, without waiting, starting with a note
+    /// about any dropped output.
+    fn take(&mut self) -> Vec<UIUpdate> {
+        let mut q = self.shared.queue.lock().unwrap();
+        let mut updates = Vec::with_capacity(q.updates.len() + 1);
+        if q.dropped > 0 {
+            updates.push(UIUpdate::AddLine(
+                q.dropped_step,
+                format!(
+                    "[{} lines of output dropped, because the UI fell behind]",
+                    q.dropped
+                ),
+            ));
+            q.dropped = 0;
+        }
+        updates.extend(q.updates.drain(..));
+        q.lines = 0;
+        drop(q);
+        if let Some(Err(e)) = self.recorder.as_mut().map(|r| r.write(&updates)) {
+            warn!("Failed to record UI updates, stopping recording: {e}");
+            self.recorder = None;
+        }
+        updates
+    }
+}
+
+impl Drop for UiReceiver {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+    }
+}
+
+/// What the TUI needs to know about a run to replay it, written first in
+/// a recording.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RecordingHeader {
+    run_id: String,
+    parallel_groups: Vec<Vec<usize>>,
+    estimates: std::collections::HashMap<String, Duration>,
+    budget: std::collections::HashMap<String, Duration>,
+    sla: Option<Duration>,
+    max_concurrency: usize,
+}
+
+/// Writes UI updates to a file as JSON lines, with the time since the
+/// recording started, for `tickbox replay`.
+struct Recorder {
+    out: std::io::BufWriter<std::fs::File>,
+    started: Instant,
+}
+
+impl Recorder {
+    fn create(path: &std::path::Path, header: &RecordingHeader) -> Result<Self> {
+        use std::io::Write;
+        let file = std::fs::File::create(path).map_err(|e| {
+            Error::msg(format!(
+                "Failed to create recording {}: {e}",
+                path.display()
+            ))
+        })?;
+        let mut out = std::io::BufWriter::new(file);
+        writeln!(out, "{}", serde_json::to_string(header)?)?;
+        Ok(Self {
+            out,
+            started: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, updates: &[UIUpdate]) -> Result<()> {
+        use std::io::Write;
+        let t = self.started.elapsed().as_secs_f64();
+        for update in updates {
+            let event = serde_json::json!({"t": t, "update": update});
+            writeln!(self.out, "{event}")?;
+        }
+        // So that the recording is useful even if tickbox is killed.
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Read a recording made with `--record`. The updates are left as JSON,
+/// because running steps are recorded relative to when they're played.
+fn load_recording(
+    path: &std::path::Path,
+) -> Result<(RecordingHeader, Vec<(Duration, serde_json::Value)>)> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| Error::msg(format!("Failed to read {}: {e}", path.display())))?;
+    let mut lines = data.lines().enumerate();
+    let header = match lines.next() {
+        Some((_, line)) => serde_json::from_str(line)
+            .map_err(|e| Error::msg(format!("{}: bad header: {e}", path.display())))?,
+        None => return Err(Error::msg(format!("{} is empty", path.display()))),
+    };
+    let mut events = Vec::new();
+    for (i, line) in lines {
+        let bad = |e: &dyn std::fmt::Display| {
+            Error::msg(format!("{}:{}: bad event: {e}", path.display(), i + 1))
+        };
+        let mut event: serde_json::Value = serde_json::from_str(line).map_err(|e| bad(&e))?;
+        let t = event["t"]
+            .as_f64()
+            .and_then(|t| Duration::try_from_secs_f64(t).ok())
+            .ok_or_else(|| bad(&"no time"))?;
+        events.push((t, event["update"].take()));
+    }
+    Ok((header, events))
+}
+
+/// Play back a recording in the TUI, `speed` times faster than it happened.
+async fn replay(path: &std::path::Path, speed: f64) -> Result<()> {
+    if !(speed > 0.0 && speed.is_finite()) {
+        return Err(Error::msg(format!("Invalid speed: {speed}")));
+    }
+    let (header, events) = load_recording(path)?;
+    let (tx, rx) = ui_channel(DEFAULT_OUTPUT_BUFFER);
+    // Restarting a recording does nothing.
+    let (ctl_tx, _) = mpsc::channel(1);
+    let cfg = TuiConfig {
+        theme: Theme::default(),
+        error_regex: regex::Regex::new(DEFAULT_ERROR_REGEX)?,
+        highlights: default_highlights(),
+        bell: false,
+        title: true,
+        parallel_groups: header.parallel_groups,
+        estimates: header.estimates,
+        budget: header.budget,
+        sla: header.sla,
+        max_concurrency: header.max_concurrency,
+        run_id: header.run_id,
+        ui: UiDefaults::default(),
+        keys: Keymap::new(&std::collections::HashMap::new())?,
+        wait: WaitMode::Always,
+        exit_after: None,
+        envs: Vec::new(),
+        records: Arc::new(Mutex::new(Vec::new())),
+    };
+    let player = task::spawn(async move {
+        let started = tokio::time::Instant::now();
+        for (t, update) in events {
+            tokio::time::sleep_until(started + t.div_f64(speed)).await;
+            let update = match serde_json::from_value(update) {
+                Ok(update) => update,
+                Err(e) => UIUpdate::AddLine(0, format!("==> Bad event in recording: {e}")),
+            };
+            if tx.send(update).await.is_err() {
+                return;
+            }
+        }
+    });
+    run_tui(rx, ctl_tx, cfg).await?;
+    player.abort();
+    Ok(())
+}
+
+/// Requests from the UI to the runner.
+enum Control {
+    /// Abort all steps, and run the workflow again from the start.
+    Restart,
+}
+
+/// Remember an annotation of step `n`, for the summary. Returns how many
+/// warnings the step has now.
+fn push_annotation(annotations: &mut Vec<Vec<Annotation>>, n: usize, a: Annotation) -> usize {
+    if annotations.len() <= n {
+        annotations.resize_with(n + 1, Vec::new);
+    }
+    annotations[n].push(a);
+    warning_count(annotations, n)
+}
+
+/// Number of warnings from step `n`.
+fn warning_count(annotations: &[Vec<Annotation>], n: usize) -> usize {
+    annotations
+        .get(n)
+        .into_iter()
+        .flatten()
+        .filter(|a| matches!(a, Annotation::Warning(_)))
+        .count()
+}
+
+/// Keep the last `SUMMARY_LINES` lines of output of a step.
+fn push_tail(tails: &mut Vec<VecDeque<String>>, n: usize, line: &str) {
+    if tails.len() <= n {
+        tails.resize_with(n + 1, VecDeque::new);
+    }
+    tails[n].push_back(line.to_string());
+    if tails[n].len() > SUMMARY_LINES {
+        tails[n].pop_front();
+    }
+}
+
+/// Summarize a finished run: totals, slowest steps, and the end of the output
+/// of failed steps.
+fn make_summary(
+    steps: &[Task],
+    tails: &[VecDeque<String>],
+    annotations: &[Vec<Annotation>],
+    total: Duration,
+    run_id: &str,
+    budget: &std::collections::HashMap<String, Duration>,
+    sla: Option<Duration>,
+) -> Vec<Line<'static>> {
+    use ansi_to_tui::IntoText;
+    let count = |f: fn(&State) -> bool| steps.iter().filter(|t| f(&t.state)).count();
+    let mut lines = vec![Line::from(format!(
+        "Finished after {}: {} succeeded, {} failed, {} aborted, {} skipped, {} not run",
+        format_duration(total).trim(),
+        count(|s| matches!(s, State::Complete(_))),
+        count(|s| matches!(s, State::Failed(_))),
+        count(|s| matches!(s, State::Aborted(_))),
+        count(|s| matches!(s, State::Skipped)),
+        count(|s| matches!(s, State::Pending | State::Running(_))),
+    ))];
+    if let Some(sla) = sla.filter(|sla| total > *sla) {
+        let failed = count(|s| !matches!(s, State::Complete(_) | State::Skipped));
+        let what = match failed {
+            0 => "Completed, but exceeded",
+            _ => "Exceeded",
+        };
+        lines.push(Line::styled(
+            format!(
+                "{what} time budget of {} by {}",
+                format_duration(sla).trim(),
+                format_duration(total - sla).trim()
+            ),
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    lines.push(Line::from(format!("Run ID: {run_id}")));
+    let mut slowest: Vec<_> = steps
+        .iter()
+        .filter_map(|t| match t.state {
+            State::Complete(d) | State::Failed(d) => Some((d, &t.name)),
+            _ => None,
+        })
+        .collect();
+    slowest.sort_by_key(|(d, _)| std::cmp::Reverse(*d));
+    if !slowest.is_empty() {
+        lines.push(Line::from(""));
+        lines.push(Line::from("Slowest steps:"));
+        for (d, name) in slowest.iter().take(SUMMARY_SLOWEST) {
+            lines.push(Line::from(format!("  {} {name}", format_duration(*d))));
+        }
+    }
+    let over: Vec<_> = steps
+        .iter()
+        .filter_map(|t| match t.state {
+            State::Complete(d) | State::Failed(d) => budget
+                .get(&t.name)
+                .filter(|b| d > **b)
+                .map(|b| (d - *b, *b, &t.name)),
+            _ => None,
+        })
+        .collect();
+    if !over.is_empty() {
+        lines.push(Line::from(""));
+        lines.push(Line::from("Over time budget:"));
+        for (d, b, name) in over {
+            lines.push(Line::from(format!(
+                "  +{} {name} (budget {})",
+                format_duration(d),
+                format_duration(b).trim()
+            )));
+        }
+    }
+    let annotated: Vec<_> = steps
+        .iter()
+        .filter_map(|t| Some((t, annotations.get(t.n).filter(|a| !a.is_empty())?)))
+        .collect();
+    if !annotated.is_empty() {
+        lines.push(Line::from(""));
+        lines.push(Line::from("Annotations:"));
+        for (t, list) in annotated {
+            for a in list {
+                let style = match a {
+                    Annotation::Warning(_) => Style::default().fg(Color::Yellow),
+                    Annotation::Output(..) => Style::default(),
+                };
+                lines.push(Line::styled(format!("  {}: {a}", t.name), style));
+            }
+        }
+    }
+    for t in steps.iter().filter(|t| matches!(t.state, State::Failed(_))) {
+        lines.push(Line::from(""));
+        lines.push(Line::styled(
+            format!("Failed: {}", t.name),
+            Style::default().fg(Color::Red),
+        ));
+        for line in tails.get(t.n).into_iter().flatten() {
+            lines.extend(line.into_text().map(|text| text.lines).unwrap_or_default());
+        }
+    }
+    lines
+}
+
+/// Ring the terminal bell.
+fn ring_bell() {
+    use std::io::Write;
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(b"\x07");
+    let _ = stdout.flush();
+}
+
+/// How much to print without the TUI.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Verbosity {
+    /// Only when steps start and finish, and the summary.
+    Quiet,
+
+    /// Output and status of the steps.
+    Normal,
+
+    /// Also what the scheduler decides.
+    Verbose,
+}
+
+/// Print the output and status of the steps, without a TUI.
+///
+/// Returns the summary of the run, if it finished.
+async fn run_raw(
+    mut rx: UiReceiver,
+    bell: bool,
+    prefix: bool,
+    verbosity: Verbosity,
+    run_id: &str,
+    budget: &std::collections::HashMap<String, Duration>,
+    sla: Option<Duration>,
+) -> Result<Option<Vec<Line<'static>>>> {
+    let mut status = Vec::new();
+    let mut tails = Vec::new();
+    let mut annotations = Vec::new();
+    let mut summary = None;
+    let started = clock();
+    let color = {
+        use std::io::IsTerminal;
+        std::io::stdout().is_terminal()
+    };
+    let print = |status: &[Task], n: usize, line: &str| {
+        if verbosity == Verbosity::Quiet {
+            return;
+        }
+        if !prefix {
+            println!("{line}");
+            return;
+        }
+        let width = status
+            .iter()
+            .map(|t| t.name.chars().count())
+            .max()
+            .unwrap_or(0)
+            .min(PREFIX_WIDTH);
+        let prefix = line_prefix(status, n, width, color);
+        for line in line.split('\n') {
+            println!("{prefix}{line}");
+        }
+    };
+    while let Some(updates) = rx.recv_many().await {
+        for update in updates {
+            match update {
+                UIUpdate::Wait
+                | UIUpdate::Restart(_)
+                | UIUpdate::Stalled(..)
+                | UIUpdate::Progress(..)
+                | UIUpdate::Check(..)
+                | UIUpdate::CheckDone(..) => {
+                    // Waiting, restarting, progress bars, and checklists only
+                    // make sense in TUI mode, and stalls and sub-items are
+                    // already in the output.
+                }
+                UIUpdate::Annotation(n, a) => {
+                    // Already in the output, but also for the summary.
+                    push_annotation(&mut annotations, n, a);
+                }
+                UIUpdate::Scheduled(msg) => {
+                    if verbosity == Verbosity::Verbose {
+                        println!("[scheduler] {msg}");
+                    }
+                }
+                UIUpdate::Finished => {
+                    if bell {
+                        ring_bell();
+                    }
+                    println!("=== Summary ===");
+                    let total = since(started);
+                    let lines =
+                        make_summary(&status, &tails, &annotations, total, run_id, budget, sla);
+                    for line in &lines {
+                        println!("{line}");
+                    }
+                    summary = Some(lines);
+                }
+                UIUpdate::AddLine(n, line) => {
+                    push_tail(&mut tails, n, &line);
+                    print(&status, n, &line);
+                }
+                UIUpdate::AddStderrLine(n, line) => {
+                    let line = format!("{STDERR_PREFIX}{line}");
+                    push_tail(&mut tails, n, &line);
+                    print(&status, n, &line);
+                }
+                UIUpdate::Status(st) if st.n == status.len() => {
+                    status.push(st);
+                }
+                UIUpdate::Status(st) => {
+                    if bell && matches!(st.state, State::Failed(_)) {
+                        ring_bell();
+                    }
+                    if verbosity == Verbosity::Quiet {
+                        println!("{}: {}", st.name, st.state.describe());
+                        let n = st.n;
+                        status[n] = st;
+                        continue;
+                    }
+                    status[st.n] = st.clone();
+                    let maxlen = status
+                        .iter()
+                        .map(|s| s.name.len())
+                        .max()
+                        .expect("no steps?");
+                    println!("=== Status ===");
+                    for task in &status {
+                        println!("  {:>maxlen$} {}", task.name, task.state);
+                    }
+                }
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Longest step name in line prefixes without the TUI, before it's cut short.
+const PREFIX_WIDTH: usize = 20;
+
+/// Colors of line prefixes, as ANSI codes, picked by step number.
+const PREFIX_COLORS: &[u8] = &[36, 33, 32, 35, 34, 31];
+
+/// Start of lines of output from step `n` without the TUI: its name, cut
+/// short or padded to `width`, in a color of its own.
+fn line_prefix(status: &[Task], n: usize, width: usize, color: bool) -> String {
+    let name = status.get(n).map_or("?", |t| t.name.as_str());
+    let name: String = name.chars().take(width).collect();
+    if !color {
+        return format!("{name:<width$} | ");
+    }
+    let code = PREFIX_COLORS[n % PREFIX_COLORS.len()];
+    format!("\x1b[{code}m{name:<width$} |\x1b[0m ")
+}
+
+/// Remove ANSI escape codes, e.g. colors.
+fn strip_ansi(s: &str) -> String {
+    use ansi_parser::{AnsiParser, Output};
+    s.ansi_parse()
+        .filter_map(|o| match o {
+            Output::TextBlock(t) => Some(t),
+            Output::Escape(_) => None,
+        })
+        .collect()
+}
+
+/// Copy text to the system clipboard, using the OSC 52 terminal escape.
+fn copy_to_clipboard(text: &str) -> Result<()> {
+    use base64::Engine;
+    use std::io::Write;
+    let mut stdout = std::io::stdout();
+    write!(
+        stdout,
+        "\x1b]52;c;{}\x07",
+        base64::engine::general_purpose::STANDARD.encode(text)
+    )?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Save text to a new file in the current directory, returning its name.
+fn save_output(text: &str) -> Result<String> {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let filename = format!("tickbox-output-{secs}.txt");
+    std::fs::write(&filename, text)?;
+    Ok(filename)
+}
+
+/// Set if anything panicked, so that the TUI can get out of the way.
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+/// The last panic message, for printing once the TUI is gone.
+static PANIC_MESSAGE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Install a panic hook that restores the terminal before printing the panic.
+fn install_panic_hook() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        ratatui::restore();
+        PANICKED.store(true, Ordering::SeqCst);
+        if let Ok(mut msg) = PANIC_MESSAGE.lock() {
+            *msg = Some(info.to_string());
+        }
+        default(info);
+    }));
+}
+
+/// Total time tickbox has been suspended (Ctrl-Z), in milliseconds.
+static SUSPENDED_MS: AtomicU64 = AtomicU64::new(0);
+
+/// A clock that stands still while tickbox is suspended, for timing steps.
+fn clock() -> Instant {
+    let suspended = Duration::from_millis(SUSPENDED_MS.load(Ordering::SeqCst));
+    let now = Instant::now();
+    now.checked_sub(suspended).unwrap_or(now)
+}
+
+/// Time since `t`, as measured by `clock()`.
+fn since(t: Instant) -> Duration {
+    clock().saturating_duration_since(t)
+}
+
+/// Suspend tickbox and its steps, like Ctrl-Z normally would, and take back
+/// the terminal once continued.
+fn suspend(terminal: &mut ratatui::DefaultTerminal) -> Result<()> {
+    ratatui::restore();
+    let start = Instant::now();
+    // SIGTSTP is handled by us, so stop with SIGSTOP. Sending it to the process
+    // group stops the running steps too.
+    //
+    // SAFETY: kill(2) has no memory safety requirements.
+    if unsafe { libc::kill(0, libc::SIGSTOP) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // Continued.
+    SUSPENDED_MS.fetch_add(start.elapsed().as_millis() as u64, Ordering::SeqCst);
+    crossterm::terminal::enable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), crossterm::terminal::EnterAlternateScreen)?;
+    terminal.clear()?;
+    Ok(())
+}
+
+/// State of the `--interactive` step picker.
+struct Picker {
+    /// Step numbers, in the order shown.
+    order: Vec<usize>,
+
+    /// Parallel group of each step, by step number.
+    group: Vec<usize>,
+
+    /// Whether each step will run, by step number.
+    enabled: Vec<bool>,
+
+    /// Position of the cursor in `order`.
+    cursor: usize,
+}
+
+impl Picker {
+    fn new(groups: &[Vec<&Task>]) -> Self {
+        let mut group = Vec::new();
+        for (i, members) in groups.iter().enumerate() {
+            for t in members {
+                if group.len() <= t.n {
+                    group.resize(t.n + 1, 0);
+                }
+                group[t.n] = i;
+            }
+        }
+        Self {
+            order: (0..group.len()).collect(),
+            enabled: vec![true; group.len()],
+            group,
+            cursor: 0,
+        }
+    }
+
+    fn toggle(&mut self) {
+        let n = self.order[self.cursor];
+        self.enabled[n] = !self.enabled[n];
+    }
+
+    fn set_all(&mut self, on: bool) {
+        self.enabled.iter_mut().for_each(|e| *e = on);
+    }
+
+    fn up(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn down(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.order.len().saturating_sub(1));
+    }
+
+    /// Move the step under the cursor up (-1) or down (1), but only within
+    /// its parallel group.
+    fn shift(&mut self, delta: isize) {
+        let Some(other) = self.cursor.checked_add_signed(delta) else {
+            return;
+        };
+        if other >= self.order.len()
+            || self.group[self.order[other]] != self.group[self.order[self.cursor]]
+        {
+            return;
+        }
+        self.order.swap(self.cursor, other);
+        self.cursor = other;
+    }
+
+    /// Names of the steps turned off.
+    fn deselected(&self, steps: &[Task]) -> Vec<String> {
+        steps
+            .iter()
+            .filter(|t| !self.enabled[t.n])
+            .map(|t| t.name.clone())
+            .collect()
+    }
+
+    /// Priorities making parallel groups start in the picked order, for the
+    /// groups that were reordered.
+    fn priorities(&self, steps: &[Task]) -> Vec<(String, i64)> {
+        let moved: std::collections::HashSet<usize> = self
+            .order
+            .iter()
+            .enumerate()
+            .filter(|(pos, n)| *pos != **n)
+            .map(|(_, n)| self.group[*n])
+            .collect();
+        self.order
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| moved.contains(&self.group[**n]))
+            .map(|(pos, n)| (steps[*n].name.clone(), (self.order.len() - pos) as i64))
+            .collect()
+    }
+
+    fn lines(&self, steps: &[Task]) -> Vec<Line<'static>> {
+        self.order
+            .iter()
+            .enumerate()
+            .map(|(pos, &n)| {
+                let check = if self.enabled[n] { "[x]" } else { "[ ]" };
+                // Mark steps that may run in parallel with the one before.
+                let par = if pos > 0 && self.group[self.order[pos - 1]] == self.group[n] {
+                    "|"
+                } else {
+                    " "
+                };
+                let mut style = Style::default();
+                if pos == self.cursor {
+                    style = style.add_modifier(Modifier::REVERSED);
+                }
+                if !self.enabled[n] {
+                    style = style.fg(Color::DarkGray);
+                }
+                Line::styled(format!("{check} {par} {}", steps[n].name), style)
+            })
+            .collect()
+    }
+}
+
+/// Let the user pick which steps to run, for `--interactive`.
+///
+/// Returns `None` if the user quit instead.
+fn pick_steps(steps: &[Task], groups: &[Vec<&Task>]) -> Result<Option<Picker>> {
+    use ratatui::widgets::{Block, Paragraph};
+    let mut picker = Picker::new(groups);
+    let mut terminal = ratatui::init();
+    let _guard = TerminalGuard;
+    let mut scroll = 0;
+    loop {
+        terminal.draw(|frame| {
+            let area = frame.area();
+            let height = area.height.saturating_sub(2) as usize;
+            if picker.cursor < scroll {
+                scroll = picker.cursor;
+            } else if picker.cursor >= scroll + height {
+                scroll = picker.cursor + 1 - height;
+            }
+            let lines: Vec<Line> = picker
+                .lines(steps)
+                .into_iter()
+                .skip(scroll)
+                .take(height)
+                .collect();
+            frame.render_widget(
+                Paragraph::new(lines).block(Block::bordered().title(
+                    "Pick steps: Space toggles, a/n all/none, J/K move parallel steps, \
+                     Enter runs, q quits",
+                )),
+                area,
+            );
+        })?;
+        let crossterm::event::Event::Key(key) = crossterm::event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('j') | KeyCode::Down => picker.down(),
+            KeyCode::Char('k') | KeyCode::Up => picker.up(),
+            KeyCode::Char('J') => picker.shift(1),
+            KeyCode::Char('K') => picker.shift(-1),
+            KeyCode::Char(' ') => picker.toggle(),
+            KeyCode::Char('a') => picker.set_all(true),
+            KeyCode::Char('n') => picker.set_all(false),
+            KeyCode::Enter => return Ok(Some(picker)),
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Restores the terminal when dropped, including when unwinding.
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+/// Puts back the terminal title when dropped, on terminals that keep a stack
+/// of titles.
+struct TitleGuard;
+
+impl TitleGuard {
+    fn new() -> Self {
+        use std::io::Write;
+        let _ = std::io::stdout().write_all(b"\x1b[22;0t");
+        Self
+    }
+}
+
+impl Drop for TitleGuard {
+    fn drop(&mut self) {
+        use std::io::Write;
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(b"\x1b[23;0t");
+        let _ = stdout.flush();
+    }
+}
+
+/// Terminal title with how the workflow is going, like `tickbox 7/23 ✓5 ✗1`:
+/// steps started of all steps, then succeeded, and failed, if any.
+fn terminal_title(status: &[Task]) -> String {
+    let count = |f: fn(&State) -> bool| status.iter().filter(|t| f(&t.state)).count();
+    let started = count(|s| !matches!(s, State::Pending));
+    let ok = count(|s| matches!(s, State::Complete(_) | State::Skipped));
+    let failed = count(|s| matches!(s, State::Failed(_) | State::Aborted(_)));
+    let title = format!("tickbox {started}/{} ✓{ok}", status.len());
+    match failed {
+        0 => title,
+        n => format!("{title} ✗{n}"),
+    }
+}
+
+/// Run the UI until the channel with UIUpdates ends.
+///
+/// The screen is only redrawn when something changed, or periodically while a
+/// step is running, to update its timer.
+///
+/// Returns the summary of the run, if it finished.
+async fn run_tui(
+    mut rx: UiReceiver,
+    ctl: mpsc::Sender<Control>,
+    mut cfg: TuiConfig,
+) -> Result<Option<Vec<Line<'static>>>> {
+    use futures::StreamExt;
+    install_panic_hook();
+    let mut terminal = ratatui::init();
+    let _guard = TerminalGuard;
+    let _title_guard = cfg.title.then(TitleGuard::new);
+    let mut title = String::new();
+    let mut out = Output::default();
+    let mut step_out: Vec<Output> = Vec::new();
+    let mut status: Vec<Task> = Vec::new();
+    let mut state = UiState {
+        follow: true,
+        wait: cfg.wait == WaitMode::Always,
+        compact: cfg.ui.compact,
+        collapse_groups: cfg.ui.collapse_groups,
+        graph: cfg.ui.graph,
+        hide_done: cfg.ui.hide_done,
+        ..Default::default()
+    };
+    let mut events = crossterm::event::EventStream::new();
+    let mut tick = tokio::time::interval(Duration::from_millis(100));
+    let mut tstp =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::from_raw(libc::SIGTSTP))?;
+    let mut open = true;
+    let mut done = false;
+    let mut tails = Vec::new();
+    let mut annotations = Vec::new();
+    let mut started = clock();
+    let mut dirty = true;
+    let mut last_draw = Instant::now();
+    loop {
+        if PANICKED.load(Ordering::SeqCst) {
+            // The terminal has already been restored, so don't draw on it.
+            return Ok(None);
+        }
+        if done && !state.wait {
+            break;
+        }
+        if state.exit_at.is_some_and(|t| t <= Instant::now()) {
+            break;
+        }
+        if dirty {
+            state.streaming = status
+                .iter()
+                .find(|t| matches!(t.state, State::Running(_)))
+                .map(|t| t.name.clone());
+            state.remaining = estimate_remaining(
+                &status,
+                &cfg.parallel_groups,
+                &cfg.estimates,
+                cfg.max_concurrency,
+            );
+            let expected = since(started) + state.remaining.unwrap_or_default();
+            state.over_budget = cfg.sla.is_some_and(|sla| expected > sla);
+            let (status_lines, focus) = make_status(&status, &state, &cfg);
+            if cfg.title && !status.is_empty() {
+                let new = terminal_title(&status);
+                if new != title {
+                    crossterm::execute!(std::io::stdout(), crossterm::terminal::SetTitle(&new))?;
+                    title = new;
+                }
+            }
+            terminal.draw(|frame| {
+                render(
+                    frame,
+                    &out,
+                    &step_out,
+                    &status,
+                    &status_lines,
+                    focus,
+                    &mut state,
+                    &cfg,
+                )
+            })?;
+            last_draw = Instant::now();
+        }
+        let mut updates = Vec::new();
+        tokio::select! {
+            batch = rx.recv_many(), if open => {
+                match batch {
+                    Some(batch) => {
+                        // Let updates pile up for a frame, to draw them all
+                        // at once.
+                        tokio::time::sleep_until((last_draw + FRAME_TIME).into()).await;
+                        updates = batch;
+                        updates.extend(rx.take());
+                    }
+                    None => {
+                        open = false;
+                        done = true;
+                    }
+                }
+                dirty = true;
+            }
+            event = events.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                dirty = true;
+                if let Ok(crossterm::event::Event::Key(_)) = event {
+                    state.exit_at = None;
+                }
+                match event? {
+                    crossterm::event::Event::Key(key)
+                        if key.kind == KeyEventKind::Press && state.filter_input.is_some() =>
+                    {
+                        edit_filter(&mut state, key.code);
+                    }
+                    crossterm::event::Event::Key(key) if key.kind == KeyEventKind::Press => {
+                        state.message = None;
+                        if key.code == KeyCode::Esc {
+                            state.help = false;
+                            state.details = None;
+                        }
+                        let scroll = if cfg.ui.natural_scroll { -1 } else { 1 };
+                        match cfg.keys.action(&key) {
+                            Some(Action::Suspend) => suspend(&mut terminal)?,
+                            // Pick the step to show details of.
+                            Some(Action::ScrollDown) if state.details.is_some() => {
+                                state.pick_details(1, status.len())
+                            }
+                            Some(Action::ScrollUp) if state.details.is_some() => {
+                                state.pick_details(-1, status.len())
+                            }
+                            Some(Action::ScrollDown) => state.scroll_output(scroll),
+                            Some(Action::ScrollUp) => state.scroll_output(-scroll),
+                            Some(Action::PageDown) => state.scroll_output(state.page()),
+                            Some(Action::PageUp) => state.scroll_output(-state.page()),
+                            Some(Action::WorkflowDown) => state.scroll_status(scroll),
+                            Some(Action::WorkflowUp) => state.scroll_status(-scroll),
+                            Some(Action::Follow) => state.follow = !state.follow,
+                            Some(Action::Compact) => state.compact = !state.compact,
+                            Some(Action::CollapseGroups) => {
+                                state.collapse_groups = !state.collapse_groups
+                            }
+                            Some(Action::Graph) => state.graph = !state.graph,
+                            Some(Action::HideDone) => state.hide_done = !state.hide_done,
+                            Some(Action::Filter) => {
+                                let current = state.filter.as_ref().map(|f| f.to_string());
+                                state.filter_input = Some(current.unwrap_or_default());
+                            }
+                            Some(Action::Wait) => state.wait = !state.wait,
+                            Some(Action::Zoom) => state.zoom = !state.zoom,
+                            Some(Action::Help) => state.help = !state.help,
+                            Some(Action::Copy) => {
+                                state.message = Some(match copy_to_clipboard(&strip_ansi(&out.text)) {
+                                    Ok(()) => "copied to clipboard".to_string(),
+                                    Err(e) => format!("copy failed: {e}"),
+                                });
+                            }
+                            Some(Action::Save) => {
+                                state.message = Some(match save_output(&strip_ansi(&out.text)) {
+                                    Ok(filename) => format!("saved to {filename}"),
+                                    Err(e) => format!("save failed: {e}"),
+                                });
+                            }
+                            Some(Action::Summary) => state.show_summary = !state.show_summary,
+                            Some(Action::Restart) => {
+                                // Fails if a restart is already queued up.
+                                let _ = ctl.try_send(Control::Restart);
+                            }
+                            Some(Action::Redraw) => terminal.clear()?,
+                            Some(Action::Split) => {
+                                state.split = match state.split {
+                                    Some(_) => None,
+                                    None => Some(Split::new(&status)),
+                                }
+                            }
+                            Some(Action::SplitSwitch) => {
+                                if let Some(split) = &mut state.split {
+                                    split.active = 1 - split.active;
+                                }
+                            }
+                            Some(Action::SplitNext) => {
+                                if let Some(split) = &mut state.split {
+                                    split.cycle(1, status.len());
+                                }
+                            }
+                            Some(Action::SplitPrev) => {
+                                if let Some(split) = &mut state.split {
+                                    split.cycle(-1, status.len());
+                                }
+                            }
+                            Some(Action::Details) => {
+                                state.details = match state.details {
+                                    Some(_) => None,
+                                    None if status.is_empty() => None,
+                                    None => Some(Split::new(&status).steps[0]),
+                                }
+                            }
+                            Some(Action::Quit) => break,
+                            None => {}
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ = tstp.recv() => {
+                suspend(&mut terminal)?;
+                dirty = true;
+            }
+            _ = tick.tick() => {
+                dirty = state.exit_at.is_some()
+                    || status.iter().any(|t| matches!(t.state, State::Running(_)));
+                if state.flash_until.is_some_and(|t| t <= Instant::now()) {
+                    state.flash_until = None;
+                    dirty = true;
+                }
+            }
+        }
+        if !updates.is_empty() {
+            dirty = true;
+        }
+        for update in updates {
+            // Stderr lines are marked in the gutter, and otherwise the same.
+            let update = match update {
+                UIUpdate::AddStderrLine(n, line) => {
+                    UIUpdate::AddLine(n, format!("{STDERR_GUTTER}{line}"))
+                }
+                update => update,
+            };
+            match update {
+                UIUpdate::Wait => state.wait |= cfg.wait == WaitMode::OnFailure,
+                UIUpdate::AddLine(n, line) | UIUpdate::AddStderrLine(n, line) => {
+                    push_tail(&mut tails, n, &line);
+                    state.lines += line.split('\n').count();
+                    if cfg.error_regex.is_match(&strip_ansi(&line)) {
+                        state.errors += 1;
+                    }
+                    out.push(&line, &cfg.highlights);
+                    if step_out.len() <= n {
+                        step_out.resize_with(n + 1, Output::default);
+                    }
+                    step_out[n].push(&line, &cfg.highlights);
+                }
+                UIUpdate::Stalled(n, stalled) => {
+                    if let Some(st) = status.get_mut(n) {
+                        st.stalled = stalled;
+                    }
+                }
+                UIUpdate::Progress(n, progress) => {
+                    if let Some(st) = status.get_mut(n) {
+                        st.progress = Some(progress);
+                    }
+                }
+                UIUpdate::Annotation(n, a) => {
+                    let warnings = push_annotation(&mut annotations, n, a);
+                    if let Some(st) = status.get_mut(n) {
+                        st.warnings = warnings;
+                    }
+                }
+                UIUpdate::Check(n, item) => {
+                    if let Some(st) = status.get_mut(n) {
+                        st.checklist.push((item, false));
+                    }
+                }
+                UIUpdate::CheckDone(n, item) => {
+                    if let Some(st) = status.get_mut(n) {
+                        st.check_done(item.as_deref());
+                    }
+                }
+                UIUpdate::Scheduled(_) => {
+                    // Only for --verbose without the TUI.
+                }
+                UIUpdate::Status(st) if st.n == status.len() => {
+                    status.push(st);
+                    let n = status.len() - 1;
+                    if !cfg.parallel_groups.iter().flatten().any(|&k| k == n) {
+                        add_to_groups(&mut cfg.parallel_groups, &status, n);
+                    }
+                }
+                UIUpdate::Status(st) => {
+                    if cfg.bell && matches!(st.state, State::Failed(_)) {
+                        ring_bell();
+                        state.flash_until = Some(Instant::now() + FLASH_TIME);
+                    }
+                    let n = st.n;
+                    let old = std::mem::replace(&mut status[n], st);
+                    // The runner doesn't keep track of warnings and checklists.
+                    status[n].warnings = warning_count(&annotations, n);
+                    status[n].checklist = old.checklist;
+                }
+                UIUpdate::Restart(groups) => {
+                    cfg.parallel_groups = groups;
+                    out.clear();
+                    step_out.clear();
+                    state.split = None;
+                    status.clear();
+                    tails.clear();
+                    annotations.clear();
+                    started = clock();
+                    state.summary = None;
+                    state.show_summary = false;
+                    state.scroll = 0;
+                    state.lines = 0;
+                    state.errors = 0;
+                    state.exit_at = None;
+                    done = false;
+                }
+                UIUpdate::Finished => {
+                    if cfg.bell {
+                        ring_bell();
+                        state.flash_until = Some(Instant::now() + FLASH_TIME);
+                    }
+                    done = true;
+                    state.exit_at = cfg.exit_after.map(|d| Instant::now() + d);
+                    state.summary = Some(make_summary(
+                        &status,
+                        &tails,
+                        &annotations,
+                        since(started),
+                        &cfg.run_id,
+                        &cfg.budget,
+                        cfg.sla,
+                    ));
+                    state.show_summary = true;
+                }
+            }
+        }
+    }
+    let (status_lines, focus) = make_status(&status, &state, &cfg);
+    out.push("\n======== Exiting tickbox UI ==========", &cfg.highlights);
+    terminal
+        .draw(|frame| {
+            render(
+                frame,
+                &out,
+                &step_out,
+                &status,
+                &status_lines,
+                focus,
+                &mut state,
+                &cfg,
+            )
+        })
+        .unwrap();
+    Ok(state.summary)
+}
+
+/// CPU and IO priority to run a step at.
+#[derive(Clone, Copy, Debug, Default)]
+struct Priority {
+    nice: Option<i32>,
+
+    /// As given to `ioprio_set()`, class and level.
+    ioprio: Option<i32>,
+}
+
+impl Priority {
+    /// Set the priority of the current process.
+    fn apply(&self) -> std::io::Result<()> {
+        if let Some(nice) = self.nice {
+            // SAFETY: Plain syscall, with no pointers.
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(ioprio) = self.ioprio {
+            const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+            // SAFETY: Plain syscall, with no pointers.
+            if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How steps are kept off the network.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Offline {
+    /// They aren't, without `--offline`.
+    Online,
+
+    /// In a network namespace of their own.
+    Namespace,
+
+    /// With proxy env vars, because network namespaces aren't available.
+    Proxy,
+}
+
+/// Env vars that make well behaved programs fail to reach the network, for
+/// `--offline` without network namespaces. Port 9 is the discard service.
+const OFFLINE_PROXY_ENVS: &[&str] = &[
+    "http_proxy",
+    "https_proxy",
+    "ftp_proxy",
+    "all_proxy",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "FTP_PROXY",
+    "ALL_PROXY",
+];
+
+/// Run a command in a network namespace of its own, with nothing but a
+/// loopback interface that's down. Unless root, that takes a user namespace
+/// too, in which the user is mapped to itself.
+#[cfg(target_os = "linux")]
+fn isolate_network(command: &mut tokio::process::Command) {
+    // SAFETY: Plain syscalls, with no pointers.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    // Formatted here, since allocating between fork and exec isn't safe.
+    let uid_map = format!("{uid} {uid} 1");
+    let gid_map = format!("{gid} {gid} 1");
+    // SAFETY: Only makes syscalls, which is fine between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            if uid == 0 {
+                if libc::unshare(libc::CLONE_NEWNET) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                return Ok(());
+            }
+            if libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            write_proc(c"/proc/self/uid_map", uid_map.as_bytes())?;
+            write_proc(c"/proc/self/setgroups", b"deny")?;
+            write_proc(c"/proc/self/gid_map", gid_map.as_bytes())
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn isolate_network(_command: &mut tokio::process::Command) {}
+
+/// Write a `/proc` file, without allocating, so that it's fine between fork
+/// and exec.
+#[cfg(target_os = "linux")]
+fn write_proc(path: &std::ffi::CStr, data: &[u8]) -> std::io::Result<()> {
+    // SAFETY: `path` is NUL terminated, and `data` valid for its length.
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let n = libc::write(fd, data.as_ptr().cast(), data.len());
+        let err = std::io::Error::last_os_error();
+        libc::close(fd);
+        if n != data.len() as isize {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Check if commands can be run in network namespaces of their own.
+async fn network_namespaces_work() -> bool {
+    if !cfg!(target_os = "linux") {
+        return false;
+    }
+    let mut command = tokio::process::Command::new("true");
+    isolate_network(&mut command);
+    command
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await
+        .is_ok_and(|s| s.success())
+}
+
+/// Parse an IO priority, like `ionice`: `idle`, or `best-effort` or
+/// `realtime` with an optional level 0-7, e.g. `best-effort:7`.
+fn parse_ioprio(s: &str) -> Result<i32, String> {
+    let (class, level) = match s.split_once(':') {
+        Some((class, level)) => (
+            class,
+            level
+                .parse::<i32>()
+                .ok()
+                .filter(|l| (0..8).contains(l))
+                .ok_or(format!("Invalid IO priority level: {level}"))?,
+        ),
+        None => (s, 4),
+    };
+    let class = match class {
+        "realtime" => 1,
+        "best-effort" => 2,
+        "idle" => 3,
+        _ => return Err(format!("Invalid IO priority class: {class}")),
+    };
+    Ok((class << 13) | level)
+}
+
+/// What a step's exit code means.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Success,
+    Failure,
+    Skipped,
+    Retry,
+}
+
+impl Outcome {
+    /// Interpret an exit code, or `None` if killed by a signal, using the
+    /// step's table of exit codes, if any.
+    fn from_exit(
+        code: Option<i32>,
+        table: Option<&std::collections::HashMap<i32, Outcome>>,
+    ) -> Self {
+        match code {
+            Some(c) => table.and_then(|t| t.get(&c)).copied().unwrap_or(if c == 0 {
+                Outcome::Success
+            } else {
+                Outcome::Failure
+            }),
+            None => Outcome::Failure,
+        }
+    }
+
+    /// Lower case name, as in config and env vars.
+    fn name(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Skipped => "skipped",
+            Outcome::Retry => "retry",
+        }
+    }
+}
+
+/// Cap on the output of a step shown in the UI.
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OutputLimit {
+    lines: Option<usize>,
+    bytes: Option<usize>,
+}
+
+/// How to run a step.
+#[derive(Clone, Debug, Default)]
+struct RunOptions {
+    prio: Priority,
+    limit: OutputLimit,
+
+    /// File to write all the output to.
+    log: Option<std::path::PathBuf>,
+
+    /// Split output lines longer than this many bytes.
+    max_line: Option<usize>,
+
+    /// Send stderr to the same pipe as stdout.
+    merge_output: bool,
+
+    /// Arguments to run the command with.
+    args: Vec<String>,
+
+    /// Run the command as a bash script, instead of executing it.
+    shell: bool,
+
+    /// Shell command to run instead of the file.
+    inline: Option<String>,
+
+    /// Report the step as stalled after this long without output.
+    stall_timeout: Option<Duration>,
+
+    /// Kill the step when it stalls.
+    kill_stalled: bool,
+
+    /// User name or ID to run the command as.
+    user: Option<String>,
+
+    /// Group name or ID to run the command as.
+    group: Option<String>,
+
+    /// Restrictions to run the command under.
+    sandbox: Option<Sandbox>,
+
+    /// Run the command in a network namespace of its own, without network.
+    offline: bool,
+
+    /// Versions of tools to run the command with.
+    tools: std::collections::BTreeMap<String, String>,
+    tool_resolver: ToolResolver,
+
+    /// Wrapper to run the command in a Nix development shell, if any.
+    dev_shell: Vec<OsString>,
+
+    /// Where steps added by the command go, if it may add any.
+    added: Option<AddedSteps>,
+}
+
+/// Wrapper command to run a step in a Nix development shell, from its
+/// `flake` or `nix_shell` setting.
+fn dev_shell(conf: &Config, name: &str) -> Vec<OsString> {
+    let installable: Vec<OsString> = match (conf.flake.get(name), conf.nix_shell.get(name)) {
+        (Some(flake), _) => vec![flake.into()],
+        (None, Some(file)) => vec!["--file".into(), file.into()],
+        (None, None) => return Vec::new(),
+    };
+    let mut wrapper: Vec<OsString> = vec!["nix".into(), "develop".into()];
+    wrapper.extend(installable);
+    wrapper.push("--command".into());
+    wrapper
+}
+
+/// Run a command through a wrapper command, like `mise exec --`, which
+/// takes the command to run as its last arguments.
+fn wrap_command(
+    command: &tokio::process::Command,
+    wrapper: &[OsString],
+) -> tokio::process::Command {
+    let inner = command.as_std();
+    let mut wrapped = tokio::process::Command::new(&wrapper[0]);
+    wrapped
+        .args(&wrapper[1..])
+        .arg(inner.get_program())
+        .args(inner.get_args());
+    wrapped
+}
+
+/// Tool version manager, to pin the versions of tools that steps use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ToolResolver {
+    #[default]
+    Mise,
+    Asdf,
+
+    /// Versions are nixpkgs branches or revisions, e.g. `nixos-24.05`.
+    Nix,
+}
+
+impl ToolResolver {
+    /// Wrapper command, and env vars, to run a command with the given
+    /// versions of tools.
+    fn resolve(
+        &self,
+        tools: &std::collections::BTreeMap<String, String>,
+    ) -> (Vec<OsString>, Vec<(OsString, OsString)>) {
+        if tools.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+        let wrapper = |cmd: &[&str], args: Vec<String>, end: &str| -> Vec<OsString> {
+            cmd.iter()
+                .map(OsString::from)
+                .chain(args.into_iter().map(OsString::from))
+                .chain(std::iter::once(end.into()))
+                .collect()
+        };
+        match self {
+            ToolResolver::Mise => {
+                let args = tools.iter().map(|(t, v)| format!("{t}@{v}")).collect();
+                (wrapper(&["mise", "exec"], args, "--"), Vec::new())
+            }
+            // asdf shims pick the version from env vars.
+            ToolResolver::Asdf => {
+                let envs = tools
+                    .iter()
+                    .map(|(t, v)| {
+                        let name = t.to_uppercase().replace('-', "_");
+                        (format!("ASDF_{name}_VERSION").into(), v.into())
+                    })
+                    .collect();
+                (Vec::new(), envs)
+            }
+            ToolResolver::Nix => {
+                let args = tools
+                    .iter()
+                    .map(|(t, v)| format!("nixpkgs/{v}#{t}"))
+                    .collect();
+                (wrapper(&["nix", "shell"], args, "--command"), Vec::new())
+            }
+        }
+    }
+}
+
+/// What a sandboxed step may touch. Enforced with bubblewrap (`bwrap`).
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Sandbox {
+    /// Paths the step may write to. Everything else is read-only.
+    #[serde(default)]
+    write: Vec<std::path::PathBuf>,
+
+    /// Paths hidden from the step, behind an empty directory.
+    #[serde(default = "default_sandbox_hide")]
+    hide: Vec<std::path::PathBuf>,
+
+    /// Allow network access.
+    #[serde(default)]
+    network: bool,
+}
+
+/// By default, sandboxed steps don't see the home directory.
+fn default_sandbox_hide() -> Vec<std::path::PathBuf> {
+    vec!["~".into()]
+}
+
+impl Sandbox {
+    /// Arguments for `bwrap` to run the step at `path`, before the command.
+    ///
+    /// The step's own directory and `TICKBOX_CWD` stay readable, even if
+    /// under a hidden path, and its `TICKBOX_STEP_TMPDIR` writable. Relative
+    /// paths are relative to `TICKBOX_CWD`, and `~` is the home directory.
+    fn bwrap_args(&self, path: &std::path::Path, envs: &[(OsString, OsString)]) -> Vec<OsString> {
+        let env = |name: &str| {
+            envs.iter()
+                .rev()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| std::path::PathBuf::from(v))
+                .or_else(|| std::env::var_os(name).map(Into::into))
+        };
+        let cwd = env("TICKBOX_CWD").unwrap_or_default();
+        let home = env("HOME").unwrap_or_default();
+        let resolve = |p: &std::path::Path| match p.strip_prefix("~") {
+            Ok(rest) if rest.as_os_str().is_empty() => home.clone(),
+            Ok(rest) => home.join(rest),
+            Err(_) => cwd.join(p),
+        };
+        let mut args: Vec<OsString> = ["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"]
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        if !self.network {
+            args.push("--unshare-net".into());
+        }
+        args.push("--die-with-parent".into());
+        for p in self.hide.iter().map(|p| resolve(p)) {
+            args.extend(["--tmpfs".into(), p.into_os_string()]);
+        }
+        let dir = resolve(path.parent().unwrap_or(std::path::Path::new(".")));
+        for p in [dir, cwd.clone()] {
+            args.extend(["--ro-bind-try".into(), p.clone().into(), p.into()]);
+        }
+        let writable = self
+            .write
+            .iter()
+            .map(|p| resolve(p))
+            .chain(env("TICKBOX_STEP_TMPDIR"));
+        for p in writable {
+            args.extend(["--bind-try".into(), p.clone().into(), p.into()]);
+        }
+        args
+    }
+}
+
+/// Who to run a command as.
+#[derive(Clone, Debug, PartialEq)]
+struct RunAs {
+    uid: u32,
+    gid: u32,
+
+    /// Name and home directory of the user, if it has an entry in the user
+    /// database.
+    account: Option<(String, std::path::PathBuf)>,
+}
+
+impl RunAs {
+    /// Look up the user and group to run as. Without a group, the user's
+    /// primary group is used, and without a user, the current one.
+    fn new(user: Option<&str>, group: Option<&str>) -> Result<Option<Self>> {
+        if user.is_none() && group.is_none() {
+            return Ok(None);
+        }
+        // SAFETY: Just a syscall.
+        if unsafe { libc::geteuid() } != 0 {
+            return Err(Error::msg(
+                "tickbox must run as root to run steps as another user or group",
+            ));
+        }
+        let (uid, user_gid, account) = match user {
+            Some(user) => lookup_user(user)?,
+            None => (0, 0, None),
+        };
+        let gid = match group {
+            Some(group) => lookup_group(group)?,
+            None => user_gid,
+        };
+        Ok(Some(RunAs { uid, gid, account }))
+    }
+}
+
+/// Size of the buffer for user and group database entries.
+const PWBUF_SIZE: usize = 16384;
+
+/// Name and home directory of a user account.
+type Account = (String, std::path::PathBuf);
+
+/// User ID, primary group ID, and account of a user name or numeric ID.
+fn lookup_user(user: &str) -> Result<(u32, u32, Option<Account>)> {
+    use std::os::unix::ffi::OsStrExt;
+    // SAFETY: A plain C struct, for which all zeroes is valid.
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; PWBUF_SIZE];
+    let mut found = std::ptr::null_mut();
+    let numeric = user.parse::<u32>().ok();
+    let name = std::ffi::CString::new(user)?;
+    // SAFETY: All pointers are valid for the call, and the strings it
+    // points `pwd` to live in `buf`.
+    let rc = unsafe {
+        match numeric {
+            Some(uid) => libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found),
+            None => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            ),
+        }
+    };
+    if found.is_null() {
+        return match (numeric, rc) {
+            // A user ID without an entry is fine, with the same group ID.
+            (Some(uid), 0) => Ok((uid, uid, None)),
+            (_, 0) => Err(Error::msg(format!("No such user: {user}"))),
+            _ => Err(Error::msg(format!(
+                "Failed to look up user {user}: {}",
+                std::io::Error::from_raw_os_error(rc)
+            ))),
+        };
+    }
+    // SAFETY: Found, so these are NUL terminated strings in `buf`.
+    let (name, home) = unsafe {
+        (
+            std::ffi::CStr::from_ptr(pwd.pw_name),
+            std::ffi::CStr::from_ptr(pwd.pw_dir),
+        )
+    };
+    let account = (
+        name.to_string_lossy().into_owned(),
+        std::path::PathBuf::from(std::ffi::OsStr::from_bytes(home.to_bytes())),
+    );
+    Ok((pwd.pw_uid, pwd.pw_gid, Some(account)))
+}
+
+/// Group ID of a group name or numeric ID.
+fn lookup_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+    // SAFETY: A plain C struct, for which all zeroes is valid.
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; PWBUF_SIZE];
+    let mut found = std::ptr::null_mut();
+    let name = std::ffi::CString::new(group)?;
+    // SAFETY: All pointers are valid for the call.
+    let rc = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    match (found.is_null(), rc) {
+        (false, _) => Ok(grp.gr_gid),
+        (true, 0) => Err(Error::msg(format!("No such group: {group}"))),
+        (true, _) => Err(Error::msg(format!(
+            "Failed to look up group {group}: {}",
+            std::io::Error::from_raw_os_error(rc)
+        ))),
+    }
+}
+
+/// Output lines starting with this are commands from the step to tickbox.
+const STEP_COMMAND_PREFIX: &str = "::tickbox ";
+
+/// A command from a step to tickbox, in its output.
+#[derive(Debug, PartialEq)]
+enum StepCommand {
+    /// How far along the step is, in percent, from `progress 42%` or
+    /// `progress 3/10`.
+    Progress(u8),
+
+    /// From `set-output key=value` or `warning message`.
+    Annotate(Annotation),
+
+    /// A step to run after this one, from `add-step name command`.
+    AddStep(String, String),
+
+    /// A sub-item the step is working on, from `check "item"`.
+    Check(String),
+
+    /// The sub-item is done, from `done` or `done "item"`.
+    Done(Option<String>),
+}
+
+/// Something a step said about itself, for the summary and reports.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+enum Annotation {
+    /// A named value, like the URL of what the step deployed.
+    Output(String, String),
+    Warning(String),
+}
+
+impl std::fmt::Display for Annotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Annotation::Output(key, value) => write!(f, "{key}={value}"),
+            Annotation::Warning(msg) => write!(f, "warning: {msg}"),
+        }
+    }
+}
+
+/// Parse a line of output, if it's a command to tickbox. Anything else,
+/// including malformed commands, is just output.
+fn parse_step_command(line: &str) -> Option<StepCommand> {
+    let rest = line.strip_prefix(STEP_COMMAND_PREFIX)?.trim();
+    let (command, arg) = rest.split_once(' ').unwrap_or((rest, ""));
+    match command {
+        "progress" => {
+            let arg = arg.trim();
+            let percent = match arg.split_once('/') {
+                Some((done, total)) => {
+                    let done: f64 = done.trim().parse().ok()?;
+                    let total: f64 = total.trim().parse().ok()?;
+                    if total <= 0.0 {
+                        return None;
+                    }
+                    done / total * 100.0
+                }
+                None => arg.strip_suffix('%').unwrap_or(arg).parse().ok()?,
+            };
+            if !percent.is_finite() {
+                return None;
+            }
+            Some(StepCommand::Progress(percent.clamp(0.0, 100.0) as u8))
+        }
+        "set-output" => {
+            let (key, value) = arg.trim_start().split_once('=')?;
+            if key.is_empty() {
+                return None;
+            }
+            Some(StepCommand::Annotate(Annotation::Output(
+                key.to_string(),
+                value.to_string(),
+            )))
+        }
+        "warning" => Some(StepCommand::Annotate(Annotation::Warning(unquote(arg)))),
+        "add-step" => {
+            let (name, command) = arg.trim().split_once(char::is_whitespace)?;
+            let command = command.trim();
+            if command.is_empty() {
+                return None;
+            }
+            Some(StepCommand::AddStep(name.to_string(), command.to_string()))
+        }
+        "check" => {
+            let item = unquote(arg);
+            if item.is_empty() {
+                return None;
+            }
+            Some(StepCommand::Check(item))
+        }
+        "done" => Some(StepCommand::Done(
+            Some(unquote(arg)).filter(|item| !item.is_empty()),
+        )),
+        _ => None,
+    }
+}
+
+/// Argument of a step command, without any surrounding quotes.
+fn unquote(arg: &str) -> String {
+    let arg = arg.trim();
+    arg.strip_prefix('"')
+        .and_then(|a| a.strip_suffix('"'))
+        .unwrap_or(arg)
+        .to_string()
+}
+
+/// Where steps added by a running step go.
+#[derive(Clone, Debug)]
+struct AddedSteps {
+    /// The step adding steps.
+    parent: Task,
+
+    /// State of every step, which is also where added steps get their
+    /// number from.
+    states: Arc<Mutex<Vec<State>>>,
+
+    /// Added steps the scheduler hasn't seen yet, with the number of the
+    /// step that added them.
+    steps: Arc<Mutex<Vec<(usize, Task)>>>,
+}
+
+impl AddedSteps {
+    /// Add a step running `command` with bash, to run after the parent.
+    /// Steps added by the same parent may run in parallel, like a matrix.
+    fn add(&self, name: &str, command: &str, tx: &UiSender) -> Result<Task, UiClosed> {
+        // Hold the lock until the UI has been told, so that it learns about
+        // the new steps in order.
+        let mut states = self.states.lock().unwrap();
+        let task = Task {
+            n: states.len(),
+            name: format!("{} [{name}]", self.parent.name),
+            state: State::Pending,
+            matrix: true,
+            expected_failure: false,
+            inline: Some(command.to_string()),
+            stalled: false,
+            progress: None,
+            warnings: 0,
+            checklist: Vec::new(),
+            ..self.parent.clone()
+        };
+        states.push(State::Pending);
+        self.steps
+            .lock()
+            .unwrap()
+            .push((self.parent.n, task.clone()));
+        tx.send_now(UIUpdate::Status(task.clone()))?;
+        Ok(task)
+    }
+}
+
+/// Where the output lines of a command go.
+struct OutputSink {
+    step: usize,
+    tx: UiSender,
+    limit: OutputLimit,
+    log: Option<(std::path::PathBuf, std::fs::File)>,
+    lines: usize,
+    bytes: usize,
+
+    /// Lines not sent to the UI, because of the limit.
+    dropped: usize,
+
+    /// What the step said about itself.
+    annotations: Vec<Annotation>,
+
+    /// Where steps added by the command go, if it may add any.
+    added: Option<AddedSteps>,
+}
+
+impl OutputSink {
+    /// Handle a line, returning `false` if the UI is gone.
+    async fn line(&mut self, line: String, stderr: bool) -> bool {
+        use std::io::Write;
+        if let Some((_, file)) = &mut self.log {
+            let prefix = if stderr { STDERR_PREFIX } else { "" };
+            // Losing the log isn't worth failing the step over.
+            let _ = writeln!(file, "{prefix}{line}");
+        }
+        if let Some(command) = parse_step_command(&line) {
+            let update = match command {
+                StepCommand::Progress(p) => UIUpdate::Progress(self.step, p),
+                StepCommand::Annotate(a) => {
+                    let line = UIUpdate::AddLine(self.step, format!("==> {a}"));
+                    if self.tx.send(line).await.is_err() {
+                        return false;
+                    }
+                    self.annotations.push(a.clone());
+                    UIUpdate::Annotation(self.step, a)
+                }
+                StepCommand::AddStep(name, command) => match &self.added {
+                    Some(added) => match added.add(&name, &command, &self.tx) {
+                        Ok(task) => UIUpdate::AddLine(
+                            self.step,
+                            format!("==> Added step \"{}\"", task.name),
+                        ),
+                        Err(UiClosed) => return false,
+                    },
+                    None => UIUpdate::AddLine(
+                        self.step,
+                        format!("==> Only steps can add steps, not adding \"{name}\""),
+                    ),
+                },
+                StepCommand::Check(item) => {
+                    let line = UIUpdate::AddLine(self.step, format!("==> {item}"));
+                    if self.tx.send(line).await.is_err() {
+                        return false;
+                    }
+                    UIUpdate::Check(self.step, item)
+                }
+                StepCommand::Done(item) => UIUpdate::CheckDone(self.step, item),
+            };
+            return self.tx.send(update).await.is_ok();
+        }
+        self.lines += 1;
+        self.bytes += line.len() + 1;
+        let over = self.limit.lines.is_some_and(|l| self.lines > l)
+            || self.limit.bytes.is_some_and(|b| self.bytes > b);
+        if !over {
+            let update = if stderr {
+                UIUpdate::AddStderrLine(self.step, line)
+            } else {
+                UIUpdate::AddLine(self.step, line)
+            };
+            return self.tx.send(update).await.is_ok();
+        }
+        self.dropped += 1;
+        if self.dropped > 1 {
+            return true;
+        }
+        let msg = match &self.log {
+            Some((path, _)) => format!("[output truncated, the rest is in {}]", path.display()),
+            None => "[output truncated]".to_string(),
+        };
+        self.tx
+            .send(UIUpdate::AddLine(self.step, msg))
+            .await
+            .is_ok()
+    }
+
+    /// Say how much output was truncated, if any.
+    async fn finish(&mut self) {
+        if self.dropped == 0 {
+            return;
+        }
+        let msg = match &self.log {
+            Some((path, _)) => format!(
+                "[output truncated, {} more lines in {}]",
+                self.dropped,
+                path.display()
+            ),
+            None => format!("[output truncated, {} more lines]", self.dropped),
+        };
+        let _ = self.tx.send(UIUpdate::AddLine(self.step, msg)).await;
+    }
+}
+
+/// Reads lines of output, whatever the bytes.
+///
+/// Invalid UTF-8 is replaced, and lines longer than the max are split.
+struct LineReader<R> {
+    reader: tokio::io::BufReader<R>,
+    buf: Vec<u8>,
+    max: usize,
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> LineReader<R> {
+    fn new(reader: R, max: usize) -> Self {
+        Self {
+            reader: tokio::io::BufReader::new(reader),
+            buf: Vec::new(),
+            max: max.max(1),
+        }
+    }
+
+    /// Read the next line, or `None` at the end.
+    ///
+    /// Cancel safe, since partial lines are kept in `self`.
+    async fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        use tokio::io::AsyncBufReadExt;
+        loop {
+            let data = self.reader.fill_buf().await?;
+            if data.is_empty() {
+                return Ok((!self.buf.is_empty()).then(|| self.take()));
+            }
+            let room = self.max - self.buf.len();
+            // A full line may still be followed by its newline.
+            let end = data.len().min(room + 1);
+            if let Some(i) = data[..end].iter().position(|&b| b == b'\n') {
+                self.buf.extend_from_slice(&data[..i]);
+                self.reader.consume(i + 1);
+                return Ok(Some(self.take()));
+            }
+            let n = data.len().min(room);
+            self.buf.extend_from_slice(&data[..n]);
+            self.reader.consume(n);
+            if self.buf.len() >= self.max {
+                return Ok(Some(self.take()));
+            }
+        }
+    }
+
+    fn take(&mut self) -> String {
+        if self.buf.last() == Some(&b'\r') {
+            self.buf.pop();
+        }
+        let line = String::from_utf8_lossy(&self.buf).into_owned();
+        self.buf.clear();
+        line
+    }
+}
+
+/// Run a command, returning its exit code, or `None` if it was killed, and
+/// what it said about itself.
+async fn run_command(
+    step: usize,
+    name: &str,
+    path: &std::path::Path,
+    envs: &[(OsString, OsString)],
+    run: &RunOptions,
+    tx: UiSender,
+) -> Result<(Option<i32>, Vec<Annotation>)> {
+    // TODO: Make this fixed width.
+    tx.send(UIUpdate::AddLine(
+        step,
+        format!("============ Running \"{name}\" ================",),
+    ))
+    .await
+    .unwrap();
+
+    let mut command = if let Some(inline) = &run.inline {
+        let mut command = tokio::process::Command::new("bash");
+        command.arg("-c").arg(inline).arg(path);
+        command
+    } else if run.shell {
+        let mut command = tokio::process::Command::new("bash");
+        command.arg(path);
+        command
+    } else {
+        tokio::process::Command::new(path)
+    };
+    command.args(&run.args);
+    let (wrapper, tool_envs) = run.tool_resolver.resolve(&run.tools);
+    if !wrapper.is_empty() {
+        command = wrap_command(&command, &wrapper);
+    }
+    if !run.dev_shell.is_empty() {
+        command = wrap_command(&command, &run.dev_shell);
+    }
+    if let Some(sandbox) = &run.sandbox {
+        let mut wrapper = vec!["bwrap".into()];
+        wrapper.extend(sandbox.bwrap_args(path, envs));
+        wrapper.push("--".into());
+        command = wrap_command(&command, &wrapper);
+    }
+    command
+        .envs(envs.iter().map(|(k, v)| (k.as_os_str(), v.as_os_str())))
+        .envs(tool_envs)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        // Kill the command if the step is aborted.
+        .kill_on_drop(true);
+    if run.offline {
+        isolate_network(&mut command);
+    }
+    if let Some(run_as) = RunAs::new(run.user.as_deref(), run.group.as_deref())? {
+        command.uid(run_as.uid).gid(run_as.gid);
+        if let Some((name, home)) = &run_as.account {
+            command
+                .env("USER", name)
+                .env("LOGNAME", name)
+                .env("HOME", home);
+        }
+        // Let the step use its own temporary directory.
+        if let Some((_, dir)) = envs.iter().rev().find(|(k, _)| k == "TICKBOX_STEP_TMPDIR") {
+            use std::os::unix::fs::PermissionsExt;
+            let dir = std::path::Path::new(dir);
+            if let Some(root) = dir.parent() {
+                std::fs::set_permissions(root, std::fs::Permissions::from_mode(0o711))?;
+            }
+            std::os::unix::fs::chown(dir, Some(run_as.uid), Some(run_as.gid))?;
+        }
+    }
+    let prio = run.prio;
+    if prio.nice.is_some() || prio.ioprio.is_some() {
+        // SAFETY: Only makes syscalls, which is fine between fork and exec.
+        unsafe {
+            command.pre_exec(move || prio.apply());
+        }
+    }
+    if run.merge_output {
+        // Send stderr to the stdout pipe, like `2>&1`, to keep the order of
+        // lines. The stderr pipe then just gets closed.
+        //
+        // SAFETY: Only makes a syscall, which is fine between fork and exec.
+        unsafe {
+            command.pre_exec(|| match libc::dup2(1, 2) {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(()),
+            });
+        }
+    }
+    let mut cmd = command
+        .spawn()
+        .map_err(|e| Error::msg(format!("Failed to run {}: {e}", path.display())))?;
+    let stdout = cmd.stdout.take().unwrap();
+    let stderr = cmd.stderr.take().unwrap();
+    let max_line = run.max_line.unwrap_or(DEFAULT_MAX_LINE);
+    let mut lout = LineReader::new(stdout, max_line);
+    let mut lerr = LineReader::new(stderr, max_line);
+
+    let log = match &run.log {
+        Some(path) => Some((
+            path.clone(),
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| Error::msg(format!("Failed to open {}: {e}", path.display())))?,
+        )),
+        None => None,
+    };
+    let mut sink = OutputSink {
+        step,
+        tx: tx.clone(),
+        limit: run.limit,
+        log,
+        lines: 0,
+        bytes: 0,
+        dropped: 0,
+        annotations: Vec::new(),
+        added: run.added.clone(),
+    };
+    let mut out_open = true;
+    let mut err_open = true;
+    let stall_timeout = run.stall_timeout.unwrap_or_default();
+    let stall = tokio::time::sleep(stall_timeout);
+    tokio::pin!(stall);
+    let mut stalled = false;
+
+    loop {
+        trace!("Main loop iteration");
+        tokio::select! {
+            line = lerr.next_line(), if err_open => {
+                trace!("Stderr line");
+                match line? {
+                    Some(line) => {
+                        stall.as_mut().reset(tokio::time::Instant::now() + stall_timeout);
+                        if stalled {
+                            stalled = false;
+                            tx.send(UIUpdate::Stalled(step, false)).await.unwrap();
+                        }
+                        if !sink.line(line, true).await {
+                            cmd.kill().await?;
+                            break;
+                        }
+                    }
+                    None => err_open = false,
+                }
+            }
+            line = lout.next_line(), if out_open => {
+                trace!("Stdout line");
+                match line? {
+                    Some(line) => {
+                        stall.as_mut().reset(tokio::time::Instant::now() + stall_timeout);
+                        if stalled {
+                            stalled = false;
+                            tx.send(UIUpdate::Stalled(step, false)).await.unwrap();
+                        }
+                        if !sink.line(line, false).await {
+                            cmd.kill().await?;
+                            break;
+                        }
+                    }
+                    None => out_open = false,
+                }
+            }
+            () = &mut stall, if run.stall_timeout.is_some() && !stalled => {
+                stalled = true;
+                tx.send(UIUpdate::Stalled(step, true)).await.unwrap();
+                tx.send(UIUpdate::AddLine(step, format!(
+                    "==> No output from \"{name}\" for {stall_timeout:?}, it may be stalled",
+                )))
+                .await
+                .unwrap();
+                if run.kill_stalled {
+                    tx.send(UIUpdate::AddLine(step, "==> Killing stalled step".to_string()))
+                        .await
+                        .unwrap();
+                    cmd.start_kill()?;
+                }
+            }
+
+            status = cmd.wait() => {
+                trace!("Command finished");
+                let status = status?;
+                sink.finish().await;
+                tx.send(UIUpdate::AddLine(step, "".to_string())).await.unwrap();
+                use std::os::unix::process::ExitStatusExt;
+                if let Some(code) = status.code() {
+                    tx.send(UIUpdate::AddLine(step, format!(
+                        "==> Command \"{name}\" exited with code {code}",
+                    )))
+                    .await
+                    .unwrap();
+                } else if let Some(sig) = status.signal() {
+                    tx.send(UIUpdate::AddLine(step, format!(
+                        "==> Command \"{name}\" exited with signal {sig} ",
+                    )))
+                    .await
+                    .unwrap();
+                }
+                return Ok((status.code(), sink.annotations));
+            },
+        };
+    }
+    Ok((None, sink.annotations))
+}
+
+/// Run a hook from the config, if one is set.
+///
+/// Returns `true` if there was no hook, or if the hook succeeded.
+async fn run_hook(
+    step: usize,
+    name: &str,
+    hook: Option<&std::path::Path>,
+    envs: &[(OsString, OsString)],
+    tx: UiSender,
+) -> Result<bool> {
+    match hook {
+        Some(path) => {
+            let (code, _) = run_command(step, name, path, envs, &RunOptions::default(), tx).await?;
+            Ok(code == Some(0))
+        }
+        None => Ok(true),
+    }
+}
+
+/// Check a Rhai condition, like `env.TICKBOX_BRANCH == "main"`.
+async fn check_condition(cond: &str, envs: &[(OsString, OsString)]) -> Result<bool> {
+    let (cond, envs) = (cond.to_string(), envs.to_vec());
+    // Scripts can run commands, so keep them off the async runtime.
+    task::spawn_blocking(move || eval_script(&cond, &envs)).await?
+}
+
+/// Set environment variables to the values of Rhai scripts.
+///
+/// The scripts are run in name order, and see the variables set before
+/// them.
+fn compute_envs(
+    scripts: &std::collections::BTreeMap<String, String>,
+    envs: &mut Vec<(OsString, OsString)>,
+) -> Result<()> {
+    for (name, script) in scripts {
+        let value: rhai::Dynamic = eval_script(script, envs)
+            .map_err(|e| Error::msg(format!("Failed to compute {name}: {e}")))?;
+        envs.push((name.into(), value.to_string().into()));
+    }
+    Ok(())
+}
+
+/// Evaluate a Rhai script, with the environment variables in the object
+/// map `env`, and `run(command)` to get the output of a shell command.
+fn eval_script<T: Clone + 'static>(script: &str, envs: &[(OsString, OsString)]) -> Result<T> {
+    let mut engine = rhai::Engine::new();
+    engine.set_max_operations(SCRIPT_MAX_OPERATIONS);
+    let run_envs = envs.to_vec();
+    engine.register_fn(
+        "run",
+        move |cmd: &str| -> Result<String, Box<rhai::EvalAltResult>> {
+            command_output(cmd, &run_envs).map_err(|e| e.to_string().into())
+        },
+    );
+    let env: rhai::Map = envs
+        .iter()
+        .map(|(k, v)| {
+            let v = v.to_string_lossy().to_string();
+            (k.to_string_lossy().into(), v.into())
+        })
+        .collect();
+    let mut scope = rhai::Scope::new();
+    scope.push_constant("env", env);
+    engine
+        .eval_with_scope(&mut scope, script)
+        .map_err(|e| Error::msg(format!("{script}: {e}")))
+}
+
+/// Output of a shell command, without trailing newlines.
+fn command_output(cmd: &str, envs: &[(OsString, OsString)]) -> Result<String> {
+    let out = std::process::Command::new("bash")
+        .arg("-c")
+        .arg(cmd)
+        .envs(envs.iter().map(|(k, v)| (k.as_os_str(), v.as_os_str())))
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        return Err(Error::msg(format!(
+            "Command failed with {}: {cmd}",
+            out.status
+        )));
+    }
+    let out = String::from_utf8_lossy(&out.stdout);
+    Ok(out.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// What a plugin says about an event.
+#[derive(Debug, Default, serde::Deserialize)]
+struct PluginResponse {
+    /// Reason not to run the step, which is then skipped.
+    veto: Option<String>,
+
+    /// Lines to add to the output of the step.
+    #[serde(default)]
+    annotations: Vec<String>,
+}
+
+/// Directory with user level tickbox files, `~/.config/tickbox`, or under
+/// `$XDG_CONFIG_HOME`.
+fn config_dir() -> Option<std::path::PathBuf> {
+    let config = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("tickbox"))
+}
+
+/// Directory for cached files, `~/.cache/tickbox`, or under
+/// `$XDG_CACHE_HOME`.
+fn cache_dir() -> Option<std::path::PathBuf> {
+    let cache = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache.join("tickbox"))
+}
+
+/// Directory with plugins.
+fn plugin_dir() -> Option<std::path::PathBuf> {
+    Some(config_dir()?.join("plugins"))
+}
+
+/// Settings from the user config, `config.json` in `config_dir()`.
+fn user_config() -> Result<serde_json::Map<String, serde_json::Value>> {
+    match config_dir() {
+        Some(dir) => read_config_json(&dir.join("config.json")),
+        None => Ok(Default::default()),
+    }
+}
+
+/// Executables in the plugin directory, sorted by name.
+fn list_plugins() -> Vec<std::path::PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    let Some(Ok(entries)) = plugin_dir().map(std::fs::read_dir) else {
+        return Vec::new();
+    };
+    let mut plugins: Vec<_> = entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| {
+            std::fs::metadata(p).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+        .collect();
+    plugins.sort();
+    plugins
+}
+
+/// Run every plugin with an event as JSON on stdin, and parse their
+/// responses from stdout. Plugins without anything to say print nothing.
+async fn run_plugins(
+    plugins: &[std::path::PathBuf],
+    event: &serde_json::Value,
+    envs: &[(OsString, OsString)],
+) -> Vec<(String, Result<PluginResponse>)> {
+    let mut out = Vec::new();
+    for plugin in plugins {
+        let name = plugin
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        out.push((name, run_plugin(plugin, event, envs, PLUGIN_TIMEOUT).await));
+    }
+    out
+}
+
+/// Run a plugin, killing it if it takes longer than `timeout`.
+async fn run_plugin(
+    plugin: &std::path::Path,
+    event: &serde_json::Value,
+    envs: &[(OsString, OsString)],
+    timeout: Duration,
+) -> Result<PluginResponse> {
+    use tokio::io::AsyncWriteExt;
+    let mut child = tokio::process::Command::new(plugin)
+        .envs(envs.iter().map(|(k, v)| (k.as_os_str(), v.as_os_str())))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let run = async {
+        // Plugins that don't care about the event don't have to read it.
+        match stdin.write_all(format!("{event}\n").as_bytes()).await {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+            other => other?,
+        }
+        drop(stdin);
+        Ok::<_, Error>(child.wait_with_output().await?)
+    };
+    // Dropping the child on timeout kills it.
+    let output = tokio::time::timeout(timeout, run).await.map_err(|_| {
+        Error::msg(format!(
+            "timed out after {}",
+            format_duration(timeout).trim()
+        ))
+    })??;
+    if !output.status.success() {
+        return Err(Error::msg(format!("exited with {}", output.status)));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.trim().is_empty() {
+        return Ok(PluginResponse::default());
+    }
+    Ok(serde_json::from_str(&stdout)?)
+}
+
+/// Send an event about a step to the plugins, and show their annotations
+/// and errors in its output.
+///
+/// Returns `true` if any plugin vetoed the step, which then doesn't run and
+/// counts as skipped.
+async fn step_plugins(
+    n: usize,
+    plugins: &[std::path::PathBuf],
+    event: serde_json::Value,
+    envs: &[(OsString, OsString)],
+    tx: &UiSender,
+) -> bool {
+    let mut vetoed = false;
+    for (name, response) in run_plugins(plugins, &event, envs).await {
+        let mut lines = Vec::new();
+        match response {
+            Ok(r) => {
+                lines.extend(r.annotations.iter().map(|a| format!("==> {name}: {a}")));
+                if let Some(reason) = r.veto {
+                    lines.push(format!("==> Vetoed by plugin {name}: {reason}"));
+                    vetoed = true;
+                }
+            }
+            Err(e) => lines.push(format!("==> Plugin {name} failed: {e}")),
+        }
+        for line in lines {
+            let _ = tx.send(UIUpdate::AddLine(n, line)).await;
+        }
+    }
+    vetoed
+}
+
+fn parse_usize_prefix(input: &str) -> Option<usize> {
+    let digits_end = input
+        .char_indices()
+        .take_while(|(_, c)| c.is_ascii_digit())
+        .map(|(i, _)| i + 1)
+        .last()?;
+
+    let (digits, _) = input.split_at(digits_end);
+    let value = digits.parse::<usize>().ok()?;
+    Some(value)
+}
+
+/// Number of a step: its position in `order`, counting from 1, or its
+/// numeric prefix if there's no `order`.
+fn step_id(name: &str, order: &[String]) -> Result<usize> {
+    if order.is_empty() {
+        return parse_usize_prefix(name).ok_or(Error::msg(format!(
+            "step name doesn't start with a number: {name}"
+        )));
+    }
+    let top = name.split('/').next().unwrap_or(name);
+    order
+        .iter()
+        .position(|o| o == top)
+        .map(|i| i + 1)
+        .ok_or(Error::msg(format!("step isn't listed in order: {top}")))
+}
+
+/// List steps in a directory, as number, name, and path, sorted.
+///
+/// The numbers are from `order`, if not empty, or else numeric prefixes.
+fn list_steps(
+    path: &std::path::Path,
+    order: &[String],
+) -> Result<Vec<(usize, String, std::path::PathBuf)>> {
+    use itertools::Itertools;
+    Ok(std::fs::read_dir(path)
+        .map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Failed to read directory {}: {e}", path.display()),
+            )
+        })?
+        .flatten()
+        .filter_map(|entry| {
+            let cmd = entry.path();
+            let name = cmd.file_name().unwrap().to_str().unwrap().to_string();
+
+            if name.ends_with("~") // Don't join.
+               || name.ends_with(".conf")
+               || name.ends_with(".json")
+               || name.starts_with(".")
+            {
+                return None;
+            }
+            let id = match step_id(&name, order) {
+                Ok(x) => x,
+                Err(e) => return Some(Err(e)),
+            };
+            Some(Ok((id, name, cmd)))
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        // Steps sharing a number are in name order.
+        .sorted_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)))
+        .collect())
+}
+
+/// Name, command, and environment of a step in a group.
+type GroupStep = (String, std::path::PathBuf, Vec<(OsString, OsString)>);
+
+/// Load the steps of a subdirectory, recursing into further subdirectories.
+///
+/// A subdirectory can be a workflow of its own, e.g. a symlink to a reusable
+/// one. Its config `envs` then apply to its steps, on top of `envs`.
+fn load_group(
+    dir: &std::path::Path,
+    prefix: &str,
+    envs: &[(OsString, OsString)],
+) -> Result<Vec<GroupStep>> {
+    let mut envs = envs.to_vec();
+    envs.extend(load_config(dir)?.envs);
+    let mut out = Vec::new();
+    for (_, name, cmd) in list_steps(dir, &[])? {
+        let name = format!("{prefix}/{name}");
+        if cmd.is_dir() {
+            out.extend(load_group(&cmd, &name, &envs)?);
+        } else {
+            out.push((name, cmd, envs.clone()));
+        }
+    }
+    Ok(out)
+}
+
+/// Load workflow (list of tasks) from directory.
+///
+/// A subdirectory is a group of steps, which all get the group's ID. Steps
+/// are numbered by their position in `order`, if not empty.
+fn load_tasks(path: &std::path::Path, order: &[String]) -> Result<Vec<Task>> {
+    let mut tasks = Vec::new();
+    for (id, name, cmd) in list_steps(path, order)? {
+        if cmd.is_dir() {
+            for (sub, cmd, envs) in load_group(&cmd, &name, &[])? {
+                tasks.push(Task {
+                    n: tasks.len(),
+                    id,
+                    name: sub,
+                    cmd,
+                    state: State::Pending,
+                    group: Some(name.clone()),
+                    envs,
+                    matrix: false,
+                    expected_failure: false,
+                    inline: None,
+                    stalled: false,
+                    progress: None,
+                    warnings: 0,
+                    checklist: Vec::new(),
+                });
+            }
+        } else {
+            tasks.push(Task {
+                n: tasks.len(),
+                id,
+                name,
+                cmd,
+                state: State::Pending,
+                group: None,
+                envs: Vec::new(),
+                matrix: false,
+                expected_failure: false,
+                inline: None,
+                stalled: false,
+                progress: None,
+                warnings: 0,
+                checklist: Vec::new(),
+            });
+        }
+    }
+    Ok(tasks)
+}
+
+/// Add the steps from the config to those from files, in order of ID.
+///
+/// Scripts are relative to `dir`.
+fn add_inline_steps(
+    mut steps: Vec<Task>,
+    inline: &[InlineStep],
+    dir: &std::path::Path,
+    order: &[String],
+) -> Result<Vec<Task>> {
+    for step in inline {
+        let id = step_id(&step.name, order)?;
+        let (cmd, inline) = match (&step.run, &step.script) {
+            (Some(run), None) => (step.name.clone().into(), Some(run.clone())),
+            (None, Some(script)) => (dir.join(script), None),
+            _ => {
+                return Err(Error::msg(format!(
+                    "step {} needs exactly one of run and script",
+                    step.name
+                )));
+            }
+        };
+        steps.push(Task {
+            n: 0,
+            id,
+            name: step.name.clone(),
+            cmd,
+            state: State::Pending,
+            group: step.name.split_once('/').map(|(g, _)| g.to_string()),
+            envs: Vec::new(),
+            matrix: false,
+            expected_failure: false,
+            inline,
+            stalled: false,
+            progress: None,
+            warnings: 0,
+            checklist: Vec::new(),
+        });
+    }
+    // Stable, so steps keep their order within an ID.
+    steps.sort_by_key(|t| t.id);
+    for (n, t) in steps.iter_mut().enumerate() {
+        t.n = n;
+    }
+    Ok(steps)
+}
+
+/// A loaded workflow, ready to run.
+struct Workflow {
+    /// Workflow directory, or the directory of the workflow file.
+    dir: std::path::PathBuf,
+
+    /// Where to run the steps, if `--cwd` isn't given.
+    default_cwd: std::path::PathBuf,
+    conf: Config,
+    steps: Vec<Task>,
+
+    /// Things that are allowed, but look like mistakes.
+    warnings: Vec<String>,
+
+    /// The `--profile`, if any, for its step filters.
+    profile: Profile,
+}
+
+/// Numbers shared by more than one step, with the names of the steps. The
+/// steps of a group count as one.
+fn duplicate_ids(steps: &[Task]) -> Vec<(usize, Vec<String>)> {
+    let mut ids: std::collections::BTreeMap<usize, Vec<String>> = Default::default();
+    for t in steps {
+        let name = t.group.as_ref().unwrap_or(&t.name);
+        let names = ids.entry(t.id).or_default();
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    ids.into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .collect()
+}
+
+/// Find and load the workflow and its config, per the options.
+fn load_workflow(opt: &Opt) -> Result<Workflow> {
+    let (dir, default_cwd) = match (&opt.file, &opt.dir) {
+        // Absolute, so that scripts aren't looked up in `$PATH` when the
+        // file has no directory part, and still work after changing
+        // directory.
+        (Some(file), _) => (
+            std::path::absolute(
+                file.parent()
+                    .filter(|p| !p.as_os_str().is_empty())
+                    .unwrap_or(std::path::Path::new(".")),
+            )?,
+            ".".into(),
+        ),
+        (None, Some(dir)) => (dir.clone(), ".".into()),
+        (None, None) => {
+            let dir = find_workflow_dir()?;
+            let root = dir
+                .parent()
+                .expect("workflow dir has no parent")
+                .to_path_buf();
+            (dir, root)
+        }
+    };
+    let dir = match &opt.name {
+        Some(name) => dir.join(name),
+        None => dir,
+    };
+    let path = match &opt.file {
+        Some(file) if !file.exists() => {
+            return Err(Error::msg(format!("{} doesn't exist", file.display())));
+        }
+        Some(file) => file.clone(),
+        None => dir.join("tickbox.json"),
+    };
+    // The user config has defaults, that the workflow config overrides.
+    let mut map = user_config()?;
+    map.extend(read_config_json(&path)?);
+    let mut conf: Config = serde_path_to_error::deserialize(serde_json::Value::from(map))
+        .map_err(|e| config_error(&path, e))?;
+    let profile = match &opt.profile {
+        Some(name) => conf.apply_profile(name)?,
+        None => Profile::default(),
+    };
+    // Hooks are relative to the workflow directory.
+    conf.before_each = conf.before_each.map(|p| dir.join(p));
+    conf.after_each = conf.after_each.map(|p| dir.join(p));
+    if opt.verify || opt.verify_key.is_some() {
+        let manifest = Manifest::load(&dir, opt.verify_key.as_deref())?;
+        // The config and hooks are checked now, and steps before they run.
+        let hooks = [&conf.before_each, &conf.after_each];
+        for p in std::iter::once(&path)
+            .filter(|p| p.exists())
+            .chain(hooks.into_iter().flatten())
+        {
+            manifest.verify(p)?;
+        }
+        conf.manifest = Some(manifest);
+    }
+    let steps = match opt.file {
+        Some(_) => Vec::new(),
+        None => load_tasks(&dir, &conf.order)?,
+    };
+    let steps = add_inline_steps(steps, &conf.steps, &dir, &conf.order)?;
+    if steps.is_empty() {
+        return Err(Error::msg("No steps in workflow"));
+    }
+    if let Some(name) = conf.order.iter().find(|o| {
+        !steps
+            .iter()
+            .any(|t| t.group.as_ref().unwrap_or(&t.name) == *o)
+    }) {
+        return Err(Error::msg(format!("step in order doesn't exist: {name}")));
+    }
+    if let Some(step) = conf.flake.keys().find(|s| conf.nix_shell.contains_key(*s)) {
+        return Err(Error::msg(format!(
+            "step {step} has both a flake and a nix_shell"
+        )));
+    }
+    for (step, needs) in &conf.needs {
+        for (res, n) in needs {
+            match conf.resources.get(res) {
+                None => {
+                    return Err(Error::msg(format!(
+                        "step {step} needs undefined resource {res}"
+                    )));
+                }
+                Some(have) if n > have => {
+                    return Err(Error::msg(format!(
+                        "step {step} needs {n} {res}, but there are only {have}"
+                    )));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    let warnings = duplicate_ids(&steps)
+        .into_iter()
+        .map(|(id, names)| {
+            format!(
+                "Steps {} share number {id}, and run in name order",
+                names.join(", ")
+            )
+        })
+        .collect();
+    let mut steps = expand_matrix(steps, &conf.matrix);
+    for s in &mut steps {
+        s.expected_failure = conf.expected_failure.iter().any(|r| r.is_match(&s.name));
+    }
+    Ok(Workflow {
+        dir,
+        default_cwd,
+        conf,
+        steps,
+        warnings,
+        profile,
+    })
+}
+
+/// Check that all step files can be executed, listing those that can't.
+///
+/// Inline steps, and steps run as bash scripts, don't need to be executable.
+fn check_executable(steps: &[Task], shell: &[regex::Regex]) -> Result<()> {
+    use itertools::Itertools;
+    use std::os::unix::fs::PermissionsExt;
+    let bad: Vec<String> = steps
+        .iter()
+        .filter(|s| s.inline.is_none() && !shell.iter().any(|r| r.is_match(&s.name)))
+        .filter(|s| {
+            !std::fs::metadata(&s.cmd)
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+        .map(|s| s.cmd.display().to_string())
+        .unique()
+        .collect();
+    if bad.is_empty() {
+        return Ok(());
+    }
+    Err(Error::msg(format!(
+        "Step files not executable, try chmod +x, or the shell setting:\n  {}",
+        bad.join("\n  ")
+    )))
+}
+
+/// Expand matrix steps into one step per combination of values.
+fn expand_matrix(
+    steps: Vec<Task>,
+    matrix: &std::collections::HashMap<String, std::collections::BTreeMap<String, Vec<String>>>,
+) -> Vec<Task> {
+    use itertools::Itertools;
+    let mut out = Vec::new();
+    for step in steps {
+        let Some(vars) = matrix.get(&step.name) else {
+            out.push(Task {
+                n: out.len(),
+                ..step
+            });
+            continue;
+        };
+        for values in vars
+            .iter()
+            .map(|(k, vs)| vs.iter().map(move |v| (k, v)))
+            .multi_cartesian_product()
+        {
+            let mut envs = step.envs.clone();
+            envs.extend(values.iter().map(|(k, v)| {
+                (
+                    format!("TICKBOX_MATRIX_{}", k.to_uppercase()).into(),
+                    OsString::from(v),
+                )
+            }));
+            out.push(Task {
+                n: out.len(),
+                name: format!(
+                    "{} [{}]",
+                    step.name,
+                    values.iter().map(|(k, v)| format!("{k}={v}")).join(" ")
+                ),
+                envs,
+                matrix: true,
+                expected_failure: false,
+                inline: None,
+                ..step.clone()
+            });
+        }
+    }
+    out
+}
+
+fn format_duration(d: Duration) -> String {
+    format!("{:7.1}s", d.as_secs_f64())
+}
+
+/// Format an estimate, rounded up to whole seconds, minutes, or hours and
+/// minutes.
+fn format_eta(d: Duration) -> String {
+    let secs = d.as_secs() + u64::from(d.subsec_nanos() > 0);
+    if secs < 60 {
+        format!("{secs}s")
+    } else if secs < 3600 {
+        format!("{}m", secs.div_ceil(60))
+    } else {
+        let mins = secs.div_ceil(60);
+        format!("{}h{:02}m", mins / 60, mins % 60)
+    }
+}
+
+/// Estimate how long is left of the workflow, from how long steps took last
+/// time. Each parallel group takes as long as its slowest step, or its total
+/// divided by `max_concurrency`, whichever is more. Steps without an estimate
+/// count as instant.
+///
+/// Returns `None` if nothing is left, or there are no estimates.
+fn estimate_remaining(
+    steps: &[Task],
+    parallel_groups: &[Vec<usize>],
+    estimates: &std::collections::HashMap<String, Duration>,
+    max_concurrency: usize,
+) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    for group in parallel_groups {
+        let left: Vec<Duration> = group
+            .iter()
+            .filter_map(|&n| steps.get(n))
+            .map(|s| match (&s.state, estimates.get(&s.name)) {
+                (State::Pending, Some(e)) => *e,
+                (State::Running(st), Some(e)) => e.saturating_sub(since(*st)),
+                _ => Duration::ZERO,
+            })
+            .collect();
+        let longest = left.iter().max().copied().unwrap_or_default();
+        let spread = left.iter().sum::<Duration>() / max_concurrency.max(1) as u32;
+        total += longest.max(spread);
+    }
+    if total.is_zero() { None } else { Some(total) }
+}
+
+/// Load how long steps took the last time they succeeded.
+///
+/// A missing or broken file just means there are no estimates.
+fn load_times(path: &std::path::Path) -> std::collections::HashMap<String, Duration> {
+    let Ok(data) = std::fs::read_to_string(path) else {
+        return Default::default();
+    };
+    let secs: std::collections::HashMap<String, f64> = match serde_json::from_str(&data) {
+        Ok(secs) => secs,
+        Err(e) => {
+            warn!("Ignoring step times in {}: {e}", path.display());
+            return Default::default();
+        }
+    };
+    secs.into_iter()
+        .filter_map(|(k, v)| Duration::try_from_secs_f64(v).ok().map(|d| (k, d)))
+        .collect()
+}
+
+/// Save step times, for estimates on the next run.
+fn save_times(
+    path: &std::path::Path,
+    times: &std::collections::HashMap<String, Duration>,
+) -> Result<()> {
+    let secs: std::collections::BTreeMap<&String, f64> =
+        times.iter().map(|(k, v)| (k, v.as_secs_f64())).collect();
+    std::fs::write(path, serde_json::to_string_pretty(&secs)? + "\n")?;
+    Ok(())
+}
+
+/// File for tickbox's own state about a workflow, like `.tickbox.times` in
+/// the workflow directory, or `<file>.times` next to a workflow file.
+fn state_path(opt: &Opt, dir: &std::path::Path, ext: &str) -> Result<std::path::PathBuf> {
+    Ok(std::path::absolute(match &opt.file {
+        Some(file) => file.with_extension(ext),
+        None => dir.join(format!(".tickbox.{ext}")),
+    })?)
+}
+
+/// One run of a workflow, as kept in its history for `tickbox diff`.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct RunHistory {
+    run_id: String,
+    started_at: std::time::SystemTime,
+    success: bool,
+    steps: Vec<StepHistory>,
+}
+
+/// What happened to a step in a run in the history.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct StepHistory {
+    name: String,
+
+    /// `success`, `failure`, `skipped`, `vetoed`, or `error`.
+    result: String,
+    took: Duration,
+}
+
+/// Load the history of a workflow, oldest run first. A missing file means
+/// no history.
+fn load_history(path: &std::path::Path) -> Result<Vec<RunHistory>> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(Error::msg(format!(
+                "Failed to read {}: {e}",
+                path.display()
+            )));
+        }
+    };
+    data.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| Error::msg(format!("{}:{}: {e}", path.display(), i + 1)))
+        })
+        .collect()
+}
+
+/// Add a run to the history, keeping the last `HISTORY_RUNS`.
+fn save_history(path: &std::path::Path, run: RunHistory) -> Result<()> {
+    let mut runs = load_history(path).unwrap_or_else(|e| {
+        warn!("Starting a new run history: {e}");
+        Vec::new()
+    });
+    runs.push(run);
+    let skip = runs.len().saturating_sub(HISTORY_RUNS);
+    let mut data = String::new();
+    for run in &runs[skip..] {
+        data += &serde_json::to_string(run)?;
+        data += "\n";
+    }
+    std::fs::write(path, data)?;
+    Ok(())
+}
+
+/// Find a run in the history by `last`, `last-success`, or its ID or a
+/// unique prefix of it.
+fn find_run<'a>(runs: &'a [RunHistory], spec: &str) -> Result<&'a RunHistory> {
+    let found = match spec {
+        "last" => runs.last(),
+        "last-success" => runs.iter().rev().find(|r| r.success),
+        _ => match runs.iter().find(|r| r.run_id == spec) {
+            Some(run) => Some(run),
+            None => {
+                let matches: Vec<_> = runs.iter().filter(|r| r.run_id.starts_with(spec)).collect();
+                if matches.len() > 1 {
+                    return Err(Error::msg(format!("Run ID {spec} is ambiguous")));
+                }
+                matches.first().copied()
+            }
+        },
+    };
+    found.ok_or_else(|| Error::msg(format!("No run {spec} in the history")))
+}
+
+/// Compare two runs, step by step. Steps whose result changed are marked
+/// with `!`, and steps missing from a run show `-`.
+fn diff_lines(a: &RunHistory, b: &RunHistory) -> Vec<String> {
+    let mut names: Vec<&str> = b.steps.iter().map(|s| s.name.as_str()).collect();
+    for s in &a.steps {
+        if !names.contains(&s.name.as_str()) {
+            names.push(&s.name);
+        }
+    }
+    let width = names.iter().map(|n| n.len()).max().unwrap_or(0);
+    let describe = |run: &RunHistory| {
+        let result = if run.success { "succeeded" } else { "failed" };
+        format!("{} ({}, {result})", run.run_id, format_time(run.started_at))
+    };
+    let result = |s: Option<&StepHistory>| s.map_or("-".to_string(), |s| s.result.clone());
+    let took =
+        |s: Option<&StepHistory>| s.map_or(format!("{:>8}", "-"), |s| format_duration(s.took));
+    let mut lines = vec![
+        format!("a: {}", describe(a)),
+        format!("b: {}", describe(b)),
+        String::new(),
+    ];
+    for name in names {
+        let sa = a.steps.iter().find(|s| s.name == name);
+        let sb = b.steps.iter().find(|s| s.name == name);
+        let delta = match (sa, sb) {
+            (Some(sa), Some(sb)) if sb.took >= sa.took => {
+                format!("+{}", format_duration(sb.took - sa.took).trim())
+            }
+            (Some(sa), Some(sb)) => format!("-{}", format_duration(sa.took - sb.took).trim()),
+            _ => String::new(),
+        };
+        let changed = if result(sa) == result(sb) { ' ' } else { '!' };
+        lines.push(
+            format!(
+                "{changed} {name:<width$}  {:<8} {:<8} {} {} {delta}",
+                result(sa),
+                result(sb),
+                took(sa),
+                took(sb),
+            )
+            .trim_end()
+            .to_string(),
+        );
+    }
+    lines
+}
+
+/// Print how two runs of a workflow differ, from its history.
+fn diff(opt: &Opt, run_a: &str, run_b: &str) -> Result<()> {
+    let wf = load_workflow(opt)?;
+    let runs = load_history(&state_path(opt, &wf.dir, "history")?)?;
+    let (a, b) = (find_run(&runs, run_a)?, find_run(&runs, run_b)?);
+    for line in diff_lines(a, b) {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// How a step has done in the runs in the history, leaving out runs where
+/// it was skipped.
+#[derive(Debug, Default, PartialEq)]
+struct StepStats {
+    passed: usize,
+    failed: usize,
+
+    /// Number of times it passed after failing the run before, or the
+    /// other way around.
+    changes: usize,
+}
+
+impl StepStats {
+    /// Has both passed and failed.
+    fn flaky(&self) -> bool {
+        self.passed > 0 && self.failed > 0
+    }
+}
+
+/// Stats of every step in the history, by name.
+fn step_stats(runs: &[RunHistory]) -> std::collections::BTreeMap<String, StepStats> {
+    let mut stats: std::collections::BTreeMap<String, StepStats> = Default::default();
+    let mut last: std::collections::HashMap<&str, bool> = std::collections::HashMap::new();
+    for step in runs.iter().flat_map(|r| &r.steps) {
+        let passed = match step.result.as_str() {
+            "success" => true,
+            "failure" | "error" => false,
+            _ => continue,
+        };
+        let s = stats.entry(step.name.clone()).or_default();
+        if passed {
+            s.passed += 1;
+        } else {
+            s.failed += 1;
+        }
+        if last.insert(&step.name, passed).is_some_and(|p| p != passed) {
+            s.changes += 1;
+        }
+    }
+    stats
+}
+
+/// Table of step stats, optionally only of flaky steps.
+fn stats_lines(stats: &std::collections::BTreeMap<String, StepStats>, flaky: bool) -> Vec<String> {
+    let stats: Vec<_> = stats.iter().filter(|(_, s)| !flaky || s.flaky()).collect();
+    let width = stats
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let mut lines = vec![format!(
+        "{:<width$}  {:>4}  {:>9}  {:>7}",
+        "step", "runs", "pass rate", "changes"
+    )];
+    for (name, s) in stats {
+        let runs = s.passed + s.failed;
+        let rate = format!("{}%", s.passed * 100 / runs);
+        let mark = if s.flaky() { "  flaky" } else { "" };
+        lines.push(format!(
+            "{name:<width$}  {runs:>4}  {rate:>9}  {:>7}{mark}",
+            s.changes
+        ));
+    }
+    lines
+}
+
+/// Print how often each step passed, from the history of a workflow.
+fn stats(opt: &Opt, flaky: bool) -> Result<()> {
+    let wf = load_workflow(opt)?;
+    let runs = load_history(&state_path(opt, &wf.dir, "history")?)?;
+    if runs.is_empty() {
+        return Err(Error::msg("No runs in the history of the workflow yet"));
+    }
+    let filter = StepFilter::new(opt, &wf.profile);
+    let mut stats = step_stats(&runs);
+    stats.retain(|name, _| filter.selected(name));
+    if flaky && !stats.values().any(StepStats::flaky) {
+        println!("No flaky steps in the last {} runs", runs.len());
+        return Ok(());
+    }
+    for line in stats_lines(&stats, flaky) {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// Average duration of each step, over the runs in the history where it
+/// succeeded.
+fn average_durations(runs: &[RunHistory]) -> std::collections::HashMap<String, Duration> {
+    let mut sums: std::collections::HashMap<&str, (Duration, u32)> =
+        std::collections::HashMap::new();
+    for step in runs.iter().flat_map(|r| &r.steps) {
+        if step.result == "success" {
+            let (sum, n) = sums.entry(&step.name).or_default();
+            *sum += step.took;
+            *n += 1;
+        }
+    }
+    sums.into_iter()
+        .map(|(name, (sum, n))| (name.to_string(), sum / n))
+        .collect()
+}
+
+/// Steps that run one at a time, but could run in parallel.
+#[derive(Debug, PartialEq)]
+struct ParallelSuggestion {
+    /// Names of the steps, with how long they take.
+    steps: Vec<(String, Duration)>,
+
+    /// Step numbers, for `--parallel`.
+    range: (usize, usize),
+
+    /// For `parallel_regex`.
+    regex: String,
+
+    /// How much sooner the workflow would finish.
+    saving: Duration,
+}
+
+/// Find runs of steps that aren't in parallel groups, and would finish
+/// sooner together, going by `durations`. Steps that need the same resource
+/// (or lock) are kept apart, since they'd have to wait for each other anyway.
+fn suggest_parallel(
+    groups: &[Vec<&Task>],
+    durations: &std::collections::HashMap<String, Duration>,
+    conf: &Config,
+    max_concurrency: usize,
+) -> Vec<ParallelSuggestion> {
+    let mut suggestions = Vec::new();
+    let mut run: Vec<&Task> = Vec::new();
+    let mut used: Vec<String> = Vec::new();
+    let mut finish = |run: &mut Vec<&Task>, used: &mut Vec<String>| {
+        let steps: Vec<(String, Duration)> = run
+            .iter()
+            .map(|t| {
+                (
+                    t.name.clone(),
+                    durations.get(&t.name).copied().unwrap_or_default(),
+                )
+            })
+            .collect();
+        let total: Duration = steps.iter().map(|(_, d)| *d).sum();
+        let longest = steps.iter().map(|(_, d)| *d).max().unwrap_or_default();
+        let parallel = longest.max(total / max_concurrency.max(1) as u32);
+        if steps.len() > 1 && total > parallel {
+            let names: Vec<String> = steps.iter().map(|(n, _)| regex::escape(n)).collect();
+            suggestions.push(ParallelSuggestion {
+                range: (run[0].id, run[run.len() - 1].id),
+                regex: format!("^({})$", names.join("|")),
+                saving: total - parallel,
+                steps,
+            });
+        }
+        run.clear();
+        used.clear();
+    };
+    for group in groups {
+        let [task] = group[..] else {
+            finish(&mut run, &mut used);
+            continue;
+        };
+        let needs: Vec<String> = step_needs(conf, &task.name).into_keys().collect();
+        if needs.iter().any(|r| used.contains(r)) {
+            finish(&mut run, &mut used);
+        }
+        run.push(task);
+        used.extend(needs);
+    }
+    finish(&mut run, &mut used);
+    suggestions.sort_by_key(|s| std::cmp::Reverse(s.saving));
+    suggestions
+}
+
+/// Print suggestions for steps to run in parallel, from the history of a
+/// workflow.
+fn analyze(opt: &Opt) -> Result<()> {
+    let wf = load_workflow(opt)?;
+    let runs = load_history(&state_path(opt, &wf.dir, "history")?)?;
+    if runs.is_empty() {
+        return Err(Error::msg("No runs in the history of the workflow yet"));
+    }
+    let max_concurrency = opt
+        .max_concurrency
+        .unwrap_or(wf.conf.max_concurrency.unwrap_or(DEFAULT_MAX_CONCURRENCY));
+    let durations = average_durations(&runs);
+    let groups = parallel_groups(&wf.steps, &opt.parallel, &wf.conf.parallel_regex);
+    let suggestions = suggest_parallel(&groups, &durations, &wf.conf, max_concurrency);
+    let total = estimate_remaining(
+        &wf.steps,
+        &group_numbers(&wf.steps, opt, &wf.conf),
+        &durations,
+        max_concurrency,
+    )
+    .unwrap_or_default();
+    println!(
+        "The workflow takes about {}, going by the last {} runs.",
+        format_eta(total),
+        runs.len()
+    );
+    if suggestions.is_empty() {
+        if max_concurrency == 1 {
+            println!("With a max concurrency of 1, running steps in parallel wouldn't help.");
+        } else {
+            println!("No steps would finish sooner in parallel.");
+        }
+        return Ok(());
+    }
+    let saving: Duration = suggestions.iter().map(|s| s.saving).sum();
+    println!(
+        "Running these steps in parallel would save about {}, if they don't depend on each other:",
+        format_eta(saving)
+    );
+    for s in &suggestions {
+        println!();
+        for (name, took) in &s.steps {
+            println!("  {name} ({})", format_eta(*took));
+        }
+        println!("  saves about {}, with:", format_eta(s.saving));
+        println!("    --parallel {}-{}", s.range.0, s.range.1);
+        println!(
+            "    \"parallel_regex\": [{}]",
+            serde_json::Value::from(s.regex.clone())
+        );
+    }
+    Ok(())
+}
+
+/// Take the tasks and turn them into something nicely formatted.
+///
+/// Also returns the line of the first running step, if any.
+fn make_status_update(
+    steps: &[Task],
+    compact: bool,
+    collapse_groups: bool,
+    estimates: &std::collections::HashMap<String, Duration>,
+    budget: &std::collections::HashMap<String, Duration>,
+    theme: &Theme,
+) -> (Vec<Line<'static>>, Option<usize>) {
+    let maxlen = steps
+        .iter()
+        .map(|s| s.display_name().len())
+        .chain(
+            steps
+                .iter()
+                .filter_map(|s| s.group.as_ref().map(|g| g.len() + 1)),
+        )
+        .max()
+        .unwrap_or(0);
+    let mut lines = Vec::new();
+    let mut focus = None;
+    let mut n = 0;
+    while n < steps.len() {
+        let group = &steps[n].group;
+        if group.is_some() && (n == 0 || steps[n - 1].group != *group) {
+            let members: Vec<&Task> = steps[n..]
+                .iter()
+                .take_while(|t| t.group == *group)
+                .collect();
+            let (state, total) = group_state(&members);
+            let style = theme.style(&state);
+            lines.push(Line::from(vec![Span::styled(
+                format!(
+                    "{} {:<maxlen$} {}",
+                    style.glyph,
+                    format!("{}/", group.as_deref().unwrap_or_default()),
+                    format_duration(total)
+                ),
+                Style::default()
+                    .fg(style.color)
+                    .add_modifier(Modifier::BOLD),
+            )]));
+            if collapse_groups
+                && !matches!(
+                    state,
+                    State::Running(_) | State::Failed(_) | State::Aborted(_)
+                )
+            {
+                n += members.len();
+                continue;
+            }
+        }
+        if compact {
+            let done = steps[n..]
+                .iter()
+                .take_while(|s| {
+                    s.group == *group && matches!(s.state, State::Complete(_) | State::Skipped)
+                })
+                .count();
+            if done >= COMPACT_MIN_RUN {
+                let total: Duration = steps[n..n + done]
+                    .iter()
+                    .filter_map(|s| match s.state {
+                        State::Complete(d) => Some(d),
+                        _ => None,
+                    })
+                    .sum();
+                let indent = if group.is_some() { "  " } else { "" };
+                lines.push(Line::from(vec![Span::styled(
+                    format!(
+                        "{} {:<maxlen$} {}",
+                        theme.complete.glyph,
+                        format!("{indent}({done} steps done)"),
+                        format_duration(total)
+                    ),
+                    Style::default().fg(theme.complete.color),
+                )]));
+                n += done;
+                continue;
+            }
+        }
+        let s = &steps[n];
+        let extra = state_suffix(
+            s,
+            estimates.get(&s.name).copied(),
+            budget.get(&s.name).copied(),
+        );
+        let style = theme.style(&s.state);
+        if focus.is_none() && matches!(s.state, State::Running(_)) {
+            focus = Some(lines.len());
+        }
+        lines.push(Line::from(vec![Span::styled(
+            format!("{} {:<maxlen$} {extra}", style.glyph, s.display_name()),
+            Style::default().fg(step_color(s, theme)),
+        )]));
+        let indent = " ".repeat(style.glyph.chars().count() + 1);
+        let indent = match group {
+            Some(_) => format!("{indent}    "),
+            None => format!("{indent}  "),
+        };
+        lines.extend(checklist_lines(s, &indent, theme));
+        n += 1;
+    }
+    (lines, focus)
+}
+
+/// Lines with the sub-items of a step, to go under it.
+fn checklist_lines(s: &Task, indent: &str, theme: &Theme) -> Vec<Line<'static>> {
+    s.checklist
+        .iter()
+        .map(|(item, done)| {
+            let style = match (done, &s.state) {
+                (true, _) => &theme.complete,
+                (false, State::Running(_)) => &theme.running,
+                (false, State::Failed(_)) => &theme.failed,
+                (false, State::Aborted(_)) => &theme.aborted,
+                (false, _) => &theme.pending,
+            };
+            Line::styled(
+                format!("{indent}{} {item}", style.glyph),
+                Style::default().fg(style.color),
+            )
+        })
+        .collect()
+}
+
+/// Run time of a step, how much is left of its `estimate`, and XFAIL/XPASS
+/// markers, for after its name.
+fn state_suffix(s: &Task, estimate: Option<Duration>, budget: Option<Duration>) -> String {
+    let extra = match (&s.state, estimate) {
+        (State::Running(st), Some(e)) => {
+            let elapsed = since(*st);
+            let left = format_eta(e.saturating_sub(elapsed));
+            format!("{} ({left} left)", format_duration(elapsed))
+        }
+        (State::Running(st), None) => format_duration(since(*st)),
+        (State::Complete(e) | State::Failed(e) | State::Aborted(e), _) => format_duration(*e),
+        (State::Pending, Some(e)) => format!("~{}", format_eta(e)),
+        (State::Pending | State::Skipped, _) => "".to_owned(),
+    };
+    let extra = match budget_note(s, budget) {
+        Some(note) => format!("{extra} {note}"),
+        None => extra,
+    };
+    let extra = match (&s.state, s.progress) {
+        (State::Running(_), Some(p)) => format!("{extra} {}", progress_bar(p)),
+        _ => extra,
+    };
+    let extra = match s.warnings {
+        0 => extra,
+        n => format!("{extra} ⚠{n}"),
+    };
+    match (s.expected_failure, &s.state) {
+        (true, State::Complete(_)) => format!("{extra} XFAIL"),
+        (true, State::Failed(_)) => format!("{extra} XPASS"),
+        (_, State::Running(_)) if s.stalled => format!("{extra} STALLED"),
+        _ => extra,
+    }
+}
+
+/// Width of progress bars, in characters.
+const PROGRESS_WIDTH: usize = 10;
+
+/// Progress bar for a percentage, like `████░░░░░░  42%`.
+fn progress_bar(percent: u8) -> String {
+    let done = usize::from(percent.min(100)) * PROGRESS_WIDTH / 100;
+    format!(
+        "{}{} {percent:>3}%",
+        "█".repeat(done),
+        "░".repeat(PROGRESS_WIDTH - done)
+    )
+}
+
+/// How far over or under its time budget a step is. Running steps are
+/// only reported once they're over.
+fn budget_note(s: &Task, budget: Option<Duration>) -> Option<String> {
+    let budget = budget?;
+    let (took, done) = match s.state {
+        State::Running(st) => (since(st), false),
+        State::Complete(d) | State::Failed(d) => (d, true),
+        _ => return None,
+    };
+    if took > budget {
+        Some(format!(
+            "+{} over budget",
+            format_duration(took - budget).trim()
+        ))
+    } else if done {
+        Some(format!(
+            "{} under budget",
+            format_duration(budget - took).trim()
+        ))
+    } else {
+        None
+    }
+}
+
+/// Color of a step in the status pane, yellow if it's stalled.
+fn step_color(s: &Task, theme: &Theme) -> Color {
+    if s.stalled && matches!(s.state, State::Running(_)) {
+        Color::Yellow
+    } else {
+        theme.style(&s.state).color
+    }
+}
+
+/// Status pane contents, as a list or a graph depending on the UI state.
+fn make_status(
+    steps: &[Task],
+    state: &UiState,
+    cfg: &TuiConfig,
+) -> (Vec<Line<'static>>, Option<usize>) {
+    let done = |t: &Task| matches!(t.state, State::Complete(_) | State::Skipped);
+    let shown = |t: &Task| {
+        !(state.hide_done && done(t)) && state.filter.as_ref().is_none_or(|f| filter_matches(f, t))
+    };
+    let (mut lines, focus) = if state.graph {
+        let groups: Vec<Vec<usize>> = cfg
+            .parallel_groups
+            .iter()
+            .map(|g| {
+                g.iter()
+                    .copied()
+                    .filter(|&n| steps.get(n).is_some_and(shown))
+                    .collect()
+            })
+            .collect();
+        make_graph_update(steps, &groups, &cfg.estimates, &cfg.budget, &cfg.theme)
+    } else {
+        let steps: Vec<Task> = steps.iter().filter(|t| shown(t)).cloned().collect();
+        make_status_update(
+            &steps,
+            state.compact,
+            state.collapse_groups,
+            &cfg.estimates,
+            &cfg.budget,
+            &cfg.theme,
+        )
+    };
+    let hidden = steps.iter().filter(|t| done(t)).count();
+    if !state.hide_done || hidden == 0 {
+        return (lines, focus);
+    }
+    lines.insert(
+        0,
+        Line::styled(
+            format!("{} {hidden} completed", cfg.theme.complete.glyph),
+            Style::default().fg(cfg.theme.complete.color),
+        ),
+    );
+    (lines, focus.map(|f| f + 1))
+}
+
+/// Like `make_status_update`, but with steps that may run in parallel drawn
+/// as branches under a common header.
+fn make_graph_update(
+    steps: &[Task],
+    parallel_groups: &[Vec<usize>],
+    estimates: &std::collections::HashMap<String, Duration>,
+    budget: &std::collections::HashMap<String, Duration>,
+    theme: &Theme,
+) -> (Vec<Line<'static>>, Option<usize>) {
+    const BRANCH: &str = "├─ ";
+    const LAST: &str = "└─ ";
+    let maxlen = steps.iter().map(|s| s.name.len() + 3).max().unwrap_or(0);
+    let mut lines = Vec::new();
+    let mut focus = None;
+    for group in parallel_groups {
+        let members: Vec<&Task> = group.iter().filter_map(|&n| steps.get(n)).collect();
+        if members.len() > 1 {
+            let (state, total) = group_state(&members);
+            let style = theme.style(&state);
+            lines.push(Line::from(vec![Span::styled(
+                format!(
+                    "{} {:<maxlen$} {}",
+                    style.glyph,
+                    format!("{} in parallel", members.len()),
+                    format_duration(total)
+                ),
+                Style::default()
+                    .fg(style.color)
+                    .add_modifier(Modifier::BOLD),
+            )]));
+        }
+        for (i, s) in members.iter().enumerate() {
+            let branch = if members.len() == 1 {
+                ""
+            } else if i + 1 == members.len() {
+                LAST
+            } else {
+                BRANCH
+            };
+            let width = maxlen - branch.chars().count();
+            let style = theme.style(&s.state);
+            if focus.is_none() && matches!(s.state, State::Running(_)) {
+                focus = Some(lines.len());
+            }
+            lines.push(Line::from(vec![Span::styled(
+                format!(
+                    "{branch}{} {:<width$} {}",
+                    style.glyph,
+                    s.name,
+                    state_suffix(
+                        s,
+                        estimates.get(&s.name).copied(),
+                        budget.get(&s.name).copied(),
+                    )
+                ),
+                Style::default().fg(step_color(s, theme)),
+            )]));
+            let under = if branch == BRANCH { "│  " } else { "   " };
+            let indent = " ".repeat(style.glyph.chars().count() + 1);
+            let indent = match branch {
+                "" => format!("{indent}  "),
+                _ => format!("{under}{indent}  "),
+            };
+            lines.extend(checklist_lines(s, &indent, theme));
+        }
+    }
+    (lines, focus)
+}
+
+/// Aggregate state of a group of steps, and their total run time.
+fn group_state(members: &[&Task]) -> (State, Duration) {
+    let total = members
+        .iter()
+        .map(|t| match t.state {
+            State::Complete(d) | State::Failed(d) | State::Aborted(d) => d,
+            State::Running(st) => since(st),
+            State::Pending | State::Skipped => Duration::ZERO,
+        })
+        .sum();
+    let any = |f: fn(&State) -> bool| members.iter().any(|t| f(&t.state));
+    let state = if any(|s| matches!(s, State::Failed(_))) {
+        State::Failed(total)
+    } else if any(|s| matches!(s, State::Aborted(_))) {
+        State::Aborted(total)
+    } else if any(|s| matches!(s, State::Running(_))) {
+        State::Running(clock())
+    } else if any(|s| matches!(s, State::Pending)) {
+        State::Pending
+    } else if any(|s| matches!(s, State::Complete(_))) {
+        State::Complete(total)
+    } else {
+        State::Skipped
+    };
+    (state, total)
+}
+
+#[derive(Default, serde::Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Where editors find the schema to check the config with. Not used by
+    /// tickbox itself.
+    #[serde(rename = "$schema", default)]
+    _schema: Option<String>,
+
+    #[serde(deserialize_with = "deserialize_envs", default)]
+    envs: Vec<(OsString, OsString)>,
+    #[serde(deserialize_with = "deserialize_regexes", default)]
+    parallel_regex: Vec<regex::Regex>,
+
+    /// When `--daemon` runs the workflow.
+    #[serde(default, deserialize_with = "deserialize_schedule")]
+    schedule: Option<Schedule>,
+    max_concurrency: Option<usize>,
+
+    /// Either the name of a built in theme, or a custom one.
+    theme: Option<ThemeConfig>,
+
+    /// Output lines matching this are counted as errors.
+    #[serde(deserialize_with = "deserialize_regex", default)]
+    error_regex: Option<regex::Regex>,
+
+    /// Styles for output lines, replacing the default ones.
+    highlight: Option<Vec<Highlight>>,
+
+    /// Script to run before every step.
+    before_each: Option<std::path::PathBuf>,
+
+    /// Script to run after every step, whether it succeeded or not.
+    after_each: Option<std::path::PathBuf>,
+
+    /// Step names with their priority. Within a parallel group, higher
+    /// priority steps are started first. The default is 0.
+    #[serde(default)]
+    priority: std::collections::HashMap<String, i64>,
+
+    /// Step names with the memory they need, in MiB. Parallel steps are held
+    /// back while their total would exceed the available memory.
+    #[serde(default)]
+    memory: std::collections::HashMap<String, u64>,
+
+    /// Step names with the user to run them as. Needs tickbox to run as root.
+    #[serde(default)]
+    user: std::collections::HashMap<String, String>,
+
+    /// Step names with the group to run them as. Needs tickbox to run as
+    /// root.
+    #[serde(default)]
+    group: std::collections::HashMap<String, String>,
+
+    /// Versions of tools to run steps with, by tool name.
+    #[serde(default)]
+    tools: std::collections::BTreeMap<String, String>,
+
+    /// Step names with versions of tools to run them with, overriding
+    /// `tools`.
+    #[serde(default)]
+    step_tools: std::collections::HashMap<String, std::collections::BTreeMap<String, String>>,
+
+    /// Tool version manager to pin the versions of `tools` with.
+    #[serde(default)]
+    tool_resolver: ToolResolver,
+
+    /// Step names with a Nix flake dev shell to run them in, e.g.
+    /// `.#devShell`.
+    #[serde(default)]
+    flake: std::collections::HashMap<String, String>,
+
+    /// Step names with a Nix file to run them in a shell of, e.g.
+    /// `./shell.nix`.
+    #[serde(default)]
+    nix_shell: std::collections::HashMap<String, std::path::PathBuf>,
+
+    /// Step names with what they may touch, for steps to run sandboxed.
+    #[serde(default)]
+    sandbox: std::collections::HashMap<String, Sandbox>,
+
+    /// Step names with the name of a lock they hold while running. Steps
+    /// holding the same lock never run at the same time.
+    #[serde(default)]
+    lock: std::collections::HashMap<String, String>,
+
+    /// Resource names, with how many of each there are.
+    #[serde(default)]
+    resources: std::collections::HashMap<String, u64>,
+
+    /// Step names, with how many of each resource they use while running.
+    #[serde(default)]
+    needs: std::collections::HashMap<String, std::collections::HashMap<String, u64>>,
+
+    /// Steps that are shell commands, instead of files.
+    #[serde(default)]
+    steps: Vec<InlineStep>,
+
+    /// Step names with the arguments to run them with.
+    #[serde(default)]
+    args: std::collections::HashMap<String, Vec<String>>,
+
+    /// Steps matching these are run as bash scripts, instead of executed.
+    #[serde(deserialize_with = "deserialize_regexes", default)]
+    shell: Vec<regex::Regex>,
+
+    /// Step names with what their exit codes mean. Unlisted codes are
+    /// success if 0, otherwise failure.
+    #[serde(default)]
+    exit_codes: std::collections::HashMap<String, std::collections::HashMap<i32, Outcome>>,
+
+    /// Cap on the output shown in the UI for each step.
+    #[serde(default)]
+    output_limit: OutputLimit,
+
+    /// Step names with their own output cap, instead of `output_limit`.
+    #[serde(default)]
+    output_limits: std::collections::HashMap<String, OutputLimit>,
+
+    /// Split output lines longer than this many bytes.
+    max_line_length: Option<usize>,
+
+    /// Send stderr of steps to the same pipe as stdout, keeping the order.
+    #[serde(default)]
+    merge_output: bool,
+
+    /// What to keep of the temporary directory when done.
+    #[serde(default)]
+    temp_cleanup: TempCleanup,
+
+    /// Step names with the niceness to run them at.
+    #[serde(default)]
+    nice: std::collections::HashMap<String, i32>,
+
+    /// Step names with the IO priority to run them at, e.g. `idle`.
+    #[serde(deserialize_with = "deserialize_ioprios", default)]
+    ionice: std::collections::HashMap<String, i32>,
+
+    /// Steps matching these are supposed to fail.
+    #[serde(deserialize_with = "deserialize_regexes", default)]
+    expected_failure: Vec<regex::Regex>,
+
+    /// Parallel steps matching these let the rest of their group finish and
+    /// start, even after failing.
+    #[serde(deserialize_with = "deserialize_regexes", default)]
+    keep_going: Vec<regex::Regex>,
+
+    /// Step names with how long they may go without output before they're
+    /// reported as stalled.
+    #[serde(deserialize_with = "deserialize_durations", default)]
+    stall_timeout: std::collections::HashMap<String, Duration>,
+
+    /// Step names with how long they're expected to take.
+    #[serde(deserialize_with = "deserialize_durations", default)]
+    budget: std::collections::HashMap<String, Duration>,
+
+    /// How long the whole workflow is expected to take.
+    #[serde(deserialize_with = "deserialize_opt_duration", default)]
+    sla: Option<Duration>,
+
+    /// Steps matching these are killed when they stall.
+    #[serde(deserialize_with = "deserialize_regexes", default)]
+    kill_stalled: Vec<regex::Regex>,
+
+    /// Steps matching these need the network, so are skipped with
+    /// `--offline`.
+    #[serde(deserialize_with = "deserialize_regexes", default)]
+    needs_network: Vec<regex::Regex>,
+
+    /// Steps matching these run sudo, so ask for the password before
+    /// starting.
+    #[serde(deserialize_with = "deserialize_regexes", default)]
+    needs_sudo: Vec<regex::Regex>,
+
+    /// Variables, with their values, to run a step once for each
+    /// combination of.
+    #[serde(default)]
+    matrix: std::collections::HashMap<String, std::collections::BTreeMap<String, Vec<String>>>,
+
+    /// Where to send word of failed runs.
+    #[serde(default)]
+    notifications: Notifications,
+
+    /// How the TUI starts out.
+    #[serde(default)]
+    ui: UiDefaults,
+
+    /// Keys to bind TUI actions to, replacing their defaults.
+    #[serde(default)]
+    keys: std::collections::HashMap<Action, Vec<String>>,
+
+    /// Step names with Rhai conditions. The step is skipped unless its
+    /// condition is true.
+    #[serde(default)]
+    when: std::collections::HashMap<String, String>,
+
+    /// Environment variables set to the values of Rhai scripts.
+    #[serde(default)]
+    computed_envs: std::collections::BTreeMap<String, String>,
+
+    /// Names of the steps in the order to run them, for steps without
+    /// numbers.
+    #[serde(default)]
+    order: Vec<String>,
+
+    /// Maximum number of output lines waiting for the UI, before the oldest
+    /// are dropped.
+    #[serde(default)]
+    output_buffer: Option<usize>,
+
+    /// Named sets of settings for different kinds of runs, picked with
+    /// `--profile`.
+    #[serde(default)]
+    profiles: std::collections::HashMap<String, Profile>,
+
+    /// Checksums to check steps against before running them, with
+    /// `--verify`.
+    #[serde(skip)]
+    manifest: Option<Manifest>,
+}
+
+impl Config {
+    /// Apply the settings of a profile, returning it for its step filters.
+    fn apply_profile(&mut self, name: &str) -> Result<Profile> {
+        let Some(profile) = self.profiles.get(name).cloned() else {
+            let mut names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            names.sort();
+            if names.is_empty() {
+                return Err(Error::msg(format!(
+                    "No profile {name}, the config has none"
+                )));
+            }
+            return Err(Error::msg(format!(
+                "No profile {name} in the config, only {}",
+                names.join(", ")
+            )));
+        };
+        self.envs.extend(profile.envs.iter().cloned());
+        if let Some(re) = &profile.parallel_regex {
+            self.parallel_regex = re.clone();
+        }
+        if profile.max_concurrency.is_some() {
+            self.max_concurrency = profile.max_concurrency;
+        }
+        Ok(profile)
+    }
+}
+
+/// Settings for a kind of run, like `staging` or `prod`, replacing those of
+/// the config.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    /// Environment variables, added to `envs`.
+    #[serde(deserialize_with = "deserialize_envs", default)]
+    envs: Vec<(OsString, OsString)>,
+
+    #[serde(deserialize_with = "deserialize_opt_regexes", default)]
+    parallel_regex: Option<Vec<regex::Regex>>,
+    max_concurrency: Option<usize>,
+
+    /// Only run steps matching these globs, like `--only`.
+    #[serde(deserialize_with = "deserialize_globs", default)]
+    only: Vec<regex::Regex>,
+
+    /// Don't run steps matching these globs.
+    #[serde(deserialize_with = "deserialize_globs", default)]
+    skip: Vec<regex::Regex>,
+}
+
+/// Name of the file with checksums of a workflow's files.
+const MANIFEST_FILE: &str = "tickbox.sha256";
+
+/// SHA-256 checksums of a workflow's files, for `--verify`.
+#[derive(Clone, Debug, Default)]
+struct Manifest {
+    /// Checksums by path.
+    sums: std::collections::HashMap<std::path::PathBuf, String>,
+}
+
+impl Manifest {
+    /// Parse `sha256sum` output, with paths relative to `dir`.
+    fn parse(text: &str, dir: &std::path::Path) -> Result<Self> {
+        let mut sums = std::collections::HashMap::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let (sum, name) = line
+                .split_once(' ')
+                .ok_or(Error::msg(format!("Bad line in {MANIFEST_FILE}: {line}")))?;
+            if sum.len() != 64 || !sum.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::msg(format!(
+                    "Bad checksum in {MANIFEST_FILE}: {sum}"
+                )));
+            }
+            // A `*` marks binary mode, which is the same thing on Unix.
+            let name = name.strip_prefix([' ', '*']).unwrap_or(name);
+            sums.insert(dir.join(name), sum.to_ascii_lowercase());
+        }
+        Ok(Manifest { sums })
+    }
+
+    /// Load the manifest of a workflow directory, checking its minisign
+    /// signature first, if given a public key.
+    fn load(dir: &std::path::Path, key: Option<&std::path::Path>) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        if let Some(key) = key {
+            let out = std::process::Command::new("minisign")
+                .arg("-Vq")
+                .arg("-p")
+                .arg(key)
+                .arg("-m")
+                .arg(&path)
+                .output()
+                .map_err(|e| Error::msg(format!("Failed to run minisign: {e}")))?;
+            if !out.status.success() {
+                return Err(Error::msg(format!(
+                    "Bad signature on {}: {}",
+                    path.display(),
+                    String::from_utf8_lossy(&out.stderr).trim()
+                )));
+            }
+        }
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| Error::msg(format!("Failed to read {}: {e}", path.display())))?;
+        Self::parse(&text, dir)
+    }
+
+    /// Check that a file's checksum is in the manifest, and matches.
+    fn verify(&self, path: &std::path::Path) -> Result<()> {
+        let want = self.sums.get(path).ok_or(Error::msg(format!(
+            "{} isn't in {MANIFEST_FILE}",
+            path.display()
+        )))?;
+        if sha256sum(path)? != *want {
+            return Err(Error::msg(format!(
+                "Checksum of {} doesn't match {MANIFEST_FILE}",
+                path.display()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// SHA-256 checksum of a file, in hex, using `sha256sum`.
+fn sha256sum(path: &std::path::Path) -> Result<String> {
+    let out = std::process::Command::new("sha256sum")
+        .arg("--")
+        .arg(path)
+        .output()
+        .map_err(|e| Error::msg(format!("Failed to run sha256sum: {e}")))?;
+    if !out.status.success() {
+        return Err(Error::msg(format!(
+            "sha256sum {} failed: {}",
+            path.display(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    String::from_utf8_lossy(&out.stdout)
+        .split_whitespace()
+        .next()
+        .map(str::to_ascii_lowercase)
+        .ok_or(Error::msg("sha256sum printed nothing"))
+}
+
+/// Toggles of the TUI to turn on from the start.
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct UiDefaults {
+    compact: bool,
+    collapse_groups: bool,
+    graph: bool,
+    hide_done: bool,
+
+    /// Swap the direction of the line scrolling keys.
+    natural_scroll: bool,
+
+    /// Leave the terminal title alone.
+    no_title: bool,
+}
+
+/// Where to send word of failed runs.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Notifications {
+    email: Option<EmailNotification>,
+    slack: Option<SlackNotification>,
+    matrix: Option<MatrixNotification>,
+}
+
+/// Read a secret, like a token, from an environment variable.
+fn secret_env(var: &str) -> Result<String> {
+    std::env::var(var).map_err(|e| Error::msg(format!("Failed to get {var}: {e}")))
+}
+
+/// Send a JSON body with `curl`, failing on HTTP errors.
+fn curl_json(method: &str, url: &str, headers: &[String], body: &str) -> Result<()> {
+    use std::io::Write;
+    let mut file = tempfile::NamedTempFile::new()?;
+    file.write_all(body.as_bytes())?;
+    let (mut command, config) = curl_json_command(method, url, headers, file.path());
+    run_curl(command.stdout(std::process::Stdio::null()), &config)
+}
+
+/// `curl` command to send the JSON in `body`, and the config to give it on
+/// stdin. URLs and headers can hold secrets, so they go in the config
+/// instead of on the command line, where other users can see them.
+fn curl_json_command(
+    method: &str,
+    url: &str,
+    headers: &[String],
+    body: &std::path::Path,
+) -> (std::process::Command, String) {
+    let mut data = OsString::from("@");
+    data.push(body);
+    let mut command = std::process::Command::new("curl");
+    command.args(["--silent", "--show-error", "--fail", "--request", method]);
+    command.args(["--max-time", &HTTP_TIMEOUT.as_secs().to_string()]);
+    command.args(["--config", "-", "--data-binary"]).arg(data);
+    let mut config = format!("url = {}\n", curl_quote(url));
+    for header in
+        std::iter::once("Content-Type: application/json").chain(headers.iter().map(String::as_str))
+    {
+        config += &format!("header = {}\n", curl_quote(header));
+    }
+    (command, config)
+}
+
+/// Quote a value for a `curl` config file.
+fn curl_quote(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Run `curl` with `config` on stdin, failing if it does.
+fn run_curl(command: &mut std::process::Command, config: &str) -> Result<()> {
+    use std::io::Write;
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::msg(format!("Failed to run curl: {e}")))?;
+    child.stdin.take().unwrap().write_all(config.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::msg(format!("curl failed: {status}")));
+    }
+    Ok(())
+}
+
+/// Table of the steps that ran, with their results and durations.
+fn step_table(records: &[StepRecord]) -> String {
+    let width = records.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    records
+        .iter()
+        .map(|r| {
+            let took = format_duration(r.took);
+            format!("{:<width$}  {:<8} {took}", r.name, r.result)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Post failed runs to a Slack channel, through an incoming webhook.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SlackNotification {
+    /// Environment variable with the webhook URL.
+    webhook_env: String,
+}
+
+impl SlackNotification {
+    fn payload(title: &str, table: &str) -> serde_json::Value {
+        serde_json::json!({
+            "text": title,
+            "blocks": [
+                {"type": "section", "text": {"type": "mrkdwn", "text": format!("*{title}*")}},
+                {"type": "section", "text": {"type": "mrkdwn", "text": format!("```{table}```")}},
+            ],
+        })
+    }
+
+    fn send(&self, title: &str, table: &str) -> Result<()> {
+        let url = secret_env(&self.webhook_env)?;
+        curl_json("POST", &url, &[], &Self::payload(title, table).to_string())
+    }
+}
+
+/// Post failed runs to a Matrix room.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MatrixNotification {
+    /// E.g. `https://matrix.example.com`.
+    homeserver: String,
+
+    /// Room ID, like `!abcdef:example.com`.
+    room: String,
+
+    /// Environment variable with the access token.
+    access_token_env: String,
+}
+
+impl MatrixNotification {
+    fn payload(title: &str, table: &str) -> serde_json::Value {
+        let escape = |s: &str| {
+            s.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+        };
+        serde_json::json!({
+            "msgtype": "m.text",
+            "body": format!("{title}\n\n{table}"),
+            "format": "org.matrix.custom.html",
+            "formatted_body": format!(
+                "<strong>{}</strong><pre><code>{}</code></pre>",
+                escape(title),
+                escape(table)
+            ),
+        })
+    }
+
+    fn send(&self, title: &str, table: &str) -> Result<()> {
+        let token = secret_env(&self.access_token_env)?;
+        let url = format!(
+            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/tickbox-{}",
+            self.homeserver.trim_end_matches('/'),
+            percent_encode(&self.room),
+            random_hex(8)
+        );
+        let auth = format!("Authorization: Bearer {token}");
+        curl_json(
+            "PUT",
+            &url,
+            &[auth],
+            &Self::payload(title, table).to_string(),
+        )
+    }
+}
+
+/// Percent-encode all but unreserved URL characters.
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Email the summary of failed runs.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmailNotification {
+    /// SMTP server, with an optional port, e.g. `smtp.example.com:587`.
+    host: String,
+
+    /// `starttls` (the default), `tls`, or `none`.
+    #[serde(default)]
+    tls: SmtpTls,
+    from: String,
+    to: Vec<String>,
+    username: Option<String>,
+
+    /// Environment variable with the password.
+    password_env: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SmtpTls {
+    #[default]
+    Starttls,
+    Tls,
+    None,
+}
+
+/// What to keep of the temporary directory when tickbox exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum TempCleanup {
+    /// Keep the directories of steps that failed.
+    KeepOnFailure,
+    AlwaysKeep,
+    #[default]
+    AlwaysDelete,
+}
+
+impl EmailNotification {
+    /// Config for `curl` to send an email. It holds the password, so it's
+    /// given on stdin rather than as arguments.
+    fn curl_config(&self) -> Result<String> {
+        let scheme = match self.tls {
+            SmtpTls::Tls => "smtps",
+            SmtpTls::Starttls | SmtpTls::None => "smtp",
+        };
+        let mut config = format!(
+            "url = {}\nmail-from = {}\n",
+            curl_quote(&format!("{scheme}://{}", self.host)),
+            curl_quote(&self.from)
+        );
+        if let SmtpTls::Starttls = self.tls {
+            config += "ssl-reqd\n";
+        }
+        for to in &self.to {
+            config += &format!("mail-rcpt = {}\n", curl_quote(to));
+        }
+        if let Some(user) = &self.username {
+            let password = match &self.password_env {
+                Some(var) => std::env::var(var).map_err(|e| {
+                    Error::msg(format!("Failed to get SMTP password from {var}: {e}"))
+                })?,
+                None => String::new(),
+            };
+            config += &format!("user = {}\n", curl_quote(&format!("{user}:{password}")));
+        }
+        Ok(config)
+    }
+
+    /// The mail to send. The subject names the workflow, so line breaks in
+    /// it are replaced, to not let it add headers.
+    fn mail(&self, subject: &str, body: &str) -> String {
+        let subject = subject.replace(['\r', '\n'], " ");
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: {subject}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+            self.from,
+            self.to.join(", "),
+            body.replace('\n', "\r\n")
+        )
+    }
+
+    fn send(&self, subject: &str, body: &str) -> Result<()> {
+        use std::io::Write;
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(self.mail(subject, body).as_bytes())?;
+        run_curl(
+            std::process::Command::new("curl")
+                .args(["--silent", "--show-error", "--config", "-"])
+                .args(["--max-time", &HTTP_TIMEOUT.as_secs().to_string()])
+                .arg("--upload-file")
+                .arg(file.path()),
+            &self.curl_config()?,
+        )
+    }
+}
+
+/// A step defined in the config.
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InlineStep {
+    /// Name, starting with a number like step files. A `/` puts it in a
+    /// group, e.g. `20-test/10-unit`.
+    name: String,
+
+    /// Shell command to run.
+    run: Option<String>,
+
+    /// Script to run, relative to the config.
+    script: Option<std::path::PathBuf>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(untagged)]
+enum ThemeConfig {
+    Named(String),
+    Custom(Theme),
+}
+
+fn deserialize_regexes<'de, D>(deserializer: D) -> Result<Vec<regex::Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    let strs = Vec::<String>::deserialize(deserializer)?;
+    let mut regexes = Vec::with_capacity(strs.len());
+
+    for s in strs {
+        match regex::Regex::new(&s) {
+            Ok(r) => regexes.push(r),
+            Err(e) => {
+                return Err(serde::de::Error::custom(format!(
+                    "Invalid regex '{s}': {e}"
+                )));
+            }
+        }
+    }
+    Ok(regexes)
+}
+
+fn deserialize_one_regex<'de, D>(deserializer: D) -> Result<regex::Regex, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    let s = String::deserialize(deserializer)?;
+    regex::Regex::new(&s).map_err(|e| serde::de::Error::custom(format!("Invalid regex '{s}': {e}")))
+}
+
+/// Serialize a point in time as how long ago it was, since an `Instant`
+/// can't be serialized as is.
+fn serialize_instant<S>(t: &Instant, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_f64(since(*t).as_secs_f64())
+}
+
+/// Inverse of `serialize_instant`, relative to now.
+fn deserialize_instant<'de, D>(deserializer: D) -> Result<Instant, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    let ago = f64::deserialize(deserializer)?;
+    let now = clock();
+    Ok(Duration::try_from_secs_f64(ago)
+        .ok()
+        .and_then(|ago| now.checked_sub(ago))
+        .unwrap_or(now))
+}
+
+fn deserialize_opt_regexes<'de, D>(deserializer: D) -> Result<Option<Vec<regex::Regex>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_regexes(deserializer).map(Some)
+}
+
+fn deserialize_globs<'de, D>(deserializer: D) -> Result<Vec<regex::Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| parse_glob(s).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<regex::Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_one_regex(deserializer).map(Some)
+}
+
+fn deserialize_ioprios<'de, D>(
+    deserializer: D,
+) -> Result<std::collections::HashMap<String, i32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    let map: HashMap<String, String> = HashMap::deserialize(deserializer)?;
+    map.into_iter()
+        .map(|(step, s)| Ok((step, parse_ioprio(&s).map_err(serde::de::Error::custom)?)))
+        .collect()
+}
+
+fn deserialize_durations<'de, D>(
+    deserializer: D,
+) -> Result<std::collections::HashMap<String, Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    let map: HashMap<String, String> = HashMap::deserialize(deserializer)?;
+    map.into_iter()
+        .map(|(step, s)| Ok((step, parse_duration(&s).map_err(serde::de::Error::custom)?)))
+        .collect()
+}
+
+fn deserialize_opt_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    let s: Option<String> = Option::deserialize(deserializer)?;
+    s.map(|s| parse_duration(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn deserialize_envs<'de, D>(deserializer: D) -> Result<Vec<(OsString, OsString)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    let map: HashMap<String, String> = HashMap::deserialize(deserializer)?;
+    Ok(map
+        .into_iter()
+        .map(|(key, value)| (OsString::from(key), OsString::from(value)))
+        .collect())
+}
+
+/// Load config in JSON format.
+fn load_config(dir: &std::path::Path) -> Result<Config> {
+    load_config_file(&dir.join("tickbox.json"))
+}
+
+/// Load a config file, or the default config if there's no such file.
+fn load_config_file(filename: &std::path::Path) -> Result<Config> {
+    let contents = match std::fs::read_to_string(filename) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Config::default());
+        }
+        Err(e) => {
+            return Err(std::io::Error::new(
+                e.kind(),
+                format!("Error reading {}: {}", filename.display(), e),
+            )
+            .into());
+        }
+    };
+    parse_config(&contents, filename)
+}
+
+/// Parse a config file, with errors saying where in it the problem is.
+fn parse_config(text: &str, path: &std::path::Path) -> Result<Config> {
+    let de = &mut serde_json::Deserializer::from_str(text);
+    serde_path_to_error::deserialize(de).map_err(|e| config_error(path, e))
+}
+
+/// Error about a bad config: the file, line and column if known, the
+/// setting, and what's wrong with it. For a misspelled setting or value,
+/// that's the closest valid one, instead of the list of all of them.
+fn config_error(path: &std::path::Path, e: serde_path_to_error::Error<serde_json::Error>) -> Error {
+    let mut setting = e.path().to_string();
+    let e = e.into_inner();
+    let mut msg = e.to_string();
+    let mut place = path.display().to_string();
+    if e.line() > 0 {
+        let at = format!(" at line {} column {}", e.line(), e.column());
+        msg = msg.strip_suffix(&at).unwrap_or(&msg).to_string();
+        place += &format!(":{}:{}", e.line(), e.column());
+    }
+    for (prefix, what) in [
+        ("unknown field `", "setting"),
+        ("unknown variant `", "value"),
+    ] {
+        let Some((name, expected)) = msg.strip_prefix(prefix).and_then(|m| m.split_once('`'))
+        else {
+            continue;
+        };
+        let closest = expected
+            .split('`')
+            .skip(1)
+            .step_by(2)
+            .min_by_key(|c| edit_distance(name, c))
+            .filter(|c| edit_distance(name, c) <= 3);
+        if what == "setting" {
+            // The path ends with the unknown setting itself.
+            setting = setting
+                .rsplit_once('.')
+                .map_or(".".to_string(), |(parent, _)| parent.to_string());
+        }
+        msg = match closest {
+            Some(c) => format!("unknown {what} `{name}`, did you mean `{c}`?"),
+            None => format!("unknown {what} `{name}`"),
+        };
+        break;
+    }
+    if setting == "." {
+        Error::msg(format!("{place}: {msg}"))
+    } else {
+        Error::msg(format!("{place}: {setting}: {msg}"))
+    }
+}
+
+/// Number of single character edits to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let next = (prev + usize::from(ca != *cb))
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            prev = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+/// JSON Schema of the config, for editors. It's made from the config
+/// reference: every setting gets the first paragraph of its section as
+/// description, and the JSON types of its examples.
+fn config_schema() -> serde_json::Value {
+    let json_type = |v: &serde_json::Value| match v {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Number(n) if n.is_f64() => "number",
+        serde_json::Value::Number(_) => "integer",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    };
+    let mut properties = serde_json::Map::new();
+    properties.insert(
+        "$schema".into(),
+        serde_json::json!({"type": "string", "description": "Schema to check this file with."}),
+    );
+    for section in CONFIG_REFERENCE.split("\n## ").skip(1) {
+        let (heading, body) = section.split_once('\n').unwrap_or((section, ""));
+        let description = body
+            .trim_start()
+            .split("\n\n")
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut types: Vec<(String, &str)> = Vec::new();
+        for example in body.split("```\n").skip(1).step_by(2) {
+            let Ok(serde_json::Value::Object(example)) =
+                serde_json::from_str(&format!("{{{example}}}"))
+            else {
+                continue;
+            };
+            for (name, value) in &example {
+                let t = json_type(value);
+                if !types.contains(&(name.clone(), t)) {
+                    types.push((name.clone(), t));
+                }
+            }
+        }
+        for name in heading.split('`').skip(1).step_by(2) {
+            let mut property = serde_json::json!({"description": description});
+            let t: Vec<&str> = types
+                .iter()
+                .filter(|(n, _)| n == name)
+                .map(|(_, t)| *t)
+                .collect();
+            match t.len() {
+                0 => {}
+                1 => property["type"] = t[0].into(),
+                _ => property["type"] = t.into(),
+            }
+            properties.insert(name.to_string(), property);
+        }
+    }
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "tickbox config",
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    })
+}
+
+/// Exclusive lock on a workflow, held until dropped.
+struct WorkflowLock {
+    _file: std::fs::File,
+}
+
+impl WorkflowLock {
+    /// Try to take the lock, returning `None` if someone else holds it.
+    fn try_lock(path: &std::path::Path) -> Result<Option<WorkflowLock>> {
+        use std::io::Write;
+        use std::os::fd::AsRawFd;
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to open lock file {}: {e}", path.display()),
+                )
+            })?;
+        // SAFETY: The fd is valid for the lifetime of `file`.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(err.into());
+        }
+        // Record who holds the lock, for the benefit of anyone waiting.
+        file.set_len(0)?;
+        writeln!(
+            file,
+            "pid {} user {}",
+            std::process::id(),
+            std::env::var("USER").unwrap_or_else(|_| "unknown".to_string())
+        )?;
+        Ok(Some(WorkflowLock { _file: file }))
+    }
+}
+
+/// Lock the workflow, optionally waiting for another run to finish.
+fn acquire_lock(path: &std::path::Path, wait: Option<Duration>) -> Result<WorkflowLock> {
+    let start = Instant::now();
+    let mut told = false;
+    loop {
+        if let Some(lock) = WorkflowLock::try_lock(path)? {
+            return Ok(lock);
+        }
+        let holder = std::fs::read_to_string(path)
+            .map(|s| s.trim().to_string())
+            .unwrap_or_else(|e| format!("unknown ({e})"));
+        match wait {
+            Some(wait) if start.elapsed() < wait => {
+                if !told {
+                    eprintln!("Waiting for lock {} held by {holder}", path.display());
+                    told = true;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            _ => {
+                return Err(Error::msg(format!(
+                    "Workflow lock {} held by {holder}",
+                    path.display()
+                )));
+            }
+        }
+    }
+}
+
+/// Search upwards from the current directory for a workflow directory.
+fn find_workflow_dir() -> Result<std::path::PathBuf> {
+    let start = std::env::current_dir()?;
+    for dir in start.ancestors() {
+        let candidate = dir.join(WORKFLOW_DIR);
+        if candidate.is_dir() {
+            return Ok(candidate);
+        }
+    }
+    Err(Error::msg(format!(
+        "No --dir given, and no {WORKFLOW_DIR} directory found in {} or its parents",
+        start.display()
+    )))
+}
+
+/// Names of the workflows in a directory.
+fn workflow_names(dir: &std::path::Path) -> Result<Vec<String>> {
+    let mut names = std::fs::read_dir(dir)
+        .map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Failed to read directory {}: {e}", dir.display()),
+            )
+        })?
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+/// Print the names of the workflows in a directory.
+fn list_workflows(dir: Option<std::path::PathBuf>) -> Result<()> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => find_workflow_dir()?,
+    };
+    for name in workflow_names(&dir)? {
+        println!("{name}");
+    }
+    Ok(())
+}
+
+/// Interpreters whose scripts can be inlined as `bash -c` commands.
+const INLINE_SHEBANGS: &[&str] = &[
+    "#!/bin/bash",
+    "#!/usr/bin/env bash",
+    "#!/bin/sh",
+    "#!/usr/bin/env sh",
+];
+
+/// Read a config file as JSON, keeping settings this version doesn't know.
+fn read_config_json(
+    filename: &std::path::Path,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    match std::fs::read_to_string(filename) {
+        Ok(data) => {
+            // Check the settings, to catch mistakes in a file of its own.
+            parse_config(&data, filename)?;
+            serde_json::from_str(&data).map_err(|e| Error::msg(format!("JSON parse: {e}")))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(e) => Err(std::io::Error::new(
+            e.kind(),
+            format!("Error reading {}: {e}", filename.display()),
+        )
+        .into()),
+    }
+}
+
+fn export_workflow(dir: Option<std::path::PathBuf>, out: Option<std::path::PathBuf>) -> Result<()> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => find_workflow_dir()?,
+    };
+    let mut conf = read_config_json(&dir.join("tickbox.json"))?;
+    let mut steps = Vec::new();
+    for task in load_tasks(&dir, &load_config(&dir)?.order)? {
+        let contents = std::fs::read_to_string(&task.cmd).unwrap_or_default();
+        let (first, rest) = contents.split_once('\n').unwrap_or((&contents, ""));
+        let step = if INLINE_SHEBANGS.contains(&first.trim_end()) {
+            serde_json::json!({"name": task.name, "run": rest.trim()})
+        } else {
+            serde_json::json!({"name": task.name, "script": task.cmd.canonicalize()?})
+        };
+        steps.push(step);
+    }
+    conf.insert("steps".to_string(), steps.into());
+    let json = serde_json::to_string_pretty(&conf)? + "\n";
+    match out {
+        Some(out) => std::fs::write(out, json)?,
+        None => print!("{json}"),
+    }
+    Ok(())
+}
+
+fn import_workflow(file: &std::path::Path, dir: &std::path::Path) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut conf = read_config_json(file)?;
+    let steps: Vec<InlineStep> = match conf.remove("steps") {
+        Some(steps) => serde_json::from_value(steps)?,
+        None => return Err(Error::msg(format!("No steps in {}", file.display()))),
+    };
+    let base = file.parent().unwrap_or(std::path::Path::new(""));
+    std::fs::create_dir_all(dir)?;
+    for step in steps {
+        let path = dir.join(&step.name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let contents = match (&step.run, &step.script) {
+            (Some(run), None) => format!("#!/usr/bin/env bash\n{run}\n").into_bytes(),
+            (None, Some(script)) => std::fs::read(base.join(script))?,
+            _ => {
+                return Err(Error::msg(format!(
+                    "step {} needs exactly one of run and script",
+                    step.name
+                )));
+            }
+        };
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o755)
+            .open(&path)
+            .map_err(|e| Error::msg(format!("Failed to create {}: {e}", path.display())))?
+            .write_all(&contents)?;
+    }
+    if !conf.is_empty() {
+        let json = serde_json::to_string_pretty(&conf)? + "\n";
+        std::fs::write(dir.join("tickbox.json"), json)?;
+    }
+    Ok(())
+}
+
+/// Files in a workflow directory that aren't part of the workflow.
+const PACK_EXCLUDE: &[&str] = &[
+    ".git",
+    ".tickbox.times",
+    ".tickbox.history",
+    ".tickbox.lock",
+];
+
+fn pack_workflow(dir: Option<std::path::PathBuf>, out: &std::path::Path) -> Result<()> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => find_workflow_dir()?,
+    };
+    // Check that it's a workflow, rather than packing anything.
+    load_tasks(&dir, &load_config(&dir)?.order)?;
+    let status = std::process::Command::new("tar")
+        .arg("-czf")
+        .arg(out)
+        .args(PACK_EXCLUDE.iter().map(|e| format!("--exclude=./{e}")))
+        .arg("-C")
+        .arg(&dir)
+        .arg(".")
+        .status()
+        .map_err(|e| Error::msg(format!("Failed to run tar: {e}")))?;
+    if !status.success() {
+        return Err(Error::msg(format!("tar failed with {status}")));
+    }
+    Ok(())
+}
+
+/// Check if a URL is of a tarball, rather than a git repository.
+fn is_archive_url(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    [".tar", ".tar.gz", ".tgz", ".tar.bz2", ".tar.xz", ".tar.zst"]
+        .iter()
+        .any(|ext| path.ends_with(ext))
+}
+
+/// Directory name in the cache for a URL.
+fn fetch_cache_name(url: &str) -> String {
+    url.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Run a command for fetching a workflow, failing if it fails.
+fn run_fetch_command(command: &mut std::process::Command) -> Result<()> {
+    let name = command.get_program().to_string_lossy().into_owned();
+    let status = command
+        .status()
+        .map_err(|e| Error::msg(format!("Failed to run {name}: {e}")))?;
+    if !status.success() {
+        return Err(Error::msg(format!("{name} failed with {status}")));
+    }
+    Ok(())
+}
+
+/// Download or update a workflow in the cache, returning its directory.
+fn fetch_workflow(url: &str) -> Result<std::path::PathBuf> {
+    let cache = cache_dir()
+        .ok_or(Error::msg("No cache directory, set HOME or XDG_CACHE_HOME"))?
+        .join("workflows");
+    std::fs::create_dir_all(&cache)?;
+    let dir = cache.join(fetch_cache_name(url));
+    if !is_archive_url(url) {
+        if dir.join(".git").is_dir() {
+            eprintln!("tickbox: updating {url}");
+            return run_fetch_command(
+                std::process::Command::new("git").arg("-C").arg(&dir).args([
+                    "pull",
+                    "--quiet",
+                    "--ff-only",
+                ]),
+            )
+            .map(|()| dir);
+        }
+        eprintln!("tickbox: fetching {url}");
+        run_fetch_command(
+            std::process::Command::new("git")
+                .args(["clone", "--quiet", "--depth=1", "--", url])
+                .arg(&dir),
+        )?;
+        return Ok(dir);
+    }
+    // Unpack next to the old copy, and only replace it if that works.
+    eprintln!("tickbox: fetching {url}");
+    let tmp = tempfile::TempDir::new_in(&cache)?;
+    let archive = tmp.path().join("archive");
+    let unpacked = tmp.path().join("workflow");
+    std::fs::create_dir(&unpacked)?;
+    run_fetch_command(
+        std::process::Command::new("curl")
+            .args([
+                "--silent",
+                "--show-error",
+                "--fail",
+                "--location",
+                "--output",
+            ])
+            .arg(&archive)
+            .arg(url),
+    )?;
+    run_fetch_command(
+        std::process::Command::new("tar")
+            .arg("-xf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&unpacked),
+    )?;
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    std::fs::rename(&unpacked, &dir)?;
+    Ok(dir)
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+/// Format a workflow as a graph. Every step depends on all steps of the
+/// parallel group before it.
+fn make_graph(steps: &[Task], groups: &[Vec<&Task>], format: GraphFormat) -> String {
+    use itertools::Itertools;
+    use std::fmt::Write;
+    let mut out = String::new();
+    let node = |t: &Task| format!("s{}", t.n);
+    let quote = |s: &str| s.replace('"', "'");
+    match format {
+        GraphFormat::Dot => out.push_str("digraph workflow {\n  node [shape=box];\n"),
+        GraphFormat::Mermaid => out.push_str("flowchart TD\n"),
+    }
+    for (group, members) in &steps.iter().chunk_by(|t| t.group.clone()) {
+        let members: Vec<&Task> = members.collect();
+        let indent = if group.is_some() { "    " } else { "  " };
+        match (&group, format) {
+            (Some(g), GraphFormat::Dot) => {
+                let _ = writeln!(
+                    out,
+                    "  subgraph cluster_{} {{\n    label=\"{}\";",
+                    node(members[0]),
+                    quote(g)
+                );
+            }
+            (Some(g), GraphFormat::Mermaid) => {
+                let _ = writeln!(
+                    out,
+                    "  subgraph {}_group [\"{}\"]",
+                    node(members[0]),
+                    quote(g)
+                );
+            }
+            (None, _) => {}
+        }
+        for t in members {
+            let label = quote(t.display_name().trim());
+            let _ = match format {
+                GraphFormat::Dot => writeln!(out, "{indent}{} [label=\"{label}\"];", node(t)),
+                GraphFormat::Mermaid => writeln!(out, "{indent}{}[\"{label}\"]", node(t)),
+            };
+        }
+        if group.is_some() {
+            let _ = match format {
+                GraphFormat::Dot => writeln!(out, "  }}"),
+                GraphFormat::Mermaid => writeln!(out, "  end"),
+            };
+        }
+    }
+    for (from, to) in groups.iter().tuple_windows() {
+        for a in from {
+            for b in to {
+                let _ = match format {
+                    GraphFormat::Dot => writeln!(out, "  {} -> {};", node(a), node(b)),
+                    GraphFormat::Mermaid => writeln!(out, "  {} --> {}", node(a), node(b)),
+                };
+            }
+        }
+    }
+    if let GraphFormat::Dot = format {
+        out.push_str("}\n");
+    }
+    out
+}
+
+fn graph(opt: &Opt, format: GraphFormat) -> Result<()> {
+    let wf = load_workflow(opt)?;
+    let filter = StepFilter::new(opt, &wf.profile);
+    let steps: Vec<Task> = wf
+        .steps
+        .into_iter()
+        .filter(|t| filter.selected(&t.name))
+        .collect();
+    let groups = parallel_groups(&steps, &opt.parallel, &wf.conf.parallel_regex);
+    print!("{}", make_graph(&steps, &groups, format));
+    Ok(())
+}
+
+/// Step numbers of each group of steps that may run in parallel.
+fn group_numbers(steps: &[Task], opt: &Opt, conf: &Config) -> Vec<Vec<usize>> {
+    parallel_groups(steps, &opt.parallel, &conf.parallel_regex)
+        .into_iter()
+        .map(|group| group.into_iter().map(|t| t.n).collect())
+        .collect()
+}
+
+/// Put step `n`, added while running, in the parallel groups. It's in the
+/// same group as the step added just before it, if added by the same step.
+fn add_to_groups(groups: &mut Vec<Vec<usize>>, steps: &[Task], n: usize) {
+    let same = |k: &usize| k + 1 == n && steps[*k].matrix && steps[*k].cmd == steps[n].cmd;
+    match groups.last_mut() {
+        Some(group) if group.last().is_some_and(same) => group.push(n),
+        _ => groups.push(vec![n]),
+    }
+}
+
+/// Add the environment variables of the run to the config, and then the
+/// computed ones.
+fn add_run_envs(conf: &mut Config, steps: usize, run_envs: &[(OsString, OsString)]) -> Result<()> {
+    conf.envs.extend(run_envs.iter().cloned());
+    conf.envs
+        .push(("TICKBOX_STEP_TOTAL".into(), steps.to_string().into()));
+    compute_envs(&conf.computed_envs, &mut conf.envs)
+}
+
+/// Temporary directory of a step, under `TICKBOX_TEMPDIR` in `envs`.
+fn step_temp_dir(envs: &[(OsString, OsString)], name: &str) -> Option<std::path::PathBuf> {
+    let (_, root) = envs.iter().rev().find(|(k, _)| k == "TICKBOX_TEMPDIR")?;
+    Some(std::path::Path::new(root).join(name.replace('/', "_")))
+}
+
+/// Delete the temporary directory, or the parts of it that `policy` says
+/// not to keep. Returns the directory, if anything was kept.
+fn cleanup_temp(
+    tmp_dir: tempfile::TempDir,
+    policy: TempCleanup,
+    records: &[StepRecord],
+) -> Option<std::path::PathBuf> {
+    let failed = |r: &&StepRecord| matches!(r.result, "failure" | "error");
+    match policy {
+        TempCleanup::AlwaysDelete => None,
+        TempCleanup::KeepOnFailure if !records.iter().any(|r| failed(&r)) => None,
+        TempCleanup::KeepOnFailure => {
+            let envs = [(
+                OsString::from("TICKBOX_TEMPDIR"),
+                OsString::from(tmp_dir.path()),
+            )];
+            for r in records.iter().filter(|r| !failed(r)) {
+                if let Some(dir) = step_temp_dir(&envs, &r.name) {
+                    let _ = std::fs::remove_dir_all(dir);
+                }
+            }
+            Some(tmp_dir.into_path())
+        }
+        TempCleanup::AlwaysKeep => Some(tmp_dir.into_path()),
+    }
+}
+
+/// Make sure sudo has cached credentials, asking for the password on the
+/// terminal if needed, so that steps don't get stuck at a sudo prompt.
+async fn sudo_validate() -> Result<()> {
+    // SAFETY: Just a syscall.
+    if unsafe { libc::geteuid() } == 0 {
+        return Ok(());
+    }
+    let cached = tokio::process::Command::new("sudo")
+        .args(["--non-interactive", "--validate"])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await
+        .is_ok_and(|s| s.success());
+    if cached {
+        return Ok(());
+    }
+    use std::io::IsTerminal;
+    if !std::io::stdin().is_terminal() {
+        return Err(Error::msg(
+            "steps need sudo, but there are no cached credentials, and no terminal to ask for a password on",
+        ));
+    }
+    eprintln!("tickbox: some steps need sudo");
+    let status = tokio::process::Command::new("sudo")
+        .arg("--validate")
+        .status()
+        .await
+        .map_err(|e| Error::msg(format!("Failed to run sudo: {e}")))?;
+    if !status.success() {
+        return Err(Error::msg("sudo failed, not starting the workflow"));
+    }
+    Ok(())
+}
+
+/// Load the workflow again, for a restart, as it was set up for the first
+/// run. `opt` must have an absolute `dir` or `file`.
+fn reload_workflow(
+    opt: &Opt,
+    run_envs: &[(OsString, OsString)],
+    priorities: &[(String, i64)],
+) -> Result<(Vec<Task>, Config)> {
+    let wf = load_workflow(opt)?;
+    check_executable(&wf.steps, &wf.conf.shell)?;
+    let mut conf = wf.conf;
+    conf.priority.extend(priorities.iter().cloned());
+    add_run_envs(&mut conf, wf.steps.len(), run_envs)?;
+    Ok((wf.steps, conf))
+}
+
+/// Modification times of a workflow file, or of all the files in a
+/// workflow directory, to notice changes.
+fn workflow_mtimes(
+    path: &std::path::Path,
+) -> Vec<(std::path::PathBuf, Option<std::time::SystemTime>)> {
+    let mut out = vec![(
+        path.to_path_buf(),
+        std::fs::metadata(path).and_then(|m| m.modified()).ok(),
+    )];
+    if let Ok(entries) = std::fs::read_dir(path) {
+        let mut paths: Vec<_> = entries
+            .flatten()
+            .map(|e| e.path())
+            // Like lock and times files.
+            .filter(|p| {
+                !p.file_name()
+                    .is_some_and(|n| n.to_string_lossy().starts_with('.'))
+            })
+            .collect();
+        paths.sort();
+        for p in paths {
+            out.extend(workflow_mtimes(&p));
+        }
+    }
+    out
+}
+
+/// Load a workflow, and print any warnings.
+fn validate(opt: &Opt) -> Result<()> {
+    let wf = load_workflow(opt)?;
+    check_executable(&wf.steps, &wf.conf.shell)?;
+    for w in &wf.warnings {
+        println!("warning: {w}");
+    }
+    let filter = StepFilter::new(opt, &wf.profile);
+    let selected = wf.steps.iter().filter(|t| filter.selected(&t.name)).count();
+    if selected == wf.steps.len() {
+        println!("{} steps, {} warnings", wf.steps.len(), wf.warnings.len());
+    } else {
+        println!(
+            "{} steps ({selected} selected), {} warnings",
+            wf.steps.len(),
+            wf.warnings.len()
+        );
+    }
+    Ok(())
+}
+
+/// Number for a new step after step `after`, or after the last step, given
+/// the sorted step numbers.
+fn new_step_id(ids: &[usize], after: Option<usize>) -> Result<usize> {
+    let after = match after {
+        Some(after) => after,
+        None => return Ok(ids.last().map_or(10, |last| last + 10)),
+    };
+    match ids.iter().find(|id| **id > after) {
+        None => Ok(after + 10),
+        Some(next) if next - after >= 2 => Ok(after + (next - after) / 2),
+        Some(next) => Err(Error::msg(format!(
+            "No free number between {after} and {next}, try `tickbox renumber`"
+        ))),
+    }
+}
+
+fn new_step(
+    dir: Option<std::path::PathBuf>,
+    after: Option<usize>,
+    description: &str,
+) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let dir = match dir {
+        Some(dir) => dir,
+        None => find_workflow_dir()?,
+    };
+    let steps = list_steps(&dir, &[])?;
+    let ids: Vec<usize> = steps.iter().map(|(id, _, _)| *id).collect();
+    let id = new_step_id(&ids, after)?;
+    let width = steps
+        .iter()
+        .map(|(_, name, _)| name.chars().take_while(char::is_ascii_digit).count())
+        .max()
+        .unwrap_or(2)
+        .max(id.to_string().len());
+    let slug = description
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    let path = dir.join(format!("{id:0width$}-{slug}.sh"));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o755)
+        .open(&path)
+        .map_err(|e| Error::msg(format!("Failed to create {}: {e}", path.display())))?
+        .write_all(
+            format!(
+                "#!/usr/bin/env bash\n\
+                 #\n\
+                 # {description}\n\
+                 #\n\
+                 set -euo pipefail\n\n"
+            )
+            .as_bytes(),
+        )?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+/// Settings that are maps from step name.
+const STEP_SETTINGS: &[&str] = &[
+    "args",
+    "budget",
+    "exit_codes",
+    "flake",
+    "group",
+    "ionice",
+    "lock",
+    "matrix",
+    "memory",
+    "needs",
+    "nice",
+    "nix_shell",
+    "output_limits",
+    "priority",
+    "sandbox",
+    "stall_timeout",
+    "step_tools",
+    "user",
+    "when",
+];
+
+/// Renumber the steps in a directory, and in its groups, adding the old
+/// and new names of renamed steps to `renames`.
+fn renumber_dir(
+    dir: &std::path::Path,
+    prefix: (&str, &str),
+    spacing: usize,
+    dry_run: bool,
+    renames: &mut Vec<(String, String)>,
+) -> Result<()> {
+    use itertools::Itertools;
+    let mut steps = list_steps(dir, &[])?;
+    steps.sort();
+    let ids: Vec<usize> = steps.iter().map(|(id, _, _)| *id).dedup().collect();
+    let width = (ids.len() * spacing).to_string().len().max(2);
+    let steps: Vec<(String, String, bool)> = steps
+        .into_iter()
+        .map(|(id, name, path)| {
+            let new_id = (ids.iter().position(|i| *i == id).unwrap() + 1) * spacing;
+            let rest = name.trim_start_matches(|c: char| c.is_ascii_digit());
+            let new = format!("{new_id:0width$}{rest}");
+            (name, new, path.is_dir())
+        })
+        .collect();
+    if !dry_run {
+        // In two steps, in case new names collide with old ones.
+        for (n, (old, new, _)) in steps.iter().enumerate() {
+            if old != new {
+                std::fs::rename(dir.join(old), dir.join(format!(".renumber-{n}")))?;
+            }
+        }
+        for (n, (old, new, _)) in steps.iter().enumerate() {
+            if old != new {
+                std::fs::rename(dir.join(format!(".renumber-{n}")), dir.join(new))?;
+            }
+        }
+    }
+    for (old, new, is_dir) in steps {
+        let old = format!("{}{old}", prefix.0);
+        let new = format!("{}{new}", prefix.1);
+        if is_dir {
+            let sub = if dry_run {
+                dir.join(&old[prefix.0.len()..])
+            } else {
+                dir.join(&new[prefix.1.len()..])
+            };
+            let sub_prefix = (format!("{old}/"), format!("{new}/"));
+            renumber_dir(
+                &sub,
+                (&sub_prefix.0, &sub_prefix.1),
+                spacing,
+                dry_run,
+                renames,
+            )?;
+        } else if old != new {
+            renames.push((old, new));
+        }
+    }
+    Ok(())
+}
+
+fn renumber(dir: Option<std::path::PathBuf>, spacing: usize, dry_run: bool) -> Result<()> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => find_workflow_dir()?,
+    };
+    if spacing == 0 {
+        return Err(Error::msg("spacing must be at least 1"));
+    }
+    let mut renames = Vec::new();
+    renumber_dir(&dir, ("", ""), spacing, dry_run, &mut renames)?;
+    for (old, new) in &renames {
+        println!("{old} -> {new}");
+    }
+    if dry_run {
+        return Ok(());
+    }
+    // Update settings for specific steps.
+    let filename = dir.join("tickbox.json");
+    let mut conf = read_config_json(&filename)?;
+    let mut changed = false;
+    for key in STEP_SETTINGS {
+        let Some(serde_json::Value::Object(map)) = conf.get_mut(*key) else {
+            continue;
+        };
+        let old = std::mem::take(map);
+        for (step, value) in old {
+            let step = match renames.iter().find(|(o, _)| *o == step) {
+                Some((_, new)) => {
+                    changed = true;
+                    new.clone()
+                }
+                None => step,
+            };
+            map.insert(step, value);
+        }
+    }
+    if changed {
+        std::fs::write(&filename, serde_json::to_string_pretty(&conf)? + "\n")?;
+    }
+    Ok(())
+}
+
+/// Reference documentation for `tickbox.json`.
+const CONFIG_REFERENCE: &str = include_str!("../doc/config.md");
+
+/// Write man pages for all commands, and the config reference.
+fn help_pages(out: &std::path::Path) -> Result<()> {
+    use clap::CommandFactory;
+    std::fs::create_dir_all(out)?;
+    let cmd = Cli::command();
+    let mut pages = vec![("tickbox".to_string(), cmd.clone())];
+    for sub in cmd.get_subcommands() {
+        pages.push((format!("tickbox-{}", sub.get_name()), sub.clone()));
+    }
+    for (name, cmd) in pages {
+        let mut buf = Vec::new();
+        clap_mangen::Man::new(cmd).title(&name).render(&mut buf)?;
+        std::fs::write(out.join(format!("{name}.1")), buf)?;
+    }
+    std::fs::write(out.join("tickbox-config.md"), CONFIG_REFERENCE)?;
+    Ok(())
+}
+
+/// Turn names into shell completion candidates.
+fn completions(names: Vec<String>, current: &std::ffi::OsStr) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    names
+        .into_iter()
+        .filter(|name| name.starts_with(&*current))
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+/// Complete workflow names in `.tickbox`.
+fn complete_workflows(current: &std::ffi::OsStr) -> Vec<CompletionCandidate> {
+    let names = find_workflow_dir()
+        .and_then(|dir| workflow_names(&dir))
+        .unwrap_or_default();
+    completions(names, current)
+}
+
+/// Complete step names in `.tickbox`.
+fn complete_steps(current: &std::ffi::OsStr) -> Vec<CompletionCandidate> {
+    let names = find_workflow_dir()
+        .and_then(|dir| load_tasks(&dir, &load_config(&dir)?.order))
+        .map(|tasks| tasks.into_iter().map(|t| t.name).collect())
+        .unwrap_or_default();
+    completions(names, current)
+}
+
+/// Path of an old log file.
+fn numbered_log(path: &std::path::Path, n: usize) -> std::path::PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    name.into()
+}
+
+/// When the TUI waits for the user when done.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum WaitMode {
+    Always,
+    OnFailure,
+    Never,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum LogTarget {
+    File,
+    Syslog,
+    Journald,
+}
+
+/// Where `SystemLogger` sends log records.
+enum SystemLog {
+    Syslog,
+
+    /// Connected to `JOURNAL_SOCKET`.
+    Journald(std::os::unix::net::UnixDatagram),
+}
+
+/// Logger sending to syslog or the journal, with syslog priorities.
+struct SystemLogger {
+    level: log::LevelFilter,
+    target: SystemLog,
+}
+
+impl SystemLogger {
+    fn new(level: log::LevelFilter, target: SystemLog) -> Box<Self> {
+        if let SystemLog::Syslog = target {
+            // SAFETY: The identifier is a static C string, as openlog()
+            // keeps the pointer.
+            unsafe { libc::openlog(c"tickbox".as_ptr(), libc::LOG_PID, libc::LOG_USER) };
+        }
+        Box::new(Self { level, target })
     }
 }
 
-/// A UIUpdate is sent to the UI thread whenever there's any news.
-enum UIUpdate {
-    /// Enable waiting when finished, even if all tasks succeed.
-    Wait,
+/// Logger adding the run ID to every record, for correlating logs of
+/// different runs.
+struct RunLogger {
+    run_id: String,
+    inner: Box<dyn log::Log>,
+}
 
-    /// Update the status window.
-    Status(Task),
+impl log::Log for RunLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.inner.log(
+            &log::Record::builder()
+                .args(format_args!("[{}] {}", self.run_id, record.args()))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
 
-    /// Add a line to the stdout/stderr window.
-    AddLine(String),
+    fn flush(&self) {
+        self.inner.flush();
+    }
 }
 
-async fn run_raw(mut rx: mpsc::Receiver<UIUpdate>) -> Result<()> {
-    let mut status = Vec::new();
-    loop {
-        match rx.try_recv() {
-            Ok(UIUpdate::Wait) => {
-                // Waiting only makes sense in TUI mode.
-            }
-            Ok(UIUpdate::AddLine(line)) => {
-                println!("{line}");
-            }
-            Ok(UIUpdate::Status(st)) if st.n == status.len() => {
-                status.push(st);
-            }
-            Ok(UIUpdate::Status(st)) => {
-                status[st.n] = st.clone();
-                let maxlen = status
-                    .iter()
-                    .map(|s| s.name.len())
-                    .max()
-                    .expect("no steps?");
-                println!("=== Status ===");
-                for task in &status {
-                    println!("  {:>maxlen$} {}", task.name, task.state);
-                }
+/// Unique ID of a run: the start time in milliseconds, in hex, and the PID.
+fn new_run_id() -> String {
+    format!(
+        "{:x}-{}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis(),
+        std::process::id()
+    )
+}
+
+impl log::Log for SystemLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let priority = syslog_priority(record.level());
+        let msg = record.args().to_string();
+        match &self.target {
+            SystemLog::Syslog => {
+                let msg = std::ffi::CString::new(msg.replace('\0', "")).unwrap();
+                // SAFETY: Both are valid C strings, and the format string
+                // takes one string argument.
+                unsafe { libc::syslog(priority, c"%s".as_ptr(), msg.as_ptr()) };
             }
-            Err(TryRecvError::Empty) => continue,
-            Err(TryRecvError::Disconnected) => {
-                return Ok(());
+            SystemLog::Journald(sock) => {
+                // Nowhere to report failure to log.
+                let _ = sock.send(&journal_entry(priority, &msg));
             }
         }
     }
+
+    fn flush(&self) {}
 }
 
-/// Run the UI until the channel with UIUpdates ends.
-async fn run_tui(mut rx: mpsc::Receiver<UIUpdate>) -> Result<()> {
-    let mut terminal = ratatui::init();
-    let mut out = String::new();
-    let mut status = Vec::new();
-    let mut do_wait = false;
-    let mut state = UiState::default();
-    'outer: loop {
-        loop {
-            match rx.try_recv() {
-                Ok(UIUpdate::Wait) => {
-                    do_wait = true;
-                }
-                Ok(UIUpdate::AddLine(line)) => {
-                    out += &line;
-                    out += "\n";
-                }
-                Ok(UIUpdate::Status(st)) if st.n == status.len() => {
-                    status.push(st);
-                }
-                Ok(UIUpdate::Status(st)) => {
-                    status[st.n] = st.clone();
-                }
-                Err(TryRecvError::Empty) => break,
-                Err(TryRecvError::Disconnected) => {
-                    if do_wait {
-                        break;
-                    } else {
-                        break 'outer;
-                    }
-                }
-            }
-        }
-        let status_lines = make_status_update(&status);
-        // TODO: get the actual output window height.
-        let out_height = 10;
-        terminal.draw(|frame| render(frame, &out, &status_lines, &mut state))?;
-        // Handle input.
-        if crossterm::event::poll(std::time::Duration::from_millis(50)).unwrap() {
-            match crossterm::event::read().unwrap() {
-                crossterm::event::Event::Key(key) if key.kind == KeyEventKind::Press => {
-                    match key.code {
-                        KeyCode::Char('j') | KeyCode::Down => {
-                            state.scroll = state.scroll.saturating_sub(1)
-                        }
-                        KeyCode::PageDown => state.scroll = state.scroll.saturating_sub(out_height),
-                        KeyCode::Char('k') | KeyCode::Up => state.scroll += 1,
-                        KeyCode::PageUp => state.scroll += out_height,
-                        KeyCode::Char('l') => terminal.clear()?,
-                        KeyCode::Char('q') => break,
-                        KeyCode::Char('Q') => break,
-                        _ => {}
-                    }
-                }
-                _ => {}
-            }
+/// Syslog priority of a log level.
+fn syslog_priority(level: log::Level) -> i32 {
+    match level {
+        log::Level::Error => libc::LOG_ERR,
+        log::Level::Warn => libc::LOG_WARNING,
+        log::Level::Info => libc::LOG_INFO,
+        log::Level::Debug | log::Level::Trace => libc::LOG_DEBUG,
+    }
+}
+
+/// Encode a message for the native journal protocol.
+fn journal_entry(priority: i32, msg: &str) -> Vec<u8> {
+    let mut out = format!("PRIORITY={priority}\nSYSLOG_IDENTIFIER=tickbox\n").into_bytes();
+    if msg.contains('\n') {
+        // Multi-line values are sent with their length instead.
+        out.extend(b"MESSAGE\n");
+        out.extend((msg.len() as u64).to_le_bytes());
+        out.extend(msg.as_bytes());
+        out.push(b'\n');
+    } else {
+        out.extend(format!("MESSAGE={msg}\n").as_bytes());
+    }
+    out
+}
+
+/// Rotate a non-empty log file, keeping `LOG_KEEP` old ones.
+fn rotate_log(path: &std::path::Path) -> Result<()> {
+    match std::fs::metadata(path) {
+        Ok(m) if m.is_file() && m.len() > 0 => {}
+        // Doesn't exist, or is something like /dev/null.
+        _ => return Ok(()),
+    }
+    for n in (1..LOG_KEEP).rev() {
+        let from = numbered_log(path, n);
+        if from.exists() {
+            std::fs::rename(&from, numbered_log(path, n + 1))?;
         }
     }
-    let status = make_status_update(&status);
-    out += "\n======== Exiting tickbox UI ==========";
-    terminal
-        .draw(|frame| render(frame, &out, &status, &mut state))
-        .unwrap();
-    ratatui::restore();
+    std::fs::rename(path, numbered_log(path, 1))?;
     Ok(())
 }
 
-/// Run a command, and wait for it to finish.
-///
-/// Returns `true` if the command exited with code 0.
-async fn run_command(
-    task: &Task,
-    envs: &[(OsString, OsString)],
-    tx: mpsc::Sender<UIUpdate>,
-) -> Result<bool> {
-    use tokio::io::AsyncBufReadExt;
-    use tokio::io::BufReader;
+/// A cron schedule, like `0 3 * * *` for every night at 03:00.
+#[derive(Clone, Debug, PartialEq)]
+struct Schedule {
+    /// Bit sets of the minutes, hours, days of the month, months, and days
+    /// of the week (0 is Sunday) to run at.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
 
-    // TODO: Make this fixed width.
-    tx.send(UIUpdate::AddLine(format!(
-        "============ Running \"{}\" ================",
-        task.name,
-    )))
-    .await
-    .unwrap();
+    /// Whether days of the month and of the week were given, rather than
+    /// `*`. If both were, either one matching is enough.
+    days_given: bool,
+    weekdays_given: bool,
+}
 
-    let mut cmd = tokio::process::Command::new("bash")
-        .arg("-c")
-        .arg(task.cmd.clone())
-        .envs(envs.iter().map(|(k, v)| (k.as_os_str(), v.as_os_str())))
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .expect("Failed to execute");
-    let stdout = cmd.stdout.take().unwrap();
-    let stderr = cmd.stderr.take().unwrap();
-    let rout = BufReader::new(stdout);
-    let mut lout = rout.lines();
-    let rerr = BufReader::new(stderr);
-    let mut lerr = rerr.lines();
+impl std::str::FromStr for Schedule {
+    type Err = Error;
 
-    let mut out_open = true;
-    let mut err_open = true;
+    fn from_str(s: &str) -> Result<Self> {
+        let s = match s {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            s => s,
+        };
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(Error::msg(format!(
+                "schedule {s:?} should have 5 fields: minute, hour, day, month, and weekday"
+            )));
+        };
+        // Sunday is both 0 and 7.
+        let mut weekday_bits = parse_cron_field(weekdays, 0, 7)?;
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Schedule {
+            minutes: parse_cron_field(minutes, 0, 59)?,
+            hours: parse_cron_field(hours, 0, 23)?,
+            days: parse_cron_field(days, 1, 31)?,
+            months: parse_cron_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            days_given: days != "*",
+            weekdays_given: weekdays != "*",
+        })
+    }
+}
+
+/// Parse a cron field, like `*/15`, `1-5`, or `0,30`, into a bit set.
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let bad = || Error::msg(format!("bad schedule field {field:?}, for {min}-{max}"));
+    let num = |s: &str| {
+        s.parse::<u32>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .ok_or_else(bad)
+    };
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(bad)?,
+            ),
+            None => (part, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((a, b)) => (num(a)?, num(b)?),
+            // A start with a step goes on to the end, like `5/15`.
+            None if part.contains('/') => (num(range)?, max),
+            None => (num(range)?, num(range)?),
+        };
+        if first > last {
+            return Err(bad());
+        }
+        for n in (first..=last).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+/// Local time, to the minute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct CronTime {
+    year: i32,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+}
+
+impl CronTime {
+    fn now() -> Self {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as libc::time_t;
+        // SAFETY: localtime_r only writes to the tm it's given.
+        let tm = unsafe {
+            let mut tm: libc::tm = std::mem::zeroed();
+            libc::localtime_r(&secs, &mut tm);
+            tm
+        };
+        CronTime {
+            year: tm.tm_year + 1900,
+            month: tm.tm_mon as u32 + 1,
+            day: tm.tm_mday as u32,
+            hour: tm.tm_hour as u32,
+            minute: tm.tm_min as u32,
+        }
+    }
+
+    /// The point in time, if it exists in the local time zone.
+    fn to_system_time(self) -> Option<std::time::SystemTime> {
+        // SAFETY: All zeroes is a valid tm.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = self.year - 1900;
+        tm.tm_mon = self.month as i32 - 1;
+        tm.tm_mday = self.day as i32;
+        tm.tm_hour = self.hour as i32;
+        tm.tm_min = self.minute as i32;
+        tm.tm_isdst = -1;
+        // SAFETY: mktime only reads and normalizes the tm it's given.
+        let secs = unsafe { libc::mktime(&mut tm) };
+        let secs = u64::try_from(secs).ok()?;
+        Some(std::time::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Day of the week, 0 being Sunday.
+    fn weekday(&self) -> u32 {
+        // Days since 1970-01-01, which was a Thursday.
+        let (y, m) = match self.month {
+            1 | 2 => (self.year as i64 - 1, self.month as i64 + 9),
+            m => (self.year as i64, m as i64 - 3),
+        };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * m + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        (days + 4).rem_euclid(7) as u32
+    }
 
+    fn days_in_month(&self) -> u32 {
+        let leap = self.year % 4 == 0 && (self.year % 100 != 0 || self.year % 400 == 0);
+        match self.month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    fn next_minute(self) -> Self {
+        if self.minute < 59 {
+            return CronTime {
+                minute: self.minute + 1,
+                ..self
+            };
+        }
+        CronTime { minute: 0, ..self }.next_hour()
+    }
+
+    /// Start of the next hour.
+    fn next_hour(self) -> Self {
+        if self.hour < 23 {
+            return CronTime {
+                hour: self.hour + 1,
+                minute: 0,
+                ..self
+            };
+        }
+        CronTime {
+            hour: 0,
+            minute: 0,
+            ..self
+        }
+        .next_day()
+    }
+
+    /// Start of the next day.
+    fn next_day(self) -> Self {
+        if self.day < self.days_in_month() {
+            return CronTime {
+                day: self.day + 1,
+                hour: 0,
+                minute: 0,
+                ..self
+            };
+        }
+        CronTime {
+            day: 1,
+            hour: 0,
+            minute: 0,
+            ..self
+        }
+        .next_month()
+    }
+
+    /// Start of the next month.
+    fn next_month(self) -> Self {
+        let (year, month) = match self.month {
+            12 => (self.year + 1, 1),
+            m => (self.year, m + 1),
+        };
+        CronTime {
+            year,
+            month,
+            day: 1,
+            hour: 0,
+            minute: 0,
+        }
+    }
+}
+
+impl Schedule {
+    /// The first time after `t` that the schedule says to run at, or `None`
+    /// if it never does, like on February 30th.
+    fn next_after(&self, t: CronTime) -> Option<CronTime> {
+        let has = |bits: u64, n: u32| bits & (1 << n) != 0;
+        let mut t = t.next_minute();
+        // Every schedule that matches at all does within a leap year cycle.
+        let give_up = t.year + 8;
+        while t.year < give_up {
+            let day = has(self.days, t.day);
+            let weekday = has(self.weekdays, t.weekday());
+            let day_ok = match (self.days_given, self.weekdays_given) {
+                (true, true) => day || weekday,
+                _ => day && weekday,
+            };
+            if !has(self.months, t.month) {
+                t = t.next_month();
+            } else if !day_ok {
+                t = t.next_day();
+            } else if !has(self.hours, t.hour) {
+                t = t.next_hour();
+            } else if !has(self.minutes, t.minute) {
+                t = t.next_minute();
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+fn deserialize_schedule<'de, D>(deserializer: D) -> Result<Option<Schedule>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Run the workflow whenever its schedule says to, until killed.
+///
+/// Every run is a separate tickbox, with the same arguments minus
+/// `--daemon`, and without the TUI, so it's kept in the run history like any
+/// other run. Runs don't overlap, so a run that's still going when the next
+/// one is due delays it.
+async fn daemon(schedule: Option<Schedule>) -> Result<()> {
+    let schedule = schedule.ok_or_else(|| Error::msg("--daemon needs a schedule in the config"))?;
+    let exe = std::env::current_exe()?;
+    let args: Vec<OsString> = std::env::args_os()
+        .skip(1)
+        .filter(|a| a != "--daemon" && a != "--disable-tui")
+        .collect();
     loop {
-        trace!("Main loop iteration");
-        tokio::select! {
-            line = lerr.next_line(), if err_open => {
-                trace!("Stderr line");
-                match line? {
-                    Some(line) => {
-                        if tx.send(UIUpdate::AddLine(line)).await.is_err() {
-                            cmd.kill().await?;
-                            break;
+        let Some(next) = schedule.next_after(CronTime::now()) else {
+            return Err(Error::msg("The schedule never says to run"));
+        };
+        if let Some(wait) = next
+            .to_system_time()
+            .and_then(|t| t.duration_since(std::time::SystemTime::now()).ok())
+        {
+            tokio::time::sleep(wait).await;
+        }
+        let status = tokio::process::Command::new(&exe)
+            .args(&args)
+            .arg("--disable-tui")
+            .stdin(std::process::Stdio::null())
+            .status()
+            .await;
+        match status {
+            Ok(status) => eprintln!("tickbox: scheduled run finished: {status}"),
+            Err(e) => eprintln!("tickbox: failed to start scheduled run: {e}"),
+        }
+    }
+}
+
+fn strip_newlines(os: OsString) -> OsString {
+    match os.into_string() {
+        Ok(s) => OsString::from(s.trim_end_matches(['\n', '\r'])),
+        Err(e) => panic!("Branch name not valid UTF-8: {e:?}"),
+    }
+}
+
+/// Run the workflow.
+///
+/// The abort handles of all step tasks are added to `aborts`, for when the
+/// workflow needs to be stopped.
+///
+/// Returns `true` if all steps succeeded.
+#[allow(clippy::too_many_arguments)]
+async fn run_workflow(
+    steps: Vec<Task>,
+    opt: Opt,
+    conf: Config,
+    filter: StepFilter,
+    offline: Offline,
+    max_concurrency: usize,
+    tx: UiSender,
+    aborts: Arc<Mutex<Vec<task::AbortHandle>>>,
+    records: Arc<Mutex<Vec<StepRecord>>>,
+) -> bool {
+    // Latest state of every step, shared between the step tasks.
+    let states = Arc::new(Mutex::new(
+        steps.iter().map(|t| t.state.clone()).collect::<Vec<_>>(),
+    ));
+    // Steps added by running steps, not yet seen by the scheduler.
+    let added: Arc<Mutex<Vec<(usize, Task)>>> = Arc::default();
+    let mut steps = steps;
+    let mut running: Vec<Task> = Vec::new();
+    let mut handles: Vec<tokio::task::JoinHandle<bool>> = Vec::new();
+    let run = async {
+        // Failed steps of the current parallel group, that keep it going.
+        let mut failed: Vec<Task> = Vec::new();
+        let keep_going = &conf.keep_going;
+        let mut order: VecDeque<usize> =
+            schedule_order(&steps, &opt.parallel, &conf.parallel_regex, &conf.priority).into();
+        // Added steps waiting for the step that added them to finish, as
+        // (parent, step).
+        let mut waiting: Vec<(usize, usize)> = Vec::new();
+        // Memory available to the steps, if any declare how much they need.
+        let mem_budget = if conf.memory.is_empty() {
+            None
+        } else {
+            available_memory_mib()
+        };
+        let mem = |t: &Task| conf.memory.get(&t.name).copied().unwrap_or(0);
+        let plugins = Arc::new(opt.plugins());
+        loop {
+            schedule_added(&added, &mut steps, &mut waiting, &mut order, &states, &tx).await;
+            let Some(n) = order.pop_front() else {
+                if waiting.is_empty() {
+                    break;
+                }
+                if handles.is_empty() {
+                    // The steps that added them errored out, so they'll never
+                    // finish.
+                    break;
+                }
+                // Wait for the steps that added steps to finish.
+                let ff = opt.fail_fast;
+                if !join_steps(&mut handles, &mut running, ff, keep_going, &states, &tx).await {
+                    return false;
+                }
+                continue;
+            };
+            let s = &steps[n];
+            loop {
+                let mem_used: u64 = running.iter().map(mem).sum();
+                let event = if handles.len() >= max_concurrency {
+                    "concurrency_limit"
+                } else if mem_budget.is_some_and(|b| mem_used + mem(s) > b) && !handles.is_empty() {
+                    "memory_limit"
+                } else if !resources_free(&conf, s, &running) {
+                    "resource_limit"
+                } else {
+                    break;
+                };
+                log_sched(&tx, event, s, None);
+                let (res, idx, _rem) = futures::future::select_all(&mut handles).await;
+                log_sched(&tx, "join", &running[idx], res.as_ref().ok().copied());
+                handles.remove(idx);
+                let done = running.remove(idx);
+                match res {
+                    Ok(true) => {}
+                    Ok(false) if keep_going.iter().any(|r| r.is_match(&done.name)) => {
+                        failed.push(done);
+                    }
+                    Ok(false) => {
+                        if opt.fail_fast {
+                            abort_steps(&mut handles, &mut running, &states, &tx).await;
+                        } else {
+                            join_steps(&mut handles, &mut running, false, &[], &states, &tx).await;
                         }
+                        return false;
                     }
-                    None => err_open = false,
+                    Err(e) => panic!("{e}"),
                 }
             }
-            line = lout.next_line(), if out_open => {
-                trace!("Stdout line");
-                match line? {
-                    Some(line) => {
-                        if tx.send(UIUpdate::AddLine(line)).await.is_err() {
-                            cmd.kill().await?;
-                            break;
-                        }
+            let s = s.clone();
+            let rs: Vec<&Task> = running.iter().chain(failed.iter()).collect();
+            if sync_point(&s, &rs, &opt.parallel, &conf.parallel_regex) {
+                log_sched(&tx, "sync_point", &s, None);
+                let ff = opt.fail_fast;
+                let ok = join_steps(&mut handles, &mut running, ff, keep_going, &states, &tx).await;
+                if !ok || !failed.is_empty() {
+                    return false;
+                }
+                // Steps added by the steps just finished go first.
+                order.push_front(n);
+                if schedule_added(&added, &mut steps, &mut waiting, &mut order, &states, &tx).await
+                {
+                    continue;
+                }
+                order.pop_front();
+            }
+            let mut steps = steps.clone();
+            let opt = opt.clone();
+            let filter = filter.clone();
+            let tx = tx.clone();
+            let conf = conf.clone();
+            let states = states.clone();
+            let records = records.clone();
+            let plugins = plugins.clone();
+            let added = added.clone();
+            if let Some(max) = opt.max_load {
+                let mut logged = false;
+                while !running.is_empty() && load_average().is_some_and(|l| l > max) {
+                    if !logged {
+                        log_sched(&tx, "load_limit", &s, None);
+                        logged = true;
                     }
-                    None => out_open = false,
+                    tokio::time::sleep(LOAD_POLL).await;
                 }
             }
+            running.push(s.clone());
+            log_sched(&tx, "dispatch", &s, None);
+            let handle = task::spawn(async move {
+                if !filter.selected(&steps[n].name) {
+                    steps[n].state = State::Skipped;
+                    states.lock().unwrap()[n] = State::Skipped;
+                    tx.send(UIUpdate::Status(steps[n].clone())).await.unwrap();
+                    return true;
+                }
+                if offline != Offline::Online
+                    && conf.needs_network.iter().any(|r| r.is_match(&s.name))
+                {
+                    let line = format!("==> Skipping \"{}\", it needs the network", s.name);
+                    let _ = tx.send(UIUpdate::AddLine(n, line)).await;
+                    info!("Step {} skipped, offline", s.name);
+                    steps[n].state = State::Skipped;
+                    states.lock().unwrap()[n] = State::Skipped;
+                    tx.send(UIUpdate::Status(steps[n].clone())).await.unwrap();
+                    return true;
+                }
+                let previous = match n {
+                    0 => "none",
+                    _ => states.lock().unwrap()[n - 1].short_name(),
+                };
+                let now = clock();
+                let started_at = std::time::SystemTime::now();
+                steps[n].state = State::Running(now);
+                states.lock().unwrap()[n] = steps[n].state.clone();
+                tx.send(UIUpdate::Status(steps[n].clone())).await.unwrap();
+                info!("Step {} started", s.name);
 
-            status = cmd.wait() => {
-                trace!("Command finished");
-                let status = status?;
-                tx.send(UIUpdate::AddLine("".to_string())).await.unwrap();
-                use std::os::unix::process::ExitStatusExt;
-                if let Some(code) = status.code() {
-                    tx.send(UIUpdate::AddLine(format!(
-                        "==> Command \"{}\" exited with code {code}",
-                        task.name,
-                    )))
-                    .await
-                    .unwrap();
-                } else if let Some(sig) = status.signal() {
-                    tx.send(UIUpdate::AddLine(format!(
-                        "==> Command \"{}\" exited with signal {sig} ",
-                        task.name
-                    )))
+                let mut envs = conf.envs.clone();
+                envs.extend(s.envs.clone());
+                envs.extend(vec![
+                    ("TICKBOX_STEP_NAME".into(), s.name.clone().into()),
+                    ("TICKBOX_STEP_INDEX".into(), (n + 1).to_string().into()),
+                    ("TICKBOX_PREVIOUS_STEP_STATUS".into(), previous.into()),
+                ]);
+                if let Some(dir) = step_temp_dir(&envs, &s.name) {
+                    // Start out empty, also when restarted.
+                    let _ = std::fs::remove_dir_all(&dir);
+                    match std::fs::create_dir_all(&dir) {
+                        Ok(()) => envs.push(("TICKBOX_STEP_TMPDIR".into(), dir.into())),
+                        Err(e) => {
+                            let line = format!("==> Failed to create {}: {e}", dir.display());
+                            let _ = tx.send(UIUpdate::AddLine(n, line)).await;
+                        }
+                    }
+                }
+                if let Some(cond) = conf.when.get(&s.name) {
+                    match check_condition(cond, &envs).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            let line =
+                                format!("==> Skipping \"{}\", condition not met: {cond}", s.name);
+                            let _ = tx.send(UIUpdate::AddLine(n, line)).await;
+                            info!("Step {} skipped", s.name);
+                            steps[n].state = State::Skipped;
+                            states.lock().unwrap()[n] = State::Skipped;
+                            let _ = tx.send(UIUpdate::Status(steps[n].clone())).await;
+                            return true;
+                        }
+                        Err(e) => {
+                            let line = format!("==> Failed to check condition {cond}: {e}");
+                            let _ = tx.send(UIUpdate::AddLine(n, line)).await;
+                            let _ = tx.send(UIUpdate::Wait).await;
+                            steps[n].state = State::Failed(since(now));
+                            states.lock().unwrap()[n] = steps[n].state.clone();
+                            let _ = tx.send(UIUpdate::Status(steps[n].clone())).await;
+                            return false;
+                        }
+                    }
+                }
+                let unverified = match (&conf.manifest, &s.inline) {
+                    (Some(manifest), None) => manifest.verify(&s.cmd).err(),
+                    _ => None,
+                };
+                if let Some(e) = unverified {
+                    let line = format!("==> Refusing to run \"{}\": {e}", s.name);
+                    let _ = tx.send(UIUpdate::AddLine(n, line)).await;
+                    error!("Step {} failed verification: {e}", s.name);
+                    let _ = tx.send(UIUpdate::Wait).await;
+                    steps[n].state = State::Failed(since(now));
+                    states.lock().unwrap()[n] = steps[n].state.clone();
+                    let _ = tx.send(UIUpdate::Status(steps[n].clone())).await;
+                    return false;
+                }
+                let event = serde_json::json!({"event": "step_started", "step": s.name});
+                let vetoed = step_plugins(n, &plugins, event, &envs, &tx).await;
+                let before = conf.before_each.as_deref();
+                let mut attempt = 1;
+                let mut exit_code = None;
+                let mut annotations = Vec::new();
+                let hook = if vetoed {
+                    Ok(false)
+                } else {
+                    run_hook(n, "before_each", before, &envs, tx.clone()).await
+                };
+                let result = match hook {
+                    Ok(true) => {
+                        let run = RunOptions {
+                            prio: Priority {
+                                nice: conf.nice.get(&s.name).copied(),
+                                ioprio: conf.ionice.get(&s.name).copied(),
+                            },
+                            limit: conf
+                                .output_limits
+                                .get(&s.name)
+                                .copied()
+                                .unwrap_or(conf.output_limit),
+                            log: opt
+                                .output_dir
+                                .as_ref()
+                                .map(|d| d.join(format!("{}.log", s.name.replace('/', "_")))),
+                            max_line: conf.max_line_length,
+                            merge_output: conf.merge_output,
+                            args: conf.args.get(&s.name).cloned().unwrap_or_default(),
+                            shell: conf.shell.iter().any(|r| r.is_match(&s.name)),
+                            inline: s.inline.clone(),
+                            stall_timeout: conf.stall_timeout.get(&s.name).copied(),
+                            kill_stalled: conf.kill_stalled.iter().any(|r| r.is_match(&s.name)),
+                            user: conf.user.get(&s.name).cloned(),
+                            group: conf.group.get(&s.name).cloned(),
+                            sandbox: conf.sandbox.get(&s.name).cloned(),
+                            offline: offline == Offline::Namespace,
+                            tools: conf
+                                .tools
+                                .iter()
+                                .chain(conf.step_tools.get(&s.name).into_iter().flatten())
+                                .map(|(t, v)| (t.clone(), v.clone()))
+                                .collect(),
+                            tool_resolver: conf.tool_resolver,
+                            dev_shell: dev_shell(&conf, &s.name),
+                            added: Some(AddedSteps {
+                                parent: s.clone(),
+                                states: states.clone(),
+                                steps: added.clone(),
+                            }),
+                        };
+                        let codes = conf.exit_codes.get(&s.name);
+                        let outcome = loop {
+                            let code = run_command(n, &s.name, &s.cmd, &envs, &run, tx.clone())
+                                .await
+                                .map(|(code, a)| {
+                                    annotations.extend(a);
+                                    code
+                                });
+                            exit_code = code.as_ref().ok().copied().flatten();
+                            match code.map(|c| Outcome::from_exit(c, codes)) {
+                                Ok(Outcome::Retry) if attempt < RETRY_ATTEMPTS => {
+                                    attempt += 1;
+                                    tx.send(UIUpdate::AddLine(
+                                        n,
+                                        format!(
+                                            "==> Retrying, attempt {attempt} of {RETRY_ATTEMPTS}"
+                                        ),
+                                    ))
+                                    .await
+                                    .unwrap();
+                                }
+                                Ok(Outcome::Retry) => break Ok(Outcome::Failure),
+                                other => break other,
+                            }
+                        };
+                        outcome.map(|o| match (s.expected_failure, o) {
+                            (true, Outcome::Success) => Outcome::Failure,
+                            (true, Outcome::Failure) => Outcome::Success,
+                            _ => o,
+                        })
+                    }
+                    Ok(false) if vetoed => Ok(Outcome::Skipped),
+                    Ok(false) => Ok(Outcome::Failure),
+                    Err(e) => Err(e),
+                };
+                let result_name = match &result {
+                    Ok(_) if vetoed => "vetoed",
+                    Ok(outcome) => outcome.name(),
+                    Err(_) => "error",
+                };
+                envs.push(("TICKBOX_STEP_RESULT".into(), result_name.into()));
+                let after = conf.after_each.as_deref();
+                if let Err(e) = run_hook(n, "after_each", after, &envs, tx.clone()).await {
+                    tx.send(UIUpdate::AddLine(
+                        n,
+                        format!("after_each hook error: {e:?}\n"),
+                    ))
                     .await
                     .unwrap();
                 }
-                return Ok(status.success());
-            },
-        };
+                let record = StepRecord {
+                    name: s.name.clone(),
+                    group: s.group.clone(),
+                    started_at,
+                    took: since(now),
+                    exit_code,
+                    attempts: attempt,
+                    result: result_name,
+                    annotations,
+                };
+                let (outputs, warnings) = annotation_json(&record.annotations);
+                let event = serde_json::json!({
+                    "event": "step_finished",
+                    "step": s.name,
+                    "result": result_name,
+                    "exit_code": exit_code,
+                    "attempts": attempt,
+                    "duration_secs": record.took.as_secs_f64(),
+                    "outputs": outputs,
+                    "warnings": warnings,
+                });
+                step_plugins(n, &plugins, event, &envs, &tx).await;
+                records.lock().unwrap().push(record);
+                match result {
+                    Ok(Outcome::Success) => {
+                        steps[n].state = State::Complete(since(now));
+                        states.lock().unwrap()[n] = steps[n].state.clone();
+                        info!("Step {} succeeded after {:.1?}", s.name, since(now));
+                    }
+                    Ok(Outcome::Skipped) => {
+                        steps[n].state = State::Skipped;
+                        states.lock().unwrap()[n] = State::Skipped;
+                        info!("Step {} skipped", s.name);
+                    }
+                    Ok(Outcome::Failure | Outcome::Retry) => {
+                        // This send() fails if the UI is gone, so nowhere to
+                        // display it anyway.
+                        let _ = tx.send(UIUpdate::Wait).await;
+                        steps[n].state = State::Failed(since(now));
+                        states.lock().unwrap()[n] = steps[n].state.clone();
+                        error!("Step {} failed after {:.1?}", s.name, since(now));
+                        let _ = tx.send(UIUpdate::Status(steps[n].clone())).await;
+                        return false;
+                    }
+                    Err(e) => {
+                        error!("Step {} error: {e}", s.name);
+                        tx.send(UIUpdate::AddLine(n, format!("Got an error: {e:?}\n")))
+                            .await
+                            .unwrap();
+                    }
+                }
+                let _ = tx.send(UIUpdate::Status(steps[n].clone())).await;
+                true
+            });
+            aborts.lock().unwrap().push(handle.abort_handle());
+            handles.push(handle);
+        }
+        let ff = opt.fail_fast;
+        join_steps(
+            &mut handles,
+            &mut running,
+            ff,
+            &conf.keep_going,
+            &states,
+            &tx,
+        )
+        .await
+            && failed.is_empty()
+    };
+    let Some(timeout) = opt.workflow_timeout else {
+        return run.await;
+    };
+    match tokio::time::timeout(timeout, run).await {
+        Ok(success) => success,
+        Err(_) => {
+            warn!("Workflow timed out after {}", format_duration(timeout));
+            let line = format!(
+                "==> Workflow timed out after {}, aborting",
+                format_duration(timeout)
+            );
+            let _ = tx.send(UIUpdate::AddLine(0, line)).await;
+            let aborted = abort_steps(&mut handles, &mut running, &states, &tx).await;
+            run_after_aborted(&aborted, &conf, &tx).await;
+            false
+        }
     }
-    Ok(false)
 }
 
-fn parse_usize_prefix(input: &str) -> Option<usize> {
-    let digits_end = input
-        .char_indices()
-        .take_while(|(_, c)| c.is_ascii_digit())
-        .map(|(i, _)| i + 1)
-        .last()?;
-
-    let (digits, _) = input.split_at(digits_end);
-    let value = digits.parse::<usize>().ok()?;
-    Some(value)
+/// Run the `after_each` hook for steps that were aborted before they could
+/// run it themselves.
+async fn run_after_aborted(aborted: &[Task], conf: &Config, tx: &UiSender) {
+    for t in aborted {
+        let mut envs = conf.envs.clone();
+        envs.extend(t.envs.clone());
+        envs.extend(vec![
+            ("TICKBOX_STEP_NAME".into(), t.name.clone().into()),
+            ("TICKBOX_STEP_INDEX".into(), (t.n + 1).to_string().into()),
+            ("TICKBOX_STEP_RESULT".into(), "aborted".into()),
+        ]);
+        let after = conf.after_each.as_deref();
+        if let Err(e) = run_hook(t.n, "after_each", after, &envs, tx.clone()).await {
+            let line = format!("after_each hook error: {e:?}\n");
+            let _ = tx.send(UIUpdate::AddLine(t.n, line)).await;
+        }
+    }
 }
 
-/// Load workflow (list of tasks) from directory.
-fn load_tasks(path: &std::path::Path) -> Result<Vec<Task>> {
-    use itertools::Itertools;
-    Ok(std::fs::read_dir(path)
-        .map_err(|e| {
-            std::io::Error::new(
-                e.kind(),
-                format!("Failed to read directory {}: {e}", path.display()),
-            )
-        })?
-        .flatten()
-        .filter_map(|entry| {
-            let cmd = entry.path();
-            let name = cmd.file_name().unwrap().to_str().unwrap();
+/// What happened when a step ran, for saving step times and traces.
+#[derive(Clone, Debug)]
+struct StepRecord {
+    name: String,
+    group: Option<String>,
+    started_at: std::time::SystemTime,
+    took: Duration,
 
-            if name.ends_with("~") // Don't join.
-               || name.ends_with(".conf")
-               || name.ends_with(".json")
-               || name.starts_with(".")
-            {
-                return None;
+    /// Exit code of the last attempt, if it exited normally.
+    exit_code: Option<i32>,
+    attempts: usize,
+
+    /// `success`, `failure`, `skipped`, `vetoed`, or `error`.
+    result: &'static str,
+
+    /// What the step said about itself.
+    annotations: Vec<Annotation>,
+}
+
+/// Annotations as JSON, for reports: outputs as an object, and a list of
+/// warnings.
+fn annotation_json(annotations: &[Annotation]) -> (serde_json::Value, serde_json::Value) {
+    let mut outputs = serde_json::Map::new();
+    let mut warnings = Vec::new();
+    for a in annotations {
+        match a {
+            Annotation::Output(key, value) => {
+                outputs.insert(key.clone(), value.clone().into());
             }
-            let id = match parse_usize_prefix(name).ok_or(Error::msg(format!(
-                "step file name doesn't start with a number: {name}"
-            ))) {
-                Ok(x) => x,
-                Err(e) => return Some(Err(e)),
-            };
-            Some(Ok(Task {
-                n: 0,
-                id,
-                name: name.to_string(),
-                cmd,
-                state: State::Pending,
-            }))
-        })
-        .collect::<Result<Vec<_>>>()?
-        .into_iter()
-        .sorted_by(|a, b| a.id.cmp(&b.id))
-        .enumerate()
-        .map(|(n, t)| {
-            let mut t = t.clone();
-            t.n = n;
-            t
-        })
-        .collect())
+            Annotation::Warning(msg) => warnings.push(serde_json::Value::from(msg.clone())),
+        }
+    }
+    (outputs.into(), warnings.into())
 }
 
-fn format_duration(d: Duration) -> String {
-    format!("{:7.1}s", d.as_secs_f64())
+/// Random hex string of `bytes` bytes, for trace and span IDs.
+fn random_hex(bytes: usize) -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let mut out = String::new();
+    while out.len() < bytes * 2 {
+        let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+        h.write_usize(out.len());
+        out += &format!("{:016x}", h.finish());
+    }
+    out.truncate(bytes * 2);
+    out
 }
 
-/// Take the tasks and turn them into something nicely formatted.
-fn make_status_update(steps: &[Task]) -> Vec<Line<'static>> {
-    let maxlen = steps.iter().map(|s| s.name.len()).max().expect("no steps?");
-    let lines: Vec<_> = steps
-        .iter()
-        .map(|s| {
-            let (pre, color, extra) = match s.state {
-                State::Running(st) => (UNCHECKED, Color::Blue, format_duration(st.elapsed())),
-                State::Complete(e) => (CHECKED, Color::Green, format_duration(e)),
-                State::Failed(e) => (FAILED, Color::Red, format_duration(e)),
-                State::Pending => (UNCHECKED, Color::Yellow, "".to_owned()),
-                State::Skipped => (UNCHECKED, Color::Gray, "".to_owned()),
-            };
-            Line::from(vec![Span::styled(
-                format!("{pre} {:<maxlen$} {extra}", s.name),
-                Style::default().fg(color),
-            )])
-        })
-        .collect();
-    lines
-        .clone()
-        .into_iter()
-        .map(|line| {
-            Line::from(
-                line.spans
-                    .into_iter()
-                    .map(|span| Span::styled(span.content.to_string(), span.style))
-                    .collect::<Vec<_>>(),
-            )
-        })
-        .collect()
+/// Build an OTLP/JSON trace of a run, with a span for the whole run, and a
+/// child span for every step that ran.
+fn otlp_trace(
+    workflow: &str,
+    run_id: &str,
+    started_at: std::time::SystemTime,
+    success: bool,
+    records: &[StepRecord],
+) -> serde_json::Value {
+    use serde_json::json;
+    let nanos = |t: std::time::SystemTime| {
+        t.duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_string()
+    };
+    let string = |k: &str, v: &str| json!({"key": k, "value": {"stringValue": v}});
+    let int = |k: &str, v: i64| json!({"key": k, "value": {"intValue": v.to_string()}});
+    // Status codes are 1 for ok, and 2 for error.
+    let status = |ok: bool| json!({"code": if ok { 1 } else { 2 }});
+    let trace_id = random_hex(16);
+    let root_id = random_hex(8);
+    let mut spans = vec![json!({
+        "traceId": trace_id,
+        "spanId": root_id,
+        "name": format!("tickbox {workflow}"),
+        "kind": 1,
+        "startTimeUnixNano": nanos(started_at),
+        "endTimeUnixNano": nanos(std::time::SystemTime::now()),
+        "attributes": [string("tickbox.workflow", workflow), string("tickbox.run_id", run_id)],
+        "status": status(success),
+    })];
+    for r in records {
+        let mut attributes = vec![
+            string("tickbox.step.result", r.result),
+            int("tickbox.step.retries", r.attempts as i64 - 1),
+        ];
+        if let Some(code) = r.exit_code {
+            attributes.push(int("tickbox.step.exit_code", code.into()));
+        }
+        if let Some(group) = &r.group {
+            attributes.push(string("tickbox.step.group", group));
+        }
+        for a in &r.annotations {
+            if let Annotation::Output(key, value) = a {
+                attributes.push(string(&format!("tickbox.step.output.{key}"), value));
+            }
+        }
+        let warnings = r
+            .annotations
+            .iter()
+            .filter(|a| matches!(a, Annotation::Warning(_)))
+            .count();
+        if warnings > 0 {
+            attributes.push(int("tickbox.step.warnings", warnings as i64));
+        }
+        spans.push(json!({
+            "traceId": trace_id,
+            "spanId": random_hex(8),
+            "parentSpanId": root_id,
+            "name": r.name,
+            "kind": 1,
+            "startTimeUnixNano": nanos(r.started_at),
+            "endTimeUnixNano": nanos(r.started_at + r.took),
+            "attributes": attributes,
+            "status": status(r.result != "failure" && r.result != "error"),
+        }));
+    }
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [string("service.name", "tickbox")],
+            },
+            "scopeSpans": [{
+                "scope": {"name": "tickbox", "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }],
+        }],
+    })
 }
 
-#[derive(Default, serde::Deserialize, Clone)]
-struct Config {
-    #[serde(deserialize_with = "deserialize_envs")]
-    envs: Vec<(OsString, OsString)>,
-    #[serde(deserialize_with = "deserialize_regexes", default)]
-    parallel_regex: Vec<regex::Regex>,
-    max_concurrency: Option<usize>,
+/// Memory available for new processes, in MiB, if known.
+fn available_memory_mib() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kib: u64 = meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib / 1024)
 }
 
-fn deserialize_regexes<'de, D>(deserializer: D) -> Result<Vec<regex::Regex>, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    use serde::Deserialize;
-    let strs = Vec::<String>::deserialize(deserializer)?;
-    let mut regexes = Vec::with_capacity(strs.len());
+/// One minute load average of the system.
+fn load_average() -> Option<f64> {
+    let mut load = [0.0f64; 1];
+    // SAFETY: The buffer has room for the one sample asked for.
+    match unsafe { libc::getloadavg(load.as_mut_ptr(), 1) } {
+        1 => Some(load[0]),
+        _ => None,
+    }
+}
 
-    for s in strs {
-        match regex::Regex::new(&s) {
-            Ok(r) => regexes.push(r),
-            Err(e) => {
-                return Err(serde::de::Error::custom(format!(
-                    "Invalid regex '{s}': {e}"
-                )));
+/// Wait for all running steps, returning `false` if any of them failed.
+///
+/// With `fail_fast`, the first failure aborts the rest, unless the failed
+/// step matches `keep_going`.
+async fn join_steps(
+    handles: &mut Vec<task::JoinHandle<bool>>,
+    running: &mut Vec<Task>,
+    fail_fast: bool,
+    keep_going: &[regex::Regex],
+    states: &Mutex<Vec<State>>,
+    tx: &UiSender,
+) -> bool {
+    let mut success = true;
+    while !handles.is_empty() {
+        let (res, idx, _rem) = futures::future::select_all(handles.iter_mut()).await;
+        log_sched(tx, "join", &running[idx], res.as_ref().ok().copied());
+        handles.remove(idx);
+        let done = running.remove(idx);
+        match res {
+            Ok(true) => {}
+            Ok(false) => {
+                success = false;
+                if fail_fast && !keep_going.iter().any(|r| r.is_match(&done.name)) {
+                    abort_steps(handles, running, states, tx).await;
+                }
             }
+            Err(e) => panic!("{e}"),
         }
     }
-    Ok(regexes)
-}
-
-fn deserialize_envs<'de, D>(deserializer: D) -> Result<Vec<(OsString, OsString)>, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    use serde::Deserialize;
-    use std::collections::HashMap;
-    let map: HashMap<String, String> = HashMap::deserialize(deserializer)?;
-    Ok(map
-        .into_iter()
-        .map(|(key, value)| (OsString::from(key), OsString::from(value)))
-        .collect())
+    success
 }
 
-/// Load config in JSON format.
-fn load_config(dir: &std::path::Path) -> Result<Config> {
-    let filename = dir.join("tickbox.json");
-    let contents = match std::fs::read_to_string(&filename) {
-        Ok(data) => data,
-        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
-            return Ok(Config::default());
+/// Take steps added by running steps, and put those whose parent has
+/// finished first in `order`, in the order they were added. Steps added by
+/// steps that didn't succeed are skipped. Returns whether any were put in
+/// `order`.
+async fn schedule_added(
+    added: &Mutex<Vec<(usize, Task)>>,
+    steps: &mut Vec<Task>,
+    waiting: &mut Vec<(usize, usize)>,
+    order: &mut VecDeque<usize>,
+    states: &Mutex<Vec<State>>,
+    tx: &UiSender,
+) -> bool {
+    for (parent, t) in added.lock().unwrap().drain(..) {
+        debug_assert_eq!(t.n, steps.len());
+        waiting.push((parent, t.n));
+        steps.push(t);
+    }
+    let current = states.lock().unwrap().clone();
+    let mut ready = Vec::new();
+    waiting.retain(|&(parent, n)| match &current[parent] {
+        State::Pending | State::Running(_) => true,
+        st => {
+            ready.push((n, matches!(st, State::Complete(_))));
+            false
         }
-        Err(e) => {
-            return Err(std::io::Error::new(
-                e.kind(),
-                format!("Error reading {}: {}", filename.display(), e),
-            )
-            .into());
+    });
+    let mut start = Vec::new();
+    for (n, ok) in ready {
+        if ok {
+            start.push(n);
+            continue;
         }
-    };
-    serde_json::from_str(&contents).map_err(|e| Error::msg(format!("JSON parse: {e}")))
+        steps[n].state = State::Skipped;
+        states.lock().unwrap()[n] = State::Skipped;
+        let _ = tx.send(UIUpdate::Status(steps[n].clone())).await;
+    }
+    let any = !start.is_empty();
+    for n in start.into_iter().rev() {
+        order.push_front(n);
+    }
+    any
 }
 
-fn strip_newlines(os: OsString) -> OsString {
-    match os.into_string() {
-        Ok(s) => OsString::from(s.trim_end_matches(['\n', '\r'])),
-        Err(e) => panic!("Branch name not valid UTF-8: {e:?}"),
+/// Kill running steps, marking them as aborted.
+async fn abort_steps(
+    handles: &mut Vec<task::JoinHandle<bool>>,
+    running: &mut Vec<Task>,
+    states: &Mutex<Vec<State>>,
+    tx: &UiSender,
+) -> Vec<Task> {
+    let mut aborted = Vec::new();
+    for (handle, mut t) in handles.drain(..).zip(running.drain(..)) {
+        handle.abort();
+        // Wait for it to stop, so that it doesn't change its state after
+        // this.
+        let _ = handle.await;
+        let State::Running(start) = states.lock().unwrap()[t.n].clone() else {
+            continue;
+        };
+        t.state = State::Aborted(since(start));
+        states.lock().unwrap()[t.n] = t.state.clone();
+        log_sched(tx, "abort", &t, None);
+        let _ = tx.send(UIUpdate::Status(t.clone())).await;
+        aborted.push(t);
     }
+    aborted
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let opt = Opt::parse();
-    simplelog::WriteLogger::init(
-        simplelog::LevelFilter::Info,
-        simplelog::Config::default(),
-        std::fs::File::create(&opt.log).unwrap(),
-    )?;
-    let mut conf = load_config(&opt.dir)?;
-    let steps = load_tasks(&opt.dir)?;
-    std::env::set_current_dir(&opt.cwd)?;
+    clap_complete::CompleteEnv::with_factory(<Cli as clap::CommandFactory>::command).complete();
+    let cli = Cli::parse();
+    if cli.schema {
+        println!("{:#}", config_schema());
+        return Ok(());
+    }
+    let mut opt = match cli.command {
+        None => cli.run,
+        Some(Command::Run(opt)) => opt,
+        Some(Command::ListWorkflows { dir }) => return list_workflows(dir),
+        Some(Command::HelpPages { out }) => return help_pages(&out),
+        Some(Command::Export { dir, out }) => return export_workflow(dir, out),
+        Some(Command::Import { file, dir }) => return import_workflow(&file, &dir),
+        Some(Command::Graph { format, opt }) => return graph(&opt, format),
+        Some(Command::Diff { run_a, run_b, opt }) => return diff(&opt, &run_a, &run_b),
+        Some(Command::Stats { flaky, opt }) => return stats(&opt, flaky),
+        Some(Command::Analyze { opt }) => return analyze(&opt),
+        Some(Command::Validate { opt }) => return validate(&opt),
+        Some(Command::Pack { dir, out }) => return pack_workflow(dir, &out),
+        Some(Command::Replay { file, speed }) => return replay(&file, speed).await,
+        Some(Command::Fetch { url, mut opt }) => {
+            opt.dir = Some(fetch_workflow(&url)?);
+            opt
+        }
+        Some(Command::NewStep {
+            description,
+            after,
+            dir,
+        }) => return new_step(dir, after, &description),
+        Some(Command::Renumber {
+            dir,
+            spacing,
+            dry_run,
+        }) => return renumber(dir, spacing, dry_run),
+        Some(Command::Completions { shell }) => {
+            use clap::CommandFactory;
+            clap_complete::generate(
+                shell,
+                &mut Cli::command(),
+                "tickbox",
+                &mut std::io::stdout(),
+            );
+            return Ok(());
+        }
+    };
+    let run_id = match &opt.run_id {
+        Some(id) => id.clone(),
+        None => new_run_id(),
+    };
+    let logger: Box<dyn log::Log> = match opt.log_target {
+        LogTarget::File => {
+            rotate_log(std::path::Path::new(&opt.log))?;
+            simplelog::WriteLogger::new(
+                opt.log_level,
+                simplelog::Config::default(),
+                std::fs::File::create(&opt.log).unwrap(),
+            )
+        }
+        LogTarget::Syslog => SystemLogger::new(opt.log_level, SystemLog::Syslog),
+        LogTarget::Journald => {
+            let sock = std::os::unix::net::UnixDatagram::unbound()?;
+            sock.connect(JOURNAL_SOCKET)
+                .map_err(|e| Error::msg(format!("Failed to connect to {JOURNAL_SOCKET}: {e}")))?;
+            SystemLogger::new(opt.log_level, SystemLog::Journald(sock))
+        }
+    };
+    log::set_boxed_logger(Box::new(RunLogger {
+        run_id: run_id.clone(),
+        inner: logger,
+    }))?;
+    log::set_max_level(opt.log_level);
+    let Workflow {
+        dir,
+        default_cwd,
+        mut conf,
+        steps,
+        warnings,
+        profile,
+    } = load_workflow(&opt)?;
+    for w in &warnings {
+        warn!("{w}");
+    }
+    let mut filter = StepFilter::new(&opt, &profile);
+    let cwd = opt.cwd.clone().unwrap_or(default_cwd);
+    if opt.daemon {
+        return daemon(conf.schedule).await;
+    }
+    let lock_path = opt.lock.clone().unwrap_or_else(|| match &opt.file {
+        Some(file) => file.with_extension("lock"),
+        None => dir.join(".tickbox.lock"),
+    });
+    let _lock = acquire_lock(&lock_path, opt.wait_for_lock)?;
+    // Absolute, since they're written after changing directory.
+    let times_path = state_path(&opt, &dir, "times")?;
+    let history_path = state_path(&opt, &dir, "history")?;
+    if let Some(out) = &opt.output_dir {
+        // Make it absolute, before changing directory.
+        std::fs::create_dir_all(out)?;
+        opt.output_dir = Some(out.canonicalize()?);
+    }
+    check_executable(&steps, &conf.shell)?;
+    let mut picked_priorities = Vec::new();
+    if opt.interactive {
+        let groups = parallel_groups(&steps, &opt.parallel, &conf.parallel_regex);
+        let Some(picker) = pick_steps(&steps, &groups)? else {
+            return Ok(());
+        };
+        filter.deselected = picker.deselected(&steps);
+        picked_priorities = picker.priorities(&steps);
+        conf.priority.extend(picked_priorities.clone());
+    }
+    // For reloading the workflow after changing directory.
+    let workflow_path = std::path::absolute(opt.file.as_ref().unwrap_or(&dir))?;
+    let mut reload_opt = opt.clone();
+    match &opt.file {
+        Some(_) => reload_opt.file = Some(workflow_path.clone()),
+        None => {
+            reload_opt.dir = Some(workflow_path.clone());
+            reload_opt.name = None;
+        }
+    }
+    let mut mtimes = workflow_mtimes(&workflow_path);
+    std::env::set_current_dir(&cwd)?;
     let cwd = std::env::current_dir()?;
     let tmp_dir = tempfile::TempDir::new()?;
-    conf.envs.extend(vec![
+    let mut run_envs: Vec<(OsString, OsString)> = vec![
         ("TICKBOX_TEMPDIR".into(), tmp_dir.path().into()),
         ("TICKBOX_CWD".into(), cwd.to_str().unwrap().into()),
-    ]);
+        ("TICKBOX_RUN_ID".into(), run_id.clone().into()),
+    ];
 
     // If CWD is a git repository, put the branch name into an env.
     {
@@ -634,12 +10239,55 @@ async fn main() -> Result<()> {
             }
             let branch = strip_newlines(OsString::from_vec(out.stdout.clone()));
             use std::os::unix::ffi::OsStringExt;
-            conf.envs.push(("TICKBOX_BRANCH".into(), branch));
+            run_envs.push(("TICKBOX_BRANCH".into(), branch));
         }
     }
-    let (tx, rx) = mpsc::channel(500);
-    if opt.wait {
-        tx.send(UIUpdate::Wait).await.unwrap();
+    let mut offline = match opt.offline {
+        true => Offline::Namespace,
+        false => Offline::Online,
+    };
+    if opt.offline && !network_namespaces_work().await {
+        warn!("Network namespaces not available, using proxy env vars for --offline");
+        eprintln!(
+            "tickbox: network namespaces not available, using proxy env vars to stay offline"
+        );
+        offline = Offline::Proxy;
+        let proxy = OsString::from("http://127.0.0.1:9");
+        run_envs.extend(
+            OFFLINE_PROXY_ENVS
+                .iter()
+                .map(|k| (OsString::from(k), proxy.clone())),
+        );
+        run_envs.extend(["no_proxy", "NO_PROXY"].map(|k| (OsString::from(k), OsString::new())));
+    }
+    add_run_envs(&mut conf, steps.len(), &run_envs)?;
+    let sudo = steps
+        .iter()
+        .filter(|t| filter.selected(&t.name))
+        .any(|t| conf.needs_sudo.iter().any(|r| r.is_match(&t.name)));
+    if sudo {
+        sudo_validate().await?;
+        task::spawn(async {
+            loop {
+                tokio::time::sleep(SUDO_REFRESH).await;
+                // Not drawing over the TUI.
+                let status = tokio::process::Command::new("sudo")
+                    .args(["--non-interactive", "--validate"])
+                    .stdin(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null())
+                    .status()
+                    .await;
+                if !status.is_ok_and(|s| s.success()) {
+                    warn!("Failed to refresh sudo credentials");
+                }
+            }
+        });
+    }
+    let (tx, mut rx) = ui_channel(conf.output_buffer.unwrap_or(DEFAULT_OUTPUT_BUFFER));
+    for w in &warnings {
+        tx.send(UIUpdate::AddLine(0, format!("==> Warning: {w}")))
+            .await
+            .unwrap();
     }
     for s in steps.iter() {
         tx.send(UIUpdate::Status(s.clone())).await.unwrap();
@@ -653,87 +10301,276 @@ async fn main() -> Result<()> {
         d
     };
 
+    let theme = match (opt.theme.clone(), conf.theme.clone()) {
+        (Some(theme), _) => theme,
+        (None, Some(ThemeConfig::Named(name))) => {
+            Theme::named(&name).ok_or(Error::msg(format!("Unknown theme: {name}")))?
+        }
+        (None, Some(ThemeConfig::Custom(theme))) => theme,
+        (None, None) => Theme::default(),
+    };
     let max_concurrency = opt
         .max_concurrency
         .unwrap_or(conf.max_concurrency.unwrap_or(DEFAULT_MAX_CONCURRENCY));
+    let mut times = load_times(&times_path);
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let tui_config = TuiConfig {
+        theme,
+        error_regex: match conf.error_regex.clone() {
+            Some(re) => re,
+            None => regex::Regex::new(DEFAULT_ERROR_REGEX)?,
+        },
+        highlights: conf.highlight.clone().unwrap_or_else(default_highlights),
+        bell: opt.bell,
+        title: !opt.no_title && !conf.ui.no_title,
+        parallel_groups: group_numbers(&steps, &opt, &conf),
+        estimates: times.clone(),
+        budget: conf.budget.clone(),
+        sla: conf.sla,
+        max_concurrency,
+        run_id: run_id.clone(),
+        ui: conf.ui,
+        keys: Keymap::new(&conf.keys)?,
+        wait: opt.wait,
+        exit_after: opt.exit_after,
+        envs: conf.envs.clone(),
+        records: records.clone(),
+    };
+    if let Some(path) = &opt.record {
+        let header = RecordingHeader {
+            run_id: run_id.clone(),
+            parallel_groups: tui_config.parallel_groups.clone(),
+            estimates: times.clone(),
+            budget: conf.budget.clone(),
+            sla: conf.sla,
+            max_concurrency,
+        };
+        rx.recorder = Some(Recorder::create(path, &header)?);
+    }
+    let opt_bell = opt.bell;
+    let no_prefix = opt.no_prefix;
+    let verbosity = match (opt.quiet, opt.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (false, true) => Verbosity::Verbose,
+        (false, false) => Verbosity::Normal,
+    };
+    let otlp_endpoint = opt.otlp_endpoint.clone();
+    let plugins = opt.plugins();
+    let plugin_envs = conf.envs.clone();
+    let notifications = conf.notifications.clone();
+    let temp_cleanup = match opt.keep_temp {
+        true => TempCleanup::AlwaysKeep,
+        false => conf.temp_cleanup,
+    };
+    let temp_path = tmp_dir.path().to_path_buf();
+    let budget = conf.budget.clone();
+    let sla = conf.sla;
+    let workflow = dir.display().to_string();
+    let started_at = std::time::SystemTime::now();
+    let (ctl_tx, mut ctl_rx) = mpsc::channel(10);
+    let run_records = records.clone();
     let runner = task::spawn(async move {
-        let mut success = true;
-        let mut running: Vec<Task> = Vec::new();
-        let mut handles: Vec<tokio::task::JoinHandle<bool>> = Vec::new();
-        for (n, s) in steps.clone().iter_mut().enumerate() {
-            if handles.len() >= max_concurrency {
-                let (res, idx, _rem) = futures::future::select_all(&mut handles).await;
-                match res {
-                    Ok(true) => {}
-                    Ok(false) => return false,
-                    Err(e) => panic!("{e}"),
-                }
-                handles.remove(idx);
-                running.remove(idx);
-            }
-            let s = s.clone();
-            let mut steps = steps.clone();
-            let opt = opt.clone();
-            let tx = tx.clone();
-            let conf = conf.clone();
-            let rs: Vec<&Task> = running.iter().collect();
-            if sync_point(&s, &rs, &opt.parallel, &conf.parallel_regex) {
-                for t in handles.iter_mut() {
-                    if !t.await.unwrap() {
-                        //success = false;
-                        return false;
-                    }
-                }
-                running.clear();
-                handles.clear();
-            }
-            running.push(s.clone());
-            handles.push(task::spawn(async move {
-                if !opt.matching.is_match(&steps[n].name) {
-                    steps[n].state = State::Skipped;
-                    tx.send(UIUpdate::Status(steps[n].clone())).await.unwrap();
-                    return true;
-                }
-                let now = Instant::now();
-                steps[n].state = State::Running(now);
-                tx.send(UIUpdate::Status(steps[n].clone())).await.unwrap();
-
-                match run_command(&s, &conf.envs, tx.clone()).await {
-                    Ok(true) => {
-                        steps[n].state = State::Complete(now.elapsed());
-                    }
-                    Ok(false) => {
-                        // This send() fails if the UI is gone, so nowhere to
-                        // display it anyway.
-                        let _ = tx.send(UIUpdate::Wait).await;
-                        steps[n].state = State::Failed(now.elapsed());
-                        let _ = tx.send(UIUpdate::Status(steps[n].clone())).await;
-                        return false;
+        let mut steps = steps;
+        let mut success = false;
+        let mut changes = tokio::time::interval(CHANGE_POLL);
+        'restart: loop {
+            // Only the last run counts.
+            run_records.lock().unwrap().clear();
+            let aborts = Arc::new(Mutex::new(Vec::new()));
+            let mut run = task::spawn(run_workflow(
+                steps.clone(),
+                opt.clone(),
+                conf.clone(),
+                filter.clone(),
+                offline,
+                max_concurrency,
+                tx.clone(),
+                aborts.clone(),
+                run_records.clone(),
+            ));
+            // Even after the run finishes, the UI may ask for a restart, so
+            // stay around until it's gone.
+            let mut finished = false;
+            let mut changed = false;
+            loop {
+                tokio::select! {
+                    _ = changes.tick(), if !changed => {
+                        if workflow_mtimes(&workflow_path) != mtimes {
+                            changed = true;
+                            warn!("Workflow changed on disk");
+                            let line = "==> Workflow changed on disk, restart to use the changes";
+                            let _ = tx.send(UIUpdate::AddLine(0, line.to_string())).await;
+                        }
                     }
-                    Err(e) => {
-                        tx.send(UIUpdate::AddLine(format!("Got an error: {e:?}\n")))
-                            .await
-                            .unwrap();
+                    res = &mut run, if !finished => {
+                        success = res.unwrap();
+                        finished = true;
+                        if !success && temp_cleanup != TempCleanup::AlwaysDelete {
+                            let line = format!("==> Temporary files are kept in {}", temp_path.display());
+                            let _ = tx.send(UIUpdate::AddLine(0, line)).await;
+                        }
+                        let _ = tx.send(UIUpdate::Finished).await;
                     }
+                    ctl = ctl_rx.recv() => match ctl {
+                        Some(Control::Restart) => {
+                            run.abort();
+                            for abort in aborts.lock().unwrap().iter() {
+                                abort.abort();
+                            }
+                            mtimes = workflow_mtimes(&workflow_path);
+                            let reload = reload_workflow(&reload_opt, &run_envs, &picked_priorities);
+                            let error = match reload {
+                                Ok((new_steps, new_conf)) => {
+                                    steps = new_steps;
+                                    conf = new_conf;
+                                    None
+                                }
+                                Err(e) => {
+                                    warn!("Failed to reload workflow: {e}");
+                                    Some(format!("==> Failed to reload workflow, running it unchanged: {e}"))
+                                }
+                            };
+                            let groups = group_numbers(&steps, &opt, &conf);
+                            let _ = tx.send(UIUpdate::Restart(groups)).await;
+                            if let Some(line) = error {
+                                let _ = tx.send(UIUpdate::AddLine(0, line)).await;
+                            }
+                            for s in steps.iter() {
+                                let _ = tx.send(UIUpdate::Status(s.clone())).await;
+                            }
+                            continue 'restart;
+                        }
+                        None if finished => break 'restart,
+                        None => {
+                            // Nobody left to ask for a restart.
+                            success = (&mut run).await.unwrap();
+                            break 'restart;
+                        }
+                    },
                 }
-                let _ = tx.send(UIUpdate::Status(steps[n].clone())).await;
-                true
-            }));
-        }
-        for r in handles.into_iter() {
-            if !r.await.unwrap() {
-                success = false;
             }
         }
         success
     });
 
-    if disable_tui {
-        run_raw(rx).await?;
+    let summary = if disable_tui {
+        drop(ctl_tx);
+        run_raw(rx, opt_bell, !no_prefix, verbosity, &run_id, &budget, sla).await?
     } else {
-        run_tui(rx).await?;
+        let summary = run_tui(rx, ctl_tx, tui_config).await?;
+        // The panic may have been drawn over by the TUI, so print it again.
+        if let Some(msg) = PANIC_MESSAGE.lock().ok().and_then(|m| m.clone()) {
+            eprintln!("tickbox {msg}");
+        }
+        summary
+    };
+    let success = runner.await?;
+    if success {
+        info!("Workflow {workflow} succeeded");
+    } else {
+        error!("Workflow {workflow} failed");
+    }
+    let records = records.lock().unwrap().clone();
+    // The run ended when its last step did, not when the UI exited.
+    let took = records
+        .iter()
+        .filter_map(|r| (r.started_at + r.took).duration_since(started_at).ok())
+        .max()
+        .unwrap_or_default();
+    let over_budget = sla.is_some_and(|sla| took > sla);
+    if over_budget && success {
+        let sla = format_duration(sla.unwrap_or_default()).trim().to_string();
+        warn!("Workflow {workflow} succeeded, but exceeded its time budget of {sla}");
+        eprintln!("tickbox: workflow completed, but exceeded its time budget of {sla}");
+    }
+    let event = serde_json::json!({
+        "event": "run_finished",
+        "success": success,
+        "over_budget": over_budget,
+    });
+    for (name, response) in run_plugins(&plugins, &event, &plugin_envs).await {
+        if let Err(e) = response {
+            eprintln!("tickbox: plugin {name} failed: {e}");
+        }
+    }
+    times.extend(
+        records
+            .iter()
+            .filter(|r| r.result == "success")
+            .map(|r| (r.name.clone(), r.took)),
+    );
+    if let Err(e) = save_times(&times_path, &times) {
+        warn!("Failed to save step times to {}: {e}", times_path.display());
+    }
+    let run = RunHistory {
+        run_id: run_id.clone(),
+        started_at,
+        success,
+        steps: records
+            .iter()
+            .map(|r| StepHistory {
+                name: r.name.clone(),
+                result: r.result.to_string(),
+                took: r.took,
+            })
+            .collect(),
+    };
+    if let Err(e) = save_history(&history_path, run) {
+        warn!(
+            "Failed to save run history to {}: {e}",
+            history_path.display()
+        );
+    }
+    if let Some(endpoint) = otlp_endpoint {
+        let trace = otlp_trace(&workflow, &run_id, started_at, success, &records);
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        let body = trace.to_string();
+        let post = task::spawn_blocking({
+            let url = url.clone();
+            move || curl_json("POST", &url, &[], &body)
+        });
+        if let Err(e) = post.await.unwrap_or_else(|e| Err(e.into())) {
+            eprintln!("tickbox: failed to send trace to {url}: {e}");
+        }
+    }
+    if !success {
+        let title = format!("tickbox: {workflow} failed");
+        if let Some(email) = notifications.email {
+            let body = match &summary {
+                Some(lines) => lines
+                    .iter()
+                    .map(|l| l.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                None => format!("Run {run_id} failed before finishing."),
+            };
+            let subject = title.clone();
+            let send = task::spawn_blocking(move || email.send(&subject, &body));
+            if let Err(e) = send.await.unwrap_or_else(|e| Err(e.into())) {
+                eprintln!("tickbox: failed to send email: {e}");
+            }
+        }
+        let title = format!("{title} (run {run_id})");
+        let table = step_table(&records);
+        if let Some(slack) = notifications.slack {
+            let (title, table) = (title.clone(), table.clone());
+            let send = task::spawn_blocking(move || slack.send(&title, &table));
+            if let Err(e) = send.await.unwrap_or_else(|e| Err(e.into())) {
+                eprintln!("tickbox: failed to notify Slack: {e}");
+            }
+        }
+        if let Some(matrix) = notifications.matrix {
+            let send = task::spawn_blocking(move || matrix.send(&title, &table));
+            if let Err(e) = send.await.unwrap_or_else(|e| Err(e.into())) {
+                eprintln!("tickbox: failed to notify Matrix: {e}");
+            }
+        }
+    }
+    if let Some(dir) = cleanup_temp(tmp_dir, temp_cleanup, &records) {
+        info!("Temporary files kept in {}", dir.display());
+        eprintln!("tickbox: temporary files kept in {}", dir.display());
     }
-    if !runner.await? {
+    if !success {
         std::process::exit(1);
     }
     Ok(())